@@ -0,0 +1,13 @@
+Pointer movement microbenchmark for the bench flag: three nested
+counters of 200 around a body that walks five cells right and back
+
+++++++++++[>++++++++++++++++++++<-]>
+[
+    >++++++++++[>++++++++++++++++++++<-]>
+    [
+        >++++++++++[>++++++++++++++++++++<-]>
+        [>+>+>+>+>+<<<<<-]
+        <<-
+    ]
+    <<-
+]
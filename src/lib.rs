@@ -0,0 +1,8394 @@
+//! The brainfuck interpreter behind the `rustfuck` binary, as a library.
+//!
+//! A run goes through three stages: `parse` turns source into tokens,
+//! `optimize` rewrites them with a list of `Pass`es, and `Program` executes
+//! the result against a `Tape`, optionally recording a `Trace`. The binary
+//! is a command line front end over exactly these pieces.
+//!
+//! To just run a program, `run_str` does all three with sensible defaults.
+//! Tools that work on programs themselves can walk and rewrite the ops
+//! with `visit`, or work on them as a tree of loops with `tree`.
+//!
+//! With the `serde` feature, `BrainFuckToken` and `tree::Node` can be
+//! serialized, so an optimized program can be stored as JSON or bincode and
+//! loaded by another process without running the optimizer again.
+//!
+//! Without the default `std` feature the crate is `no_std` and needs only
+//! `alloc`: the parser, the optimizer, `Tape` and `TapeWalker` remain, so
+//! small programs can run on embedded targets. Everything that touches
+//! files, the terminal, threads or the clock goes.
+//!
+//! The default `cli` feature adds the tools the binary's flags drive: the
+//! `Debugger`, `Explainer`, `Audit`, `Triggers`, `Checkpoints`,
+//! `Trajectory`, `Animation` and `CostReport`, and printing `Diagnostic`s.
+//! Embedders that only compile and run programs can turn it off and keep
+//! `std`; the library then never writes to stderr.
+//!
+//! The crate is entirely safe Rust unless the `unchecked` feature is on.
+//! That adds `Dispatch::Unchecked`, which drops the bounds checks on ops and
+//! cells for programs it can prove never need them. What that buys depends
+//! on the program and the machine, so measure it with `--bench` under each
+//! `--dispatch`; `testing::difftest_unchecked` checks it against the safe
+//! engine.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(not(feature = "unchecked"), forbid(unsafe_code))]
+
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+
+#[cfg(not(feature = "std"))]
+#[macro_use]
+extern crate alloc;
+// Lets the rest of the crate write `std::fmt` and the like either way.
+#[cfg(not(feature = "std"))]
+extern crate core as std;
+
+#[cfg(not(feature = "std"))]
+mod alloc_prelude {
+    pub use alloc::rc::Rc;
+    pub use alloc::string::{String, ToString};
+    pub use alloc::vec::Vec;
+}
+#[cfg(not(feature = "std"))]
+use alloc_prelude::*;
+
+#[cfg(not(feature = "std"))]
+use alloc::collections::{BTreeMap, VecDeque};
+#[cfg(feature = "std")]
+use std::collections::{BTreeMap, HashMap, VecDeque};
+#[cfg(feature = "std")]
+use std::convert::{TryFrom, TryInto};
+use std::fmt::{Display, Formatter, Result, Write};
+#[cfg(feature = "std")]
+use std::io;
+use std::mem::replace;
+use std::num::Wrapping;
+#[cfg(feature = "std")]
+use std::rc::Rc;
+use std::str::FromStr;
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+use optimizer::prelude::{is_clear_loop, loop_body, loop_effects, rewrite_windows, Effect};
+
+/// Everything the interpreter says about a run, as opposed to what the
+/// program writes, goes to stderr through here. That keeps stdout to the
+/// program's output alone, so it is always safe to pipe. Only the `cli`
+/// tools use it; the rest of the library never prints.
+#[cfg(feature = "cli")]
+macro_rules! status {
+    ($($arg:tt)*) => {
+        eprintln!($($arg)*)
+    };
+}
+
+/// Writes program output to stdout as soon as it is produced.
+#[cfg(feature = "std")]
+pub fn write_output(bytes: &[u8]) {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    io::Write::write_all(&mut stdout, bytes).ok();
+    io::Write::flush(&mut stdout).ok();
+}
+
+pub const DEFAULT_TAPE_SIZE: usize = 30000;
+pub const DEFAULT_MAX_DEPTH: usize = 1024;
+/// Target of a jump `--lazy-jumps` hasn't resolved yet. Far past the end of
+/// any program, so taking it ends the run loop instead of needing a check.
+const UNRESOLVED: usize = usize::max_value() / 2;
+pub type JumpLocs = (usize, usize);
+type Tokens = Vec<BrainFuckToken>;
+/// For each op a pass produced, the range of its input it was made from.
+pub type Sources = Vec<std::ops::Range<usize>>;
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum BrainFuckToken {
+    Move(isize),
+    JumpF(usize),
+    JumpB(usize),
+    Incr(i32),
+    StdOut,
+    StdIn,
+    ZeroOut,
+    /// A `Move` followed by an `Incr`, fused by `-O2` to save a dispatch.
+    MoveIncr(isize, i32),
+    /// An `Incr` followed by a `StdOut`.
+    IncrOut(i32),
+    /// A run of `StdOut`s.
+    Out(usize),
+    /// A run of `StdOut`s while the cell is known to hold this byte.
+    OutConst(u8, usize),
+}
+
+
+impl Display for BrainFuckToken {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match *self {
+            BrainFuckToken::Move(x) => write!(f, " M{}", &x),
+            BrainFuckToken::JumpF(_) => write!(f, " ["),
+            BrainFuckToken::JumpB(_) => write!(f, " ]"),
+            BrainFuckToken::Incr(x) => write!(f, " I{}", &x),
+            BrainFuckToken::StdOut => write!(f, "O"),
+            BrainFuckToken::StdIn => write!(f, " I"),
+            BrainFuckToken::ZeroOut => write!(f, " @"),
+            BrainFuckToken::MoveIncr(x, y) => write!(f, " M{}I{}", &x, &y),
+            BrainFuckToken::IncrOut(x) => write!(f, " I{}O", &x),
+            BrainFuckToken::Out(n) => write!(f, " O{}", &n),
+            BrainFuckToken::OutConst(b, n) => write!(f, " O{}x{}", &b, &n),
+        }
+    }
+}
+
+/// Reference entry for one IR instruction, for `ir-doc`. `opcode` is also
+/// what the handler-table engine encodes the op as.
+#[derive(Debug)]
+pub struct IrOp {
+    pub name: &'static str,
+    /// How traces and hot-loop reports print it.
+    pub notation: &'static str,
+    pub operands: &'static str,
+    pub opcode: u8,
+    pub semantics: &'static str,
+    /// The pass that first produces it, or `parse` for plain Brainfuck.
+    pub introduced_by: &'static str,
+    pub engines: &'static [&'static str],
+}
+
+const ALL_ENGINES: &'static [&'static str] = &["match", "table", "instrumented"];
+
+/// Every IR instruction, in opcode order. `BrainFuckToken::ir_op` matches
+/// exhaustively on the enum, so a new op can't be added without an entry.
+pub const IR_OPS: &'static [IrOp] = &[
+    IrOp {
+        name: "JumpF",
+        notation: "[",
+        operands: "target: index of the matching `]`",
+        opcode: 0,
+        semantics: "if the cell is zero, continue after `target`",
+        introduced_by: "parse",
+        engines: ALL_ENGINES,
+    },
+    IrOp {
+        name: "JumpB",
+        notation: "]",
+        operands: "target: index of the matching `[`",
+        opcode: 1,
+        semantics: "if the cell is nonzero, continue after `target`",
+        introduced_by: "parse",
+        engines: ALL_ENGINES,
+    },
+    IrOp {
+        name: "Move",
+        notation: "M<n>",
+        operands: "n: cells to move, negative for left",
+        opcode: 2,
+        semantics: "move the pointer by n; moving left of the first cell is an error, right of the last wraps",
+        introduced_by: "parse",
+        engines: ALL_ENGINES,
+    },
+    IrOp {
+        name: "Incr",
+        notation: "I<n>",
+        operands: "n: amount to add, negative to subtract",
+        opcode: 3,
+        semantics: "add n to the cell, wrapping at the cell width",
+        introduced_by: "parse",
+        engines: ALL_ENGINES,
+    },
+    IrOp {
+        name: "StdIn",
+        notation: "I",
+        operands: "",
+        opcode: 4,
+        semantics: "read a byte of input into the cell, or apply the EOF behavior",
+        introduced_by: "parse",
+        engines: ALL_ENGINES,
+    },
+    IrOp {
+        name: "StdOut",
+        notation: "O",
+        operands: "",
+        opcode: 5,
+        semantics: "write the cell's low byte",
+        introduced_by: "parse",
+        engines: ALL_ENGINES,
+    },
+    IrOp {
+        name: "ZeroOut",
+        notation: "@",
+        operands: "",
+        opcode: 6,
+        semantics: "set the cell to zero, replacing `[-]` and `[+]`",
+        introduced_by: "zero",
+        engines: ALL_ENGINES,
+    },
+    IrOp {
+        name: "MoveIncr",
+        notation: "M<n>I<m>",
+        operands: "n: cells to move; m: amount to add",
+        opcode: 7,
+        semantics: "Move(n) then Incr(m)",
+        introduced_by: "fuse",
+        engines: ALL_ENGINES,
+    },
+    IrOp {
+        name: "IncrOut",
+        notation: "I<n>O",
+        operands: "n: amount to add",
+        opcode: 8,
+        semantics: "Incr(n) then StdOut",
+        introduced_by: "fuse",
+        engines: ALL_ENGINES,
+    },
+    IrOp {
+        name: "Out",
+        notation: "O<n>",
+        operands: "n: times to write",
+        opcode: 9,
+        semantics: "write the cell's low byte n times",
+        introduced_by: "collapse",
+        engines: ALL_ENGINES,
+    },
+    IrOp {
+        name: "OutConst",
+        notation: "O<b>x<n>",
+        operands: "b: byte the cell is known to hold; n: times to write",
+        opcode: 10,
+        semantics: "write b n times without reading the cell",
+        introduced_by: "const-out",
+        engines: ALL_ENGINES,
+    },
+];
+
+impl BrainFuckToken {
+    fn ir_op(&self) -> &'static IrOp {
+        let opcode = match *self {
+            BrainFuckToken::JumpF(_) => 0,
+            BrainFuckToken::JumpB(_) => 1,
+            BrainFuckToken::Move(_) => 2,
+            BrainFuckToken::Incr(_) => 3,
+            BrainFuckToken::StdIn => 4,
+            BrainFuckToken::StdOut => 5,
+            BrainFuckToken::ZeroOut => 6,
+            BrainFuckToken::MoveIncr(_, _) => 7,
+            BrainFuckToken::IncrOut(_) => 8,
+            BrainFuckToken::Out(_) => 9,
+            BrainFuckToken::OutConst(_, _) => 10,
+        };
+        &IR_OPS[opcode]
+    }
+}
+
+/// Loop counts and costs for one run. A `Trace` belongs to one `Program`
+/// and is updated without locking; runs on other threads each keep their
+/// own, and `TraceShards` adds them up afterwards.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    count: HashMap<JumpLocs, u32>,
+    /// Steps spent in each loop, not counting loops nested inside it.
+    pub cost: HashMap<JumpLocs, u64>,
+    /// For each loop currently running, the step count when it was entered
+    /// and the steps spent so far in loops nested inside it.
+    open: Vec<(u64, u64)>,
+}
+
+#[cfg(feature = "std")]
+impl Trace {
+    fn new() -> Trace {
+        Trace {
+            count: HashMap::new(),
+            cost: HashMap::new(),
+            open: Vec::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.count = HashMap::new();
+        self.cost = HashMap::new();
+        self.open = Vec::new();
+    }
+
+    /// A loop was entered at step `steps`.
+    fn trace(&mut self, locs: JumpLocs, steps: u64) {
+        let c = self.count.entry(locs).or_insert(0);
+        *c += 1;
+        self.open.push((steps, 0));
+    }
+
+    /// A loop was left at step `steps`. Only called as often as loops are
+    /// entered, never once per iteration.
+    fn leave(&mut self, locs: JumpLocs, steps: u64) {
+        if let Some((entered, nested)) = self.open.pop() {
+            let total = steps - entered;
+            *self.cost.entry(locs).or_insert(0) += total - nested;
+            if let Some(parent) = self.open.last_mut() {
+                parent.1 += total;
+            }
+        }
+    }
+
+    /// Adds the loops `other` saw to this trace. Both have to come from
+    /// runs of the same ops, since loops are known by where they are.
+    pub fn merge(&mut self, other: &Trace) {
+        for (&locs, &count) in &other.count {
+            let c = self.count.entry(locs).or_insert(0);
+            *c = c.saturating_add(count);
+        }
+        for (&locs, &cost) in &other.cost {
+            *self.cost.entry(locs).or_insert(0) += cost;
+        }
+    }
+
+    pub fn report(&mut self, prog: &Vec<BrainFuckToken>) -> HashMap<String, u32> {
+        let mut report: HashMap<String, u32> = HashMap::new();
+        for (name, c) in self.count
+            .iter()
+            .filter(|&(_, c)| *c > 100)
+            .map(|(locs, c)| (token_run_to_string(locs, prog), c))
+        {
+            let e = report.entry(name).or_insert(0);
+            *e += c;
+        }
+
+        report
+    }
+}
+
+/// Gathers the traces of runs of one program spread over several threads.
+/// Each run counts into its own `Trace` and hands it in once it's done, so
+/// the lock is taken once per run rather than on every loop; the shards are
+/// only added up when a report asks for them.
+///
+/// ```
+/// use rustfuck::{Program, TraceShards};
+/// use std::thread;
+///
+/// let shards = TraceShards::new();
+/// let workers: Vec<_> = (0..4)
+///     .map(|_| {
+///         let shards = shards.clone();
+///         thread::spawn(move || {
+///             let mut prog: Program = "++++[>++++<-]".parse().unwrap();
+///             prog.run(b"", &mut Vec::new());
+///             shards.submit(std::mem::take(&mut prog.tracer));
+///         })
+///     })
+///     .collect();
+/// for worker in workers {
+///     worker.join().unwrap();
+/// }
+///
+/// assert_eq!(shards.len(), 4);
+/// let trace = shards.merged();
+/// assert_eq!(trace.cost.values().sum::<u64>(), 4 * 20);
+/// ```
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Default)]
+pub struct TraceShards {
+    shards: Arc<Mutex<Vec<Trace>>>,
+}
+
+#[cfg(feature = "std")]
+impl TraceShards {
+    pub fn new() -> TraceShards {
+        TraceShards::default()
+    }
+
+    /// Hands in the trace of a finished run.
+    pub fn submit(&self, trace: Trace) {
+        self.shards.lock().unwrap().push(trace);
+    }
+
+    /// How many traces have been handed in.
+    pub fn len(&self) -> usize {
+        self.shards.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// All the traces handed in so far, added up.
+    pub fn merged(&self) -> Trace {
+        let mut merged = Trace::new();
+        for shard in self.shards.lock().unwrap().iter() {
+            merged.merge(shard);
+        }
+        merged
+    }
+}
+
+/// The loop `[` ... `]` at `locs` without the bodies of the loops nested in
+/// it, which become `[]`: the ops that one iteration runs itself.
+pub fn loop_own_ops(locs: JumpLocs, ops: &[BrainFuckToken]) -> Vec<BrainFuckToken> {
+    let (start, end) = locs;
+    let mut own = Vec::new();
+    let mut depth = 0;
+
+    for &token in &ops[start..end + 1] {
+        match token {
+            BrainFuckToken::JumpF(_) => {
+                depth += 1;
+                if depth <= 2 {
+                    own.push(token);
+                }
+            }
+            BrainFuckToken::JumpB(_) => {
+                if depth <= 2 {
+                    own.push(token);
+                }
+                depth -= 1;
+            }
+            _ if depth == 1 => own.push(token),
+            _ => {}
+        }
+    }
+
+    own
+}
+
+/// Loop counters of one or more runs, keyed by the loop's ops rather than
+/// where it sits, so traces of the same program from other machines or
+/// versions still line up as long as it was optimized the same way.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct SavedTrace {
+    pub steps: u64,
+    /// Times entered and own steps spent, per loop.
+    loops: HashMap<String, (u64, u64)>,
+}
+
+#[cfg(feature = "std")]
+const TRACE_HEADER: &'static str = "# rustfuck trace v1";
+
+#[cfg(feature = "std")]
+impl SavedTrace {
+    pub fn from_run(trace: &Trace, ops: &Tokens, steps: u64) -> SavedTrace {
+        let mut saved = SavedTrace { steps: steps, loops: HashMap::new() };
+        for (locs, &count) in &trace.count {
+            let cost = trace.cost.get(locs).cloned().unwrap_or(0);
+            let name = token_run_to_string(locs, ops).trim().to_string();
+            let entry = saved.loops.entry(name).or_insert((0, 0));
+            entry.0 += count as u64;
+            entry.1 += cost;
+        }
+        saved
+    }
+
+    /// Adds the counters of `other` to these.
+    pub fn merge(&mut self, other: SavedTrace) {
+        self.steps += other.steps;
+        for (name, (count, cost)) in other.loops {
+            let entry = self.loops.entry(name).or_insert((0, 0));
+            entry.0 += count;
+            entry.1 += cost;
+        }
+    }
+
+    /// A header line with the run's steps, then one `COUNT COST LOOP` line
+    /// per loop, costliest first.
+    pub fn write_to<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "{} steps={}", TRACE_HEADER, self.steps)?;
+        for (name, &(count, cost)) in self.sorted() {
+            writeln!(w, "{}\t{}\t{}", count, cost, name)?;
+        }
+        Ok(())
+    }
+
+    pub fn parse(text: &str) -> std::result::Result<SavedTrace, String> {
+        let mut lines = text.lines();
+        let steps = lines
+            .next()
+            .and_then(|header| header.strip_prefix(TRACE_HEADER))
+            .and_then(|rest| rest.trim().strip_prefix("steps="))
+            .and_then(|steps| steps.parse().ok())
+            .ok_or("not a trace written by --trace-out")?;
+
+        let mut saved = SavedTrace { steps: steps, loops: HashMap::new() };
+        for (n, line) in lines.enumerate() {
+            let fields: Vec<&str> = line.splitn(3, '\t').collect();
+            let bad = || format!("line {}: expected COUNT, COST and LOOP separated by tabs", n + 2);
+            if fields.len() != 3 {
+                return Err(bad());
+            }
+            let count: u64 = fields[0].parse().map_err(|_| bad())?;
+            let cost: u64 = fields[1].parse().map_err(|_| bad())?;
+            let entry = saved.loops.entry(fields[2].to_string()).or_insert((0, 0));
+            entry.0 += count;
+            entry.1 += cost;
+        }
+        Ok(saved)
+    }
+
+    /// Loops by steps spent, costliest first.
+    pub fn sorted(&self) -> Vec<(&String, &(u64, u64))> {
+        let mut loops: Vec<_> = self.loops.iter().collect();
+        loops.sort_by(|&(a, x), &(b, y)| y.1.cmp(&x.1).then(a.cmp(b)));
+        loops
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Io {
+    Input,
+    Output,
+    Eof,
+}
+
+/// A consecutive run of input or output, stamped with the step and time at
+/// which it started.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+struct TranscriptEntry {
+    step: u64,
+    elapsed: Duration,
+    io: Io,
+    bytes: Vec<u8>,
+}
+
+/// Records everything a program reads and writes, in order, so interactive
+/// sessions can be audited or turned into fixtures.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct Transcript {
+    start: Instant,
+    entries: Vec<TranscriptEntry>,
+}
+
+#[cfg(feature = "std")]
+impl Transcript {
+    pub fn new() -> Transcript {
+        Transcript {
+            start: Instant::now(),
+            entries: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, step: u64, io: Io, byte: Option<u8>) {
+        let continues = match self.entries.last() {
+            Some(entry) => entry.io == io,
+            None => false,
+        };
+
+        if !continues {
+            self.entries.push(TranscriptEntry {
+                step: step,
+                elapsed: self.start.elapsed(),
+                io: io,
+                bytes: Vec::new(),
+            });
+        }
+
+        if let Some(byte) = byte {
+            self.entries.last_mut().unwrap().bytes.push(byte);
+        }
+    }
+
+    pub fn write_to<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "# step seconds direction text")?;
+
+        for entry in &self.entries {
+            let direction = match entry.io {
+                Io::Input => "in",
+                Io::Output => "out",
+                Io::Eof => "eof",
+            };
+            let text: String = entry
+                .bytes
+                .iter()
+                .flat_map(|&b| std::ascii::escape_default(b))
+                .map(|b| b as char)
+                .collect();
+            writeln!(
+                w,
+                "{} {:.6} {} \"{}\"",
+                entry.step,
+                entry.elapsed.as_secs_f64(),
+                direction,
+                text
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// What one step of a run did: the op it ran, the cell it stored to with
+/// the value it left there, and what it wrote.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepRecord {
+    pub step: u64,
+    pub op: usize,
+    pub store: Option<(usize, i32)>,
+    pub output: Vec<u8>,
+}
+
+/// One line: `STEP OP [CELL=VALUE] [out=HEX]`.
+#[cfg(feature = "std")]
+impl Display for StepRecord {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{} {}", self.step, self.op)?;
+        if let Some((cell, value)) = self.store {
+            write!(f, " {}={}", cell, value)?;
+        }
+        if !self.output.is_empty() {
+            write!(f, " out={}", hex(&self.output))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl FromStr for StepRecord {
+    type Err = String;
+
+    fn from_str(line: &str) -> std::result::Result<StepRecord, String> {
+        let mut fields = line.split_whitespace();
+        let mut number = |what| {
+            fields.next().and_then(|field| field.parse().ok()).ok_or(format!("no {} in `{}`", what, line))
+        };
+        let step = number("step")?;
+        let op = number("op")? as usize;
+        let mut record = StepRecord {
+            step: step,
+            op: op,
+            store: None,
+            output: Vec::new(),
+        };
+
+        for field in line.split_whitespace().skip(2) {
+            if let Some(digits) = field.strip_prefix("out=") {
+                record.output = (0..digits.len() / 2)
+                    .map(|idx| u8::from_str_radix(&digits[idx * 2..idx * 2 + 2], 16))
+                    .collect::<std::result::Result<_, _>>()
+                    .map_err(|_| format!("bad output `{}` in `{}`", digits, line))?;
+                continue;
+            }
+            let store = field.split_once('=').and_then(|(cell, value)| Some((cell.parse().ok()?, value.parse().ok()?)));
+            match store {
+                Some(store) => record.store = Some(store),
+                None => return Err(format!("bad field `{}` in `{}`", field, line)),
+            }
+        }
+        Ok(record)
+    }
+}
+
+/// Every step of a run, in order, as `--record` saves it and `--replay`
+/// checks a later run against it.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    pub steps: Vec<StepRecord>,
+}
+
+#[cfg(feature = "std")]
+impl Recording {
+    pub fn new() -> Recording {
+        Recording::default()
+    }
+
+    /// Reads back what `write_to` wrote. Blank lines and `#` comments are
+    /// skipped.
+    pub fn parse(text: &str) -> std::result::Result<Recording, String> {
+        let lines = text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#'));
+        Ok(Recording {
+            steps: lines.map(str::parse).collect::<std::result::Result<_, _>>()?,
+        })
+    }
+
+    pub fn write_to<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "# step op [cell=value] [out=hex]")?;
+        for step in &self.steps {
+            writeln!(w, "{}", step)?;
+        }
+        Ok(())
+    }
+}
+
+/// Recorded steps shown before a divergence.
+#[cfg(feature = "std")]
+const DIVERGENCE_CONTEXT: usize = 5;
+
+/// The first step at which a replayed run did something other than its
+/// recording.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub step: u64,
+    /// What the recording has for the step, or `None` if it ended sooner.
+    pub expected: Option<StepRecord>,
+    /// What the run did, or `None` if it stopped sooner.
+    pub actual: Option<StepRecord>,
+    /// The steps just before, where the two agreed.
+    pub context: Vec<StepRecord>,
+}
+
+#[cfg(feature = "std")]
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let what = match (&self.expected, &self.actual) {
+            (&Some(ref e), &Some(ref a)) if e.op != a.op => "ran a different op",
+            (&Some(ref e), &Some(ref a)) if e.store != a.store => "stored something else",
+            (&Some(_), &Some(_)) => "wrote something else",
+            (&Some(_), &None) => "the run stopped here",
+            (&None, _) => "the recording ends here",
+        };
+        writeln!(f, "diverged at step {}: {}", self.step, what)?;
+        for step in &self.context {
+            writeln!(f, "    both:      {}", step)?;
+        }
+        let show = |step: &Option<StepRecord>| step.as_ref().map_or("-".to_string(), |step| step.to_string());
+        writeln!(f, "    recording: {}", show(&self.expected))?;
+        write!(f, "    run:       {}", show(&self.actual))
+    }
+}
+
+/// Checks a run step by step against a `Recording`, stopping it at the
+/// first step that differs. The op must be the same, store the same value
+/// in the same cell and write the same bytes.
+///
+/// ```
+/// use rustfuck::{HaltReason, Program, Recording, Replay};
+///
+/// let mut prog: Program = "+++[>++<-]>.".parse().unwrap();
+/// prog.recording = Some(Recording::new());
+/// prog.run(b"", &mut Vec::new());
+/// let recording = prog.recording.take().unwrap();
+///
+/// let mut changed: Program = "+++[>+++<-]>.".parse().unwrap();
+/// changed.replay = Some(Replay::new(recording));
+/// let result = changed.run(b"", &mut Vec::new());
+/// assert!(matches!(result.halt, HaltReason::Aborted(_)));
+/// let divergence = changed.replay.unwrap().finish().unwrap();
+/// assert_eq!(divergence.step, 4);
+/// assert_eq!(divergence.actual.unwrap().store, Some((1, 3)));
+/// ```
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct Replay {
+    recording: Recording,
+    next: usize,
+    divergence: Option<Divergence>,
+}
+
+#[cfg(feature = "std")]
+impl Replay {
+    pub fn new(recording: Recording) -> Replay {
+        Replay {
+            recording: recording,
+            next: 0,
+            divergence: None,
+        }
+    }
+
+    /// Compares the step just run with the recording. Returns false, and
+    /// keeps the divergence, if they differ.
+    fn check(&mut self, actual: StepRecord) -> bool {
+        if self.recording.steps.get(self.next) == Some(&actual) {
+            self.next += 1;
+            return true;
+        }
+        let step = actual.step;
+        self.diverge(step, Some(actual));
+        false
+    }
+
+    fn diverge(&mut self, step: u64, actual: Option<StepRecord>) {
+        let start = self.next.saturating_sub(DIVERGENCE_CONTEXT);
+        self.divergence = Some(Divergence {
+            step: step,
+            expected: self.recording.steps.get(self.next).cloned(),
+            actual: actual,
+            context: self.recording.steps[start..self.next].to_vec(),
+        });
+    }
+
+    /// Once the run is over: where it diverged, including stopping before
+    /// the recording does, or `None` if it matched throughout.
+    pub fn finish(mut self) -> Option<Divergence> {
+        if self.divergence.is_none() && self.next < self.recording.steps.len() {
+            let step = self.recording.steps[self.next].step;
+            self.diverge(step, None);
+        }
+        self.divergence
+    }
+
+    /// Steps matched so far.
+    pub fn matched(&self) -> usize {
+        self.next
+    }
+}
+
+/// Every so many steps, writes what changed since the previous checkpoint:
+/// the cells now holding a different value and the output written since.
+/// Lets a long run be followed without a full tape snapshot each time.
+#[cfg(feature = "cli")]
+#[derive(Debug)]
+pub struct Checkpoints {
+    every: u64,
+    file: std::fs::File,
+    tape: Vec<i32>,
+    output: Vec<u8>,
+}
+
+#[cfg(feature = "cli")]
+impl Checkpoints {
+    pub fn new<C: Cell>(every: u64, file: std::fs::File, tape: &Tape<C>) -> Checkpoints {
+        Checkpoints {
+            every: every.max(1),
+            file: file,
+            tape: tape.values(0, tape.tape.len()),
+            output: Vec::new(),
+        }
+    }
+
+    fn due(&self, step: u64) -> bool {
+        step != 0 && step % self.every == 0
+    }
+
+    /// Writes one line: `STEP ptr=P CELL=VALUE... out="TEXT"`.
+    fn write<C: Cell>(&mut self, step: u64, tape: &Tape<C>) -> io::Result<()> {
+        let mut line = format!("{} ptr={}", step, tape.loc);
+        for (idx, (now, then)) in tape.tape.iter().map(|&cell| cell.to_i32()).zip(self.tape.iter_mut()).enumerate() {
+            if now != *then {
+                write!(line, " {}={}", idx, now).ok();
+                *then = now;
+            }
+        }
+        let text: String = self
+            .output
+            .drain(..)
+            .flat_map(std::ascii::escape_default)
+            .map(|b| b as char)
+            .collect();
+        writeln!(line, " out=\"{}\"", text).ok();
+        io::Write::write_all(&mut self.file, line.as_bytes())?;
+        io::Write::flush(&mut self.file)
+    }
+}
+
+/// Cells kept on each side of the pointer when a loop is captured for
+/// `--emit-loop-tests`.
+#[cfg(feature = "std")]
+const LOOP_TEST_RADIUS: usize = 16;
+/// How many of the hottest loops `--emit-loop-tests` writes tests for.
+#[cfg(feature = "std")]
+const LOOP_TESTS: usize = 10;
+/// The cells around the pointer when a loop was first entered and when it
+/// then exited, plus what it wrote in between.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+struct LoopSample {
+    /// Tape index of the first cell kept.
+    start: usize,
+    entry: Vec<i32>,
+    entry_ptr: usize,
+    exit: Option<(Vec<i32>, usize)>,
+    output: Vec<u8>,
+}
+
+/// Captures the first run of each loop, keyed by its `[`, so the hot ones
+/// can be written out as tests once the run is over.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct LoopCapture {
+    samples: HashMap<usize, LoopSample>,
+    /// Loops being captured, innermost last.
+    open: Vec<usize>,
+}
+
+#[cfg(feature = "std")]
+impl LoopCapture {
+    fn enter<C: Cell>(&mut self, open: usize, tape: &Tape<C>) {
+        if self.samples.contains_key(&open) {
+            return;
+        }
+        let start = tape.loc.saturating_sub(LOOP_TEST_RADIUS);
+        let end = (tape.loc + LOOP_TEST_RADIUS + 1).min(tape.tape.len());
+        self.samples.insert(
+            open,
+            LoopSample {
+                start: start,
+                entry: tape.values(start, end),
+                entry_ptr: tape.loc,
+                exit: None,
+                output: Vec::new(),
+            },
+        );
+        self.open.push(open);
+    }
+
+    fn leave<C: Cell>(&mut self, open: usize, tape: &Tape<C>) {
+        if self.open.last() != Some(&open) {
+            return;
+        }
+        self.open.pop();
+        let sample = self.samples.get_mut(&open).unwrap();
+        let end = sample.start + sample.entry.len();
+        sample.exit = Some((tape.values(sample.start, end), tape.loc));
+    }
+
+    fn output(&mut self, bytes: &[u8]) {
+        for open in &self.open {
+            self.samples.get_mut(open).unwrap().output.extend_from_slice(bytes);
+        }
+    }
+
+    /// Writes a test for each of the hottest loops that finished inside
+    /// the cells captured and doesn't read input, returning how many.
+    pub fn write_tests(&self, dir: &str, trace: &Trace, ops: &Tokens, steps: u64, cells: CellWidth) -> io::Result<usize> {
+        fn list<T: Display>(items: &[T]) -> String {
+            let items: Vec<String> = items.iter().map(|item| item.to_string()).collect();
+            format!("[{}]", items.join(", "))
+        }
+
+        let mut loops: Vec<(&JumpLocs, &u64)> = trace.cost.iter().collect();
+        loops.sort_by(|&(a, x), &(b, y)| y.cmp(x).then(a.cmp(b)));
+        std::fs::create_dir_all(dir)?;
+
+        let mut written = 0;
+        for (&(open, close), &cost) in loops {
+            let sample = match self.samples.get(&open) {
+                Some(sample) => sample,
+                None => continue,
+            };
+            let (exit, exit_ptr) = match sample.exit {
+                Some((ref exit, ptr)) if ptr >= sample.start && ptr < sample.start + exit.len() => (exit, ptr),
+                _ => continue,
+            };
+            if ops[open..close + 1].iter().any(|op| match *op {
+                BrainFuckToken::StdIn => true,
+                _ => false,
+            }) {
+                continue;
+            }
+
+            let mut source = String::new();
+            for op in &ops[open..close + 1] {
+                op.write_source(&mut source);
+            }
+            let mut test = String::new();
+            writeln!(
+                test,
+                "# {} at op {}, {:.1}% of the run's steps",
+                token_run_to_string(&(open, close), ops).trim(),
+                open,
+                100.0 * cost as f64 / steps.max(1) as f64
+            ).ok();
+            writeln!(test, "loop = \"{}\"", source).ok();
+            writeln!(test, "cells = {}", cells).ok();
+            writeln!(test, "tape = {}", list(&sample.entry)).ok();
+            writeln!(test, "ptr = {}", sample.entry_ptr - sample.start).ok();
+            writeln!(test, "expected_tape = {}", list(exit)).ok();
+            writeln!(test, "expected_ptr = {}", exit_ptr - sample.start).ok();
+            writeln!(test, "expected_output = {}", list(&sample.output)).ok();
+
+            written += 1;
+            let path = std::path::Path::new(dir).join(format!("loop-{:02}.toml", written));
+            std::fs::write(path, test)?;
+            if written == LOOP_TESTS {
+                break;
+            }
+        }
+        Ok(written)
+    }
+}
+
+/// One pointer move recorded by a `PointerTrail`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrailMove {
+    pub step: u64,
+    pub op: usize,
+    pub from: usize,
+    pub by: isize,
+}
+
+#[cfg(feature = "std")]
+impl Display for TrailMove {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{} -> {} (op {})", self.from, self.from as isize + self.by, self.op)
+    }
+}
+
+/// The last few pointer moves of a run, to show where the pointer was
+/// headed when it fell off the tape. A move that failed is the last one.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct PointerTrail {
+    keep: usize,
+    moves: VecDeque<TrailMove>,
+}
+
+#[cfg(feature = "std")]
+impl PointerTrail {
+    pub fn new(keep: usize) -> PointerTrail {
+        PointerTrail {
+            keep: keep.max(1),
+            moves: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, step: u64, op: usize, from: usize, by: isize) {
+        if self.moves.len() == self.keep {
+            self.moves.pop_front();
+        }
+        self.moves.push_back(TrailMove {
+            step: step,
+            op: op,
+            from: from,
+            by: by,
+        });
+    }
+
+    /// The recorded moves, oldest first.
+    pub fn moves(&self) -> &VecDeque<TrailMove> {
+        &self.moves
+    }
+}
+
+#[cfg(feature = "cli")]
+const MAX_TRAJECTORY_SAMPLES: usize = 100_000;
+
+/// Samples the tape pointer every so many steps. When the samples outgrow
+/// `MAX_TRAJECTORY_SAMPLES` every other one is dropped and the interval
+/// doubled, so long runs still fit in memory.
+#[cfg(feature = "cli")]
+#[derive(Debug)]
+pub struct Trajectory {
+    every: u64,
+    samples: Vec<(u64, usize)>,
+}
+
+#[cfg(feature = "cli")]
+impl Trajectory {
+    pub fn new(every: u64) -> Trajectory {
+        Trajectory {
+            every: every.max(1),
+            samples: Vec::new(),
+        }
+    }
+
+    fn sample(&mut self, step: u64, ptr: usize) {
+        if step % self.every != 0 {
+            return;
+        }
+
+        self.samples.push((step, ptr));
+        if self.samples.len() > MAX_TRAJECTORY_SAMPLES {
+            self.every *= 2;
+            let every = self.every;
+            self.samples.retain(|&(step, _)| step % every == 0);
+        }
+    }
+
+    pub fn write_csv<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "step,pointer")?;
+        for &(step, ptr) in &self.samples {
+            writeln!(w, "{},{}", step, ptr)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_svg<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        let (width, height, margin) = (800.0, 400.0, 40.0);
+        let max_step = self.samples.last().map_or(1, |&(step, _)| step.max(1));
+        let max_ptr = self.samples.iter().map(|&(_, ptr)| ptr).max().unwrap_or(0).max(1);
+
+        let mut points = String::new();
+        for &(step, ptr) in &self.samples {
+            let x = margin + (width - 2.0 * margin) * step as f64 / max_step as f64;
+            let y = height - margin - (height - 2.0 * margin) * ptr as f64 / max_ptr as f64;
+            write!(points, "{:.1},{:.1} ", x, y).ok();
+        }
+
+        writeln!(
+            w,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" font-family="monospace" font-size="12">
+<rect width="100%" height="100%" fill="white"/>
+<line x1="{m}" y1="{b}" x2="{r}" y2="{b}" stroke="black"/>
+<line x1="{m}" y1="{m}" x2="{m}" y2="{b}" stroke="black"/>
+<text x="{m}" y="{m}" dy="-8">pointer (max {max_ptr})</text>
+<text x="{r}" y="{b}" dy="20" text-anchor="end">step {max_step}</text>
+<polyline fill="none" stroke="steelblue" points="{points}"/>
+</svg>"#,
+            w = width,
+            h = height,
+            m = margin,
+            r = width - margin,
+            b = height - margin,
+            max_ptr = max_ptr,
+            max_step = max_step,
+            points = points.trim_end()
+        )
+    }
+}
+
+/// Steps charged to each character of a program's source, for seeing which
+/// characters its running time goes to. An op runs on behalf of every
+/// command it was made from, so each of those is charged every time it
+/// runs: `+++` folded into one op costs each `+` one step per run.
+///
+/// ```
+/// use rustfuck::{optimize_mapped, parse, CostReport, Fuel, Program, DEFAULT_PASSES};
+///
+/// let source = "++[>+<-]";
+/// let (ops, map) = optimize_mapped(parse(source, 16), DEFAULT_PASSES, &mut Fuel::unlimited());
+/// let mut prog = Program::with_profile(ops, Default::default());
+/// prog.op_counts = Some(vec![0; prog.ops.len()]);
+/// prog.run(b"", &mut Vec::new());
+///
+/// let report = CostReport::new(source, prog.op_counts.as_ref().unwrap(), Some(&map));
+/// assert_eq!(report.costs(), [1, 1, 1, 2, 2, 2, 2, 2]);
+/// ```
+#[cfg(feature = "cli")]
+#[derive(Debug)]
+pub struct CostReport {
+    source: String,
+    /// Steps for each command in the source, in order.
+    costs: Vec<u64>,
+}
+
+#[cfg(feature = "cli")]
+impl CostReport {
+    /// Charges `counts`, how often each op ran, to the commands of
+    /// `source` the ops came from according to `map`, or one op per
+    /// command without one.
+    pub fn new(source: &str, counts: &[u64], map: Option<&OpMap>) -> CostReport {
+        let commands = source.chars().filter(|&c| BrainFuckToken::from_char(c).is_some()).count();
+        let mut costs = vec![0; commands];
+
+        for (op, &count) in counts.iter().enumerate() {
+            match map {
+                Some(map) => {
+                    for &token in map.origins(op) {
+                        costs[token] += count;
+                    }
+                }
+                None => costs[op] += count,
+            }
+        }
+
+        CostReport {
+            source: source.to_string(),
+            costs: costs,
+        }
+    }
+
+    /// Steps charged to each command, in source order.
+    pub fn costs(&self) -> &[u64] {
+        &self.costs
+    }
+
+    /// Each line of the source with the characters in it and their steps;
+    /// comments cost nothing.
+    fn lines(&self) -> Vec<Vec<(char, u64)>> {
+        let mut costs = self.costs.iter();
+        self.source
+            .lines()
+            .map(|line| {
+                line.chars()
+                    .map(|c| match BrainFuckToken::from_char(c) {
+                        Some(_) => (c, *costs.next().unwrap()),
+                        None => (c, 0),
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The source with each line's total steps in the margin.
+    pub fn write_text<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        let lines = self.lines();
+        let totals: Vec<u64> = lines.iter().map(|line| line.iter().map(|&(_, cost)| cost).sum()).collect();
+        let width = totals.iter().max().map_or(1, |max| max.to_string().len()).max("steps".len());
+
+        writeln!(w, "{:>width$} |", "steps", width = width)?;
+        for (line, total) in lines.iter().zip(&totals) {
+            let text: String = line.iter().map(|&(c, _)| c).collect();
+            writeln!(w, "{:>width$} | {}", total, text, width = width)?;
+        }
+        Ok(())
+    }
+
+    /// The source as an HTML page, each command shaded by its share of the
+    /// steps and showing its count on hover, with line totals in the margin.
+    pub fn write_html<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        let max = self.costs.iter().cloned().max().unwrap_or(0).max(1);
+        let meta = Metadata::from_header(&self.source);
+        let title = meta.title.as_ref().map_or("Cost report".to_string(), |title| html_escape(title));
+
+        writeln!(w, "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title></head><body>", title)?;
+        if !meta.is_empty() {
+            writeln!(w, "<h1>{}</h1>", title)?;
+            if let Some(ref author) = meta.author {
+                writeln!(w, "<p>By {}</p>", html_escape(author))?;
+            }
+            if let Some(ref input) = meta.input {
+                writeln!(w, "<p>Input: {}</p>", html_escape(input))?;
+            }
+        }
+        writeln!(w, "<pre style=\"font-family: monospace\">")?;
+        for line in self.lines() {
+            let total: u64 = line.iter().map(|&(_, cost)| cost).sum();
+            write!(w, "<span style=\"color: gray\">{:>12} | </span>", total)?;
+            for (c, cost) in line {
+                let escaped = html_escape(&c.to_string());
+                if BrainFuckToken::from_char(c).is_some() {
+                    let alpha = cost as f64 / max as f64;
+                    write!(
+                        w,
+                        "<span title=\"{}\" style=\"background: rgba(255, 0, 0, {:.3})\">{}</span>",
+                        plural(cost as usize, "step"),
+                        alpha,
+                        escaped
+                    )?;
+                } else {
+                    write!(w, "{}", escaped)?;
+                }
+            }
+            writeln!(w)?;
+        }
+        writeln!(w, "</pre></body></html>")
+    }
+}
+
+#[cfg(feature = "cli")]
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(feature = "cli")]
+const MAX_ANIMATION_FRAMES: usize = 500;
+/// Cells shown in each frame of an animation.
+#[cfg(feature = "cli")]
+const ANIMATION_WINDOW: usize = 16;
+/// Longest an animation may play, however many frames it has.
+#[cfg(feature = "cli")]
+const MAX_ANIMATION_SECONDS: f64 = 30.0;
+
+/// One frame of an animation: the step, the pointer, and the window of
+/// cells around it.
+#[cfg(feature = "cli")]
+#[derive(Debug)]
+struct Frame {
+    step: u64,
+    ptr: usize,
+    start: usize,
+    cells: Vec<i32>,
+}
+
+/// Snapshots the tape every so many steps and writes them out as an
+/// animated SVG. Like `Trajectory`, it halves the frames and doubles the
+/// interval whenever there are too many, so even long runs stay short.
+#[cfg(feature = "cli")]
+#[derive(Debug)]
+pub struct Animation {
+    every: u64,
+    frames: Vec<Frame>,
+}
+
+#[cfg(feature = "cli")]
+impl Animation {
+    pub fn new(every: u64) -> Animation {
+        Animation {
+            every: every.max(1),
+            frames: Vec::new(),
+        }
+    }
+
+    fn sample<C: Cell>(&mut self, step: u64, tape: &Tape<C>) {
+        if step % self.every != 0 {
+            return;
+        }
+
+        // The window pages along with the pointer rather than scrolling, so
+        // cells stay put between frames.
+        let start = tape.loc / ANIMATION_WINDOW * ANIMATION_WINDOW;
+        let end = (start + ANIMATION_WINDOW).min(tape.tape.len());
+        self.frames.push(Frame {
+            step: step,
+            ptr: tape.loc,
+            start: start,
+            cells: tape.values(start, end),
+        });
+        if self.frames.len() > MAX_ANIMATION_FRAMES {
+            self.every *= 2;
+            let every = self.every;
+            self.frames.retain(|frame| frame.step % every == 0);
+        }
+    }
+
+    pub fn write_svg<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        let (cell, margin) = (40.0, 20.0);
+        let width = 2.0 * margin + cell * ANIMATION_WINDOW as f64;
+        let height = 2.0 * margin + cell + 40.0;
+        let duration = (MAX_ANIMATION_SECONDS / self.frames.len().max(1) as f64).min(0.2);
+
+        writeln!(
+            w,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="monospace" font-size="12">
+<rect width="100%" height="100%" fill="white"/>"#,
+            width, height
+        )?;
+        for (n, frame) in self.frames.iter().enumerate() {
+            // Each frame shows for its slot; the last one stays up.
+            let show = if n + 1 == self.frames.len() {
+                format!(r#"<set attributeName="visibility" to="visible" begin="{:.3}s" fill="freeze"/>"#, n as f64 * duration)
+            } else {
+                format!(
+                    r#"<set attributeName="visibility" to="visible" begin="{:.3}s" dur="{:.3}s"/>"#,
+                    n as f64 * duration,
+                    duration
+                )
+            };
+            writeln!(w, r#"<g visibility="hidden">{}"#, show)?;
+            for (i, &value) in frame.cells.iter().enumerate() {
+                let x = margin + cell * i as f64;
+                let fill = if frame.start + i == frame.ptr { "gold" } else { "white" };
+                writeln!(
+                    w,
+                    r#"<rect x="{x}" y="{y}" width="{c}" height="{c}" fill="{fill}" stroke="black"/><text x="{tx}" y="{ty}" text-anchor="middle">{value}</text><text x="{tx}" y="{iy}" text-anchor="middle" fill="gray">{idx}</text>"#,
+                    x = x,
+                    y = margin,
+                    c = cell,
+                    fill = fill,
+                    tx = x + cell / 2.0,
+                    ty = margin + cell / 2.0 + 4.0,
+                    iy = margin + cell + 14.0,
+                    value = value,
+                    idx = frame.start + i
+                )?;
+            }
+            writeln!(
+                w,
+                r#"<text x="{}" y="{}">step {}, pointer {}</text></g>"#,
+                margin,
+                height - margin,
+                frame.step,
+                frame.ptr
+            )?;
+        }
+        writeln!(w, "</svg>")
+    }
+}
+
+/// Narrates every step in plain words for `--explain-steps`, indenting the
+/// steps run inside loops.
+#[cfg(feature = "cli")]
+#[derive(Debug, Default)]
+pub struct Explainer {
+    depth: usize,
+}
+
+#[cfg(feature = "cli")]
+impl Explainer {
+    /// Describes what `instr` did, given the pointer and its cell before it
+    /// ran and the tape after.
+    fn explain<C: Cell>(&mut self, step: u64, instr: &BrainFuckToken, ptr: usize, cell: i32, tape: &Tape<C>) -> String {
+        let now = tape.get();
+        let text = match *instr {
+            BrainFuckToken::JumpF(_) if cell == 0 => format!("cell {} is 0, skip the loop", ptr),
+            BrainFuckToken::JumpF(_) => format!("cell {} is {}, enter the loop", ptr, cell),
+            BrainFuckToken::JumpB(_) if cell == 0 => format!("cell {} is 0, leave the loop", ptr),
+            BrainFuckToken::JumpB(_) => format!("cell {} is {}, go round again", ptr, cell),
+            BrainFuckToken::Move(_) => format!("ptr {}→{}", ptr, tape.loc),
+            BrainFuckToken::Incr(_) | BrainFuckToken::ZeroOut => format!("cell {}: {}→{}", ptr, cell, now),
+            BrainFuckToken::MoveIncr(_, _) => format!("ptr {}→{}, cell {}: {}→{}", ptr, tape.loc, tape.loc, cell, now),
+            BrainFuckToken::StdIn => format!("read into cell {}: {}→{}", ptr, cell, now),
+            BrainFuckToken::IncrOut(_) => format!("cell {}: {}→{}, write {:?}", ptr, cell, now, tape.getc() as char),
+            BrainFuckToken::StdOut => format!("write {:?} from cell {}", tape.getc() as char, ptr),
+            BrainFuckToken::Out(n) => format!("write {:?} from cell {} {} times", tape.getc() as char, ptr, n),
+            BrainFuckToken::OutConst(b, n) => format!("write {:?} {} times", b as char, n),
+        };
+
+        if let BrainFuckToken::JumpB(_) = *instr {
+            self.depth = self.depth.saturating_sub(1);
+        }
+        let mut source = "  ".repeat(self.depth);
+        instr.write_source(&mut source);
+        let line = format!("{:>8}  {:<12}  {}", step, source, text);
+        match *instr {
+            BrainFuckToken::JumpF(_) if cell != 0 => self.depth += 1,
+            BrainFuckToken::JumpB(_) if cell != 0 => self.depth += 1,
+            _ => {}
+        }
+        line
+    }
+}
+
+#[cfg(feature = "cli")]
+#[derive(Debug, Clone, Copy)]
+enum Breakpoint {
+    /// Stop when the pointer arrives at this cell.
+    Reach(usize),
+    /// Stop when the pointer steps outside `start..end`.
+    Leave(usize, usize),
+}
+
+#[cfg(feature = "cli")]
+impl Breakpoint {
+    fn hit(&self, from: usize, to: usize) -> bool {
+        match *self {
+            Breakpoint::Reach(cell) => from != cell && to == cell,
+            Breakpoint::Leave(start, end) => {
+                let inside = |ptr| start <= ptr && ptr < end;
+                inside(from) && !inside(to)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "cli")]
+impl Display for Breakpoint {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match *self {
+            Breakpoint::Reach(cell) => write!(f, "pointer reached cell {}", cell),
+            Breakpoint::Leave(start, end) => write!(f, "pointer left cells {}..{}", start, end),
+        }
+    }
+}
+
+/// When an `--on-cell` trigger fires, judged on the cell's value before and
+/// after each step.
+#[cfg(feature = "cli")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Condition {
+    /// The cell changed at all.
+    Changed,
+    /// The cell went from at most this value to above it.
+    Above(i32),
+    /// The cell went from at least this value to below it.
+    Below(i32),
+}
+
+#[cfg(feature = "cli")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    /// Show the cells around the pointer.
+    Dump,
+    /// Just count how often the trigger fires.
+    Count,
+    /// Stop the program.
+    Abort,
+}
+
+/// Something to do when a cell changes, given as `CELL[>N|<N]=ACTION` on the
+/// command line: `42=dump`, `7>100=abort`, `0<1=count`.
+#[cfg(feature = "cli")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trigger {
+    pub cell: usize,
+    condition: Condition,
+    action: Action,
+}
+
+#[cfg(feature = "cli")]
+impl Trigger {
+    fn fires(&self, old: i32, new: i32) -> bool {
+        match self.condition {
+            Condition::Changed => old != new,
+            Condition::Above(x) => old <= x && new > x,
+            Condition::Below(x) => old >= x && new < x,
+        }
+    }
+}
+
+#[cfg(feature = "cli")]
+impl FromStr for Trigger {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Trigger, String> {
+        let bad = || format!("expected CELL[>N|<N]=dump|count|abort, got `{}`", s);
+        let eq = s.rfind('=').ok_or_else(bad)?;
+        let (when, action) = (&s[..eq], &s[eq + 1..]);
+
+        let action = match action {
+            "dump" => Action::Dump,
+            "count" => Action::Count,
+            "abort" => Action::Abort,
+            _ => return Err(bad()),
+        };
+        let (cell, condition) = match when.find(['>', '<']) {
+            Some(at) => {
+                let x = when[at + 1..].parse().map_err(|_| bad())?;
+                let condition = if &when[at..at + 1] == ">" { Condition::Above(x) } else { Condition::Below(x) };
+                (&when[..at], condition)
+            }
+            None => (when, Condition::Changed),
+        };
+
+        Ok(Trigger {
+            cell: cell.parse().map_err(|_| bad())?,
+            condition: condition,
+            action: action,
+        })
+    }
+}
+
+#[cfg(feature = "cli")]
+impl Display for Trigger {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self.condition {
+            Condition::Changed => write!(f, "cell {} changed", self.cell),
+            Condition::Above(x) => write!(f, "cell {} rose above {}", self.cell, x),
+            Condition::Below(x) => write!(f, "cell {} fell below {}", self.cell, x),
+        }
+    }
+}
+
+/// Called with a trigger that fired, the step and the cell's old and new
+/// values; returning false stops the program.
+#[cfg(feature = "cli")]
+pub type TriggerFn = dyn FnMut(&Trigger, u64, i32, i32) -> bool;
+
+/// The `--on-cell` triggers of a run, with the value each last saw and how
+/// often each has fired. Like breakpoints, but they act without stopping to
+/// ask.
+///
+/// ```
+/// use rustfuck::{HaltReason, Program, Trigger, Triggers};
+///
+/// let mut prog: Program = "+++>+".parse().unwrap();
+/// let triggers = vec!["0>1=count".parse::<Trigger>().unwrap()];
+/// let callback = Triggers::new(triggers, &prog.tape).unwrap().on_fire(|trigger, steps, _, new| {
+///     assert_eq!((trigger.cell, steps, new), (0, 1, 3));
+///     false
+/// });
+/// prog.triggers = Some(callback);
+/// assert!(prog.run(b"", &mut Vec::new()).halt != HaltReason::Completed);
+///
+/// assert!(Triggers::new(vec!["99999=count".parse().unwrap()], &prog.tape).is_err());
+/// ```
+#[cfg(feature = "cli")]
+pub struct Triggers {
+    triggers: Vec<Trigger>,
+    last: Vec<i32>,
+    fired: Vec<u64>,
+    callback: Option<Box<TriggerFn>>,
+}
+
+#[cfg(feature = "cli")]
+impl std::fmt::Debug for Triggers {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        f.debug_struct("Triggers")
+            .field("triggers", &self.triggers)
+            .field("last", &self.last)
+            .field("fired", &self.fired)
+            .finish()
+    }
+}
+
+#[cfg(feature = "cli")]
+impl Triggers {
+    /// Watches `triggers` on `tape`, or says which cell isn't on it.
+    pub fn new<C: Cell>(triggers: Vec<Trigger>, tape: &Tape<C>) -> std::result::Result<Triggers, String> {
+        if let Some(trigger) = triggers.iter().find(|t| t.cell >= tape.tape.len()) {
+            return Err(format!("cell {} is past the end of the tape of {} cells", trigger.cell, tape.tape.len()));
+        }
+        Ok(Triggers {
+            last: triggers.iter().map(|t| tape.value(t.cell)).collect(),
+            fired: vec![0; triggers.len()],
+            triggers: triggers,
+            callback: None,
+        })
+    }
+
+    /// Also calls `callback` whenever a trigger fires, after its action.
+    pub fn on_fire<F: FnMut(&Trigger, u64, i32, i32) -> bool + 'static>(mut self, callback: F) -> Triggers {
+        self.callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Called after every step; returns why to stop if an `abort` trigger
+    /// fired.
+    fn after_step<C: Cell>(&mut self, steps: u64, tape: &Tape<C>) -> Option<String> {
+        for (i, trigger) in self.triggers.iter().enumerate() {
+            let new = tape.value(trigger.cell);
+            let old = replace(&mut self.last[i], new);
+            if !trigger.fires(old, new) {
+                continue;
+            }
+            self.fired[i] += 1;
+
+            match trigger.action {
+                Action::Dump => status!("step {}: {} ({}→{}): {}", steps, trigger, old, new, tape.window(4)),
+                Action::Count => {}
+                Action::Abort => return Some(format!("{} ({}→{}) at step {}", trigger, old, new, steps)),
+            }
+            if let Some(ref mut callback) = self.callback {
+                if !callback(trigger, steps, old, new) {
+                    return Some(format!("{} ({}→{}) at step {}, stopped by a callback", trigger, old, new, steps));
+                }
+            }
+        }
+        None
+    }
+
+    pub fn summary(&self) -> String {
+        let lines: Vec<String> = self.triggers
+            .iter()
+            .zip(&self.fired)
+            .map(|(trigger, &fired)| format!("{}: {}", trigger, plural(fired as usize, "time")))
+            .collect();
+        lines.join("\n")
+    }
+}
+
+/// Behaviors Brainfuck interpreters disagree on, which `--audit` looks for.
+#[cfg(feature = "cli")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Quirk {
+    /// A cell went past its largest value or below zero and wrapped.
+    CellWrap,
+    /// A 32 bit cell went below zero, where an unsigned one would wrap.
+    NegativeCell,
+    /// The pointer went past the last cell and wrapped to the first.
+    PointerWrap,
+    /// `,` ran out of input.
+    EofRead,
+    /// `.` wrote a cell that doesn't fit in a byte.
+    WideOutput,
+}
+
+#[cfg(feature = "cli")]
+impl Quirk {
+    fn code(&self) -> &'static str {
+        match *self {
+            Quirk::CellWrap => "cell-wrap",
+            Quirk::NegativeCell => "negative-cell",
+            Quirk::PointerWrap => "pointer-wrap",
+            Quirk::EofRead => "eof-read",
+            Quirk::WideOutput => "wide-output",
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match *self {
+            Quirk::CellWrap => "cells wrapped around, which interpreters with wider cells won't do",
+            Quirk::NegativeCell => "cells went below zero, where 8 and 16 bit interpreters wrap",
+            Quirk::PointerWrap => "the pointer wrapped from the last cell to the first, where others grow the tape or crash",
+            Quirk::EofRead => "`,` read past the end of input, where interpreters store 0, -1 or nothing",
+            Quirk::WideOutput => "`.` wrote cells above 255 or below 0, which others print differently",
+        }
+    }
+}
+
+/// What `--audit` found: for each quirk the program relied on, how often,
+/// and the step and op of the first time.
+#[cfg(feature = "cli")]
+#[derive(Debug, Default)]
+pub struct Audit {
+    found: Vec<(Quirk, u64, u64, usize)>,
+}
+
+#[cfg(feature = "cli")]
+impl Audit {
+    fn record(&mut self, quirk: Quirk, steps: u64, loc: usize) {
+        match self.found.iter_mut().find(|f| f.0 == quirk) {
+            Some(found) => found.1 += 1,
+            None => self.found.push((quirk, 1, steps, loc)),
+        }
+    }
+
+    /// Called before every step, to check what it is about to do.
+    fn before_step<C: Cell>(&mut self, steps: u64, loc: usize, instr: &BrainFuckToken, tape: &Tape<C>, input_left: bool) {
+        let cell = tape.get();
+        match *instr {
+            BrainFuckToken::Incr(x) => self.check_incr(steps, loc, tape.mask, cell, x),
+            BrainFuckToken::IncrOut(x) => {
+                self.check_incr(steps, loc, tape.mask, cell, x);
+                self.check_output(steps, loc, cell.wrapping_add(x) & tape.mask);
+            }
+            BrainFuckToken::Move(x) => self.check_move(steps, loc, tape, x),
+            BrainFuckToken::MoveIncr(x, y) => {
+                self.check_move(steps, loc, tape, x);
+                let target = tape.loc as isize + x;
+                if target >= 0 {
+                    let target = target as usize % tape.tape.len();
+                    self.check_incr(steps, loc, tape.mask, tape.value(target), y);
+                }
+            }
+            BrainFuckToken::StdIn if !input_left => self.record(Quirk::EofRead, steps, loc),
+            BrainFuckToken::StdOut | BrainFuckToken::Out(_) => self.check_output(steps, loc, cell),
+            _ => {}
+        }
+    }
+
+    fn check_incr(&mut self, steps: u64, loc: usize, mask: i32, cell: i32, x: i32) {
+        let sum = cell as i64 + x as i64;
+        let quirk = if mask == !0 {
+            if sum > i32::max_value() as i64 || sum < i32::min_value() as i64 {
+                Some(Quirk::CellWrap)
+            } else if cell >= 0 && sum < 0 {
+                Some(Quirk::NegativeCell)
+            } else {
+                None
+            }
+        } else if sum < 0 || sum > mask as i64 {
+            Some(Quirk::CellWrap)
+        } else {
+            None
+        };
+
+        if let Some(quirk) = quirk {
+            self.record(quirk, steps, loc);
+        }
+    }
+
+    fn check_move<C: Cell>(&mut self, steps: u64, loc: usize, tape: &Tape<C>, x: isize) {
+        if x > 0 && tape.loc + x as usize >= tape.tape.len() {
+            self.record(Quirk::PointerWrap, steps, loc);
+        }
+    }
+
+    fn check_output(&mut self, steps: u64, loc: usize, cell: i32) {
+        if !(0..=255).contains(&cell) {
+            self.record(Quirk::WideOutput, steps, loc);
+        }
+    }
+
+    pub fn report(&self) -> String {
+        if self.found.is_empty() {
+            return "The program relied on nothing interpreters disagree on.".to_string();
+        }
+
+        let lines: Vec<String> = self.found
+            .iter()
+            .map(|&(quirk, count, steps, loc)| {
+                format!(
+                    "{}: {}, first at step {} (op {}); {}",
+                    quirk.code(),
+                    plural(count as usize, "time"),
+                    steps,
+                    loc,
+                    quirk.describe()
+                )
+            })
+            .collect();
+        lines.join("\n")
+    }
+}
+
+/// Loops touching more cells than this aren't memoized; the cache key
+/// would be too specific to ever hit.
+#[cfg(feature = "std")]
+const MEMO_MAX_CELLS: usize = 8;
+#[cfg(feature = "std")]
+pub const DEFAULT_MEMO_ENTRIES: usize = 4096;
+
+/// For `--memoize`: the cells each pure loop leaves behind for the cells
+/// it started from, so the loop can be skipped when the same start recurs.
+/// A loop is pure if it has no nested loops and no I/O and ends where it
+/// started, so the cells it touches are all it depends on.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct Memo {
+    /// The cells each pure loop touches, relative to the pointer, by the
+    /// position of its `[`.
+    loops: HashMap<usize, Vec<isize>>,
+    total_loops: usize,
+    /// The cells after a run of the loop, and the steps it took, by the
+    /// loop and the cells before it. Starts over once full.
+    cache: HashMap<(usize, Vec<i32>), (Vec<i32>, u64)>,
+    capacity: usize,
+    /// The loop being run because it missed, with its key and the step
+    /// count it was entered at.
+    pending: Option<(usize, Vec<i32>, u64)>,
+    hits: u64,
+    misses: u64,
+    steps_skipped: u64,
+}
+
+#[cfg(feature = "std")]
+impl Memo {
+    pub fn new(ops: &[BrainFuckToken], capacity: usize) -> Memo {
+        let effects = loop_effects(ops);
+        let total_loops = effects.len();
+        let loops = effects
+            .into_iter()
+            .filter(|&(_, ref effect)| effect.shift == Some(0) && !effect.io && !effect.clobbers_unknown)
+            .map(|(open, effect)| {
+                let mut cells: Vec<isize> = effect.cleared.iter().chain(effect.deltas.iter().map(|d| &d.0)).cloned().collect();
+                cells.push(0);
+                cells.sort();
+                cells.dedup();
+                (open, cells)
+            })
+            .filter(|&(_, ref cells)| cells.len() <= MEMO_MAX_CELLS)
+            .collect();
+
+        Memo {
+            loops: loops,
+            total_loops: total_loops,
+            cache: HashMap::new(),
+            capacity: capacity,
+            pending: None,
+            hits: 0,
+            misses: 0,
+            steps_skipped: 0,
+        }
+    }
+
+    /// The loop at `open` was entered at step `steps`. If its cells were
+    /// seen before, and skipping the steps it took wouldn't jump past
+    /// `checkpoint`, sets them as the loop would and returns those steps.
+    fn enter<C: Cell>(&mut self, open: usize, tape: &mut Tape<C>, steps: u64, checkpoint: u64) -> Option<u64> {
+        let cells = self.loops.get(&open)?;
+        let at: Vec<usize> = cells.iter().map(|&off| tape.loc.wrapping_add(off as usize)).collect();
+        if at.iter().any(|&idx| idx >= tape.tape.len()) {
+            return None;
+        }
+        let key = (open, at.iter().map(|&idx| tape.value(idx)).collect());
+
+        match self.cache.get(&key) {
+            Some(&(ref after, cost)) if steps + cost <= checkpoint => {
+                for (&idx, &value) in at.iter().zip(after) {
+                    tape.tape[idx] = C::from_i32(value);
+                }
+                self.hits += 1;
+                self.steps_skipped += cost;
+                Some(cost)
+            }
+            _ => {
+                self.misses += 1;
+                self.pending = Some((key.0, key.1, steps));
+                None
+            }
+        }
+    }
+
+    /// The loop at `open` was left at step `steps`; caches what it did if
+    /// it was run because it missed.
+    fn leave<C: Cell>(&mut self, open: usize, tape: &Tape<C>, steps: u64) {
+        match self.pending {
+            Some((pending, _, _)) if pending == open => {}
+            _ => return,
+        }
+        let (open, key, entered) = self.pending.take().unwrap();
+        let after = self.loops[&open]
+            .iter()
+            .map(|&off| tape.value(tape.loc.wrapping_add(off as usize)))
+            .collect();
+        if self.cache.len() == self.capacity {
+            self.cache.clear();
+        }
+        self.cache.insert((open, key), (after, steps - entered));
+    }
+
+    pub fn report(&self) -> String {
+        let lookups = self.hits + self.misses;
+        format!(
+            "{} of {} pure; hits: {}, misses: {} ({:.1}% hit rate), steps skipped: {}, {} cached",
+            self.loops.len(),
+            plural(self.total_loops, "loop"),
+            self.hits,
+            self.misses,
+            100.0 * self.hits as f64 / lookups.max(1) as f64,
+            self.steps_skipped,
+            plural(self.cache.len(), "result")
+        )
+    }
+}
+
+#[cfg(feature = "cli")]
+const DEBUGGER_HELP: &'static str = "Commands:
+    break ptr N         stop when the pointer reaches cell N
+    break leave A..B    stop when the pointer leaves cells A up to B
+    delete              remove all breakpoints
+    step, s             execute one instruction
+    continue, c         run until the next breakpoint
+    tape, t             show the cells around the pointer
+    quit, q             stop the program";
+
+/// Interactive debugger driven from stdin. Breakpoints are expressed in terms
+/// of the tape pointer since instruction positions mean little to authors.
+#[cfg(feature = "cli")]
+#[derive(Debug)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    stepping: bool,
+    last_ptr: usize,
+}
+
+#[cfg(feature = "cli")]
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            stepping: true,
+            last_ptr: 0,
+        }
+    }
+
+    /// Called before every instruction; returns false if the user asked to
+    /// stop the program.
+    fn before_step<C: Cell>(&mut self, loc: usize, instr: &BrainFuckToken, tape: &Tape<C>) -> bool {
+        let (from, to) = (self.last_ptr, tape.loc);
+        self.last_ptr = to;
+
+        if let Some(bp) = self.breakpoints.iter().find(|bp| bp.hit(from, to)) {
+            status!("\nbreakpoint: {}", bp);
+            self.stepping = true;
+        }
+
+        if !self.stepping {
+            return true;
+        }
+
+        status!("\n[{}]{}  ptr={} cell={}", loc, instr, tape.loc, tape.get());
+        self.prompt(tape)
+    }
+
+    fn prompt<C: Cell>(&mut self, tape: &Tape<C>) -> bool {
+        use std::io::BufRead;
+
+        let stdin = io::stdin();
+        loop {
+            eprint!("(bf) ");
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                return false;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+
+            match words.as_slice() {
+                ["step"] | ["s"] => return true,
+                ["continue"] | ["c"] => {
+                    self.stepping = false;
+                    return true;
+                }
+                ["quit"] | ["q"] => return false,
+                ["tape"] | ["t"] => status!("{}", tape.window(5)),
+                ["delete"] => self.breakpoints.clear(),
+                ["break", "ptr", cell] => match cell.parse() {
+                    Ok(cell) => self.breakpoints.push(Breakpoint::Reach(cell)),
+                    Err(_) => status!("not a cell: {}", cell),
+                },
+                ["break", "leave", range] => match parse_range(range) {
+                    Some((start, end)) => self.breakpoints.push(Breakpoint::Leave(start, end)),
+                    None => status!("not a range: {}", range),
+                },
+                [] => {}
+                _ => status!("{}", DEBUGGER_HELP),
+            }
+        }
+    }
+}
+
+/// Parses `A..B` into its bounds.
+fn parse_range(s: &str) -> Option<(usize, usize)> {
+    let mut parts = s.splitn(2, "..");
+    match (parts.next(), parts.next()) {
+        (Some(start), Some(end)) => match (start.parse(), end.parse()) {
+            (Ok(start), Ok(end)) => Some((start, end)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+pub fn token_run_to_string(locs: &JumpLocs, ops: &Tokens) -> String {
+    let (start, finish) = *locs;
+    let mut s = String::with_capacity(finish - start + 1);
+
+    for token in &ops[start..finish + 1] {
+        write!(s, "{}", token).ok();
+    }
+
+    s
+}
+
+/// Number of bits in a tape cell; arithmetic wraps at that width.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CellWidth {
+    Bits8,
+    Bits16,
+    Bits32,
+}
+
+impl CellWidth {
+    pub fn mask(&self) -> i32 {
+        match *self {
+            CellWidth::Bits8 => 0xFF,
+            CellWidth::Bits16 => 0xFFFF,
+            CellWidth::Bits32 => !0,
+        }
+    }
+}
+
+impl FromStr for CellWidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<CellWidth, String> {
+        match s {
+            "8" => Ok(CellWidth::Bits8),
+            "16" => Ok(CellWidth::Bits16),
+            "32" => Ok(CellWidth::Bits32),
+            _ => Err(format!("unsupported cell width `{}`", s)),
+        }
+    }
+}
+
+impl Display for CellWidth {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let bits = match *self {
+            CellWidth::Bits8 => 8,
+            CellWidth::Bits16 => 16,
+            CellWidth::Bits32 => 32,
+        };
+        write!(f, "{}", bits)
+    }
+}
+
+/// What `,` stores once the input has run out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Eof {
+    Zero,
+    MinusOne,
+    Unchanged,
+    /// Reading past the end is an error, for test harnesses that supply
+    /// exactly the input a program should need.
+    Strict,
+}
+
+impl FromStr for Eof {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Eof, String> {
+        match s {
+            "zero" => Ok(Eof::Zero),
+            "minus-one" => Ok(Eof::MinusOne),
+            "unchanged" => Ok(Eof::Unchanged),
+            "strict" => Ok(Eof::Strict),
+            _ => Err(format!("unknown EOF behavior `{}`", s)),
+        }
+    }
+}
+
+/// What happens when a jump sends the instruction pointer past the end of
+/// the program, rather than just running off it after the last op.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpEnd {
+    /// Stop as if the program had finished, as some variants allow.
+    Halt,
+    /// Refuse to run a program with any jump out of range.
+    Error,
+}
+
+impl FromStr for IpEnd {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<IpEnd, String> {
+        match s {
+            "halt" => Ok(IpEnd::Halt),
+            "error" => Ok(IpEnd::Error),
+            _ => Err(format!("unknown end-of-program behavior `{}`", s)),
+        }
+    }
+}
+
+/// The interpreter semantics that vary between Brainfuck implementations.
+#[derive(Debug, Clone, Copy)]
+pub struct Profile {
+    pub cells: CellWidth,
+    pub eof: Eof,
+    pub ip_end: IpEnd,
+    pub tape: usize,
+    /// Read input as UTF-8 characters, one per `,`, and write output
+    /// bytes as Latin-1 characters, instead of plain bytes both ways.
+    pub char_io: bool,
+    /// Extend the tape when the pointer moves past its right end, instead
+    /// of wrapping around to the start.
+    pub grow: bool,
+}
+
+impl Default for Profile {
+    fn default() -> Profile {
+        Profile {
+            cells: CellWidth::Bits32,
+            eof: Eof::Zero,
+            ip_end: IpEnd::Error,
+            tape: DEFAULT_TAPE_SIZE,
+            char_io: false,
+            grow: false,
+        }
+    }
+}
+
+/// Execution budgets a program can ship with via `@limit` pragmas, e.g.
+///
+/// ```text
+/// @limit steps=1e9 tape=65536
+/// ```
+///
+/// Pragmas are only read from the lines before the first one with code on
+/// it. Limits given on the command line take precedence.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    pub steps: Option<u64>,
+    pub tape: Option<usize>,
+}
+
+impl Limits {
+    pub fn from_pragmas(source: &str) -> std::result::Result<Limits, Diagnostic> {
+        let mut limits = Limits::default();
+
+        for (line_no, raw) in source.lines().enumerate() {
+            let line = raw.trim();
+            if !line.starts_with("@limit") {
+                if line.chars().any(|c| "+-<>[].,".contains(c)) {
+                    break;
+                }
+                continue;
+            }
+
+            for setting in line["@limit".len()..].split_whitespace() {
+                let mut parts = setting.splitn(2, '=');
+                let (key, value) = (parts.next().unwrap(), parts.next().and_then(parse_count));
+                match (key, value) {
+                    ("steps", Some(n)) => limits.steps = Some(n),
+                    ("tape", Some(n)) if n > 0 => limits.tape = Some(n as usize),
+                    _ => {
+                        return Err(Diagnostic {
+                            code: "invalid-pragma",
+                            level: Level::Deny,
+                            span: Some(Span {
+                                line: line_no + 1,
+                                column: raw[..raw.find(setting).unwrap()].chars().count() + 1,
+                                // `setting` is a slice of `source`.
+                                offset: setting.as_ptr() as usize - source.as_ptr() as usize,
+                            }),
+                            message: format!("invalid @limit setting `{}`", setting),
+                            notes: Vec::new(),
+                        })
+                    }
+                }
+            }
+        }
+
+        Ok(limits)
+    }
+
+    /// Fills in any limit not set here from `fallback`.
+    pub fn or(self, fallback: Limits) -> Limits {
+        Limits {
+            steps: self.steps.or(fallback.steps),
+            tape: self.tape.or(fallback.tape),
+        }
+    }
+}
+
+/// What a program says about itself in the comment lines at its top:
+///
+/// ```text
+/// Title: ROT13 filter
+/// Author: Jane Doe
+/// Input: any text, read until EOF
+/// ```
+///
+/// Without a `Title:` line the first line of the header is the title, and
+/// without an `Author:` line one ending in "by" and a name gives the
+/// author. Like pragmas, the header ends at the first line with code on it.
+///
+/// ```
+/// let meta = rustfuck::Metadata::from_header("A fractal viewer written by Erik Bosman\n+[>+]");
+/// assert_eq!(meta.title.as_ref().unwrap(), "A fractal viewer written by Erik Bosman");
+/// assert_eq!(meta.author.as_ref().unwrap(), "Erik Bosman");
+/// assert_eq!(meta.input, None);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    /// What the program expects to read.
+    pub input: Option<String>,
+}
+
+impl Metadata {
+    pub fn from_header(source: &str) -> Metadata {
+        let mut meta = Metadata::default();
+        let mut first_line = None;
+        let mut byline = None;
+
+        for line in source.lines().map(str::trim) {
+            if line.chars().any(|c| "+-<>[].,".contains(c)) {
+                break;
+            }
+            if line.is_empty() || line.starts_with('@') {
+                continue;
+            }
+
+            let (key, value) = match line.find(':') {
+                Some(colon) => (line[..colon].trim().to_lowercase(), line[colon + 1..].trim().to_string()),
+                None => (String::new(), String::new()),
+            };
+            match key.as_str() {
+                "title" => meta.title = Some(value),
+                "author" => meta.author = Some(value),
+                "input" => meta.input = Some(value),
+                _ => {
+                    first_line = first_line.or_else(|| Some(line.to_string()));
+                    byline = byline.or_else(|| author_in(line));
+                }
+            }
+        }
+
+        meta.title = meta.title.or(first_line);
+        meta.author = meta.author.or(byline);
+        meta
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Metadata::default()
+    }
+}
+
+/// The name in a line ending "by Some Name", of one to three capitalized
+/// words.
+fn author_in(line: &str) -> Option<String> {
+    let name = &line[line.rfind(" by ")? + 4..];
+    let words: Vec<&str> = name.split_whitespace().collect();
+    let capitalized = words.iter().all(|word| word.chars().next().map_or(false, char::is_uppercase));
+    if !words.is_empty() && words.len() <= 3 && capitalized {
+        Some(words.join(" "))
+    } else {
+        None
+    }
+}
+
+/// Parses a count written either plainly or in `1e9` notation.
+fn parse_count(s: &str) -> Option<u64> {
+    let mut parts = s.splitn(2, ['e', 'E']);
+    let mantissa: u64 = parts.next()?.parse().ok()?;
+    match parts.next() {
+        Some(exp) => 10u64.checked_pow(exp.parse().ok()?)?.checked_mul(mantissa),
+        None => Some(mantissa),
+    }
+}
+
+/// A file to load onto the tape before running, written `file.bin[,offset]`.
+#[derive(Debug, Clone)]
+pub struct TapeImage {
+    pub path: String,
+    pub offset: usize,
+}
+
+impl FromStr for TapeImage {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<TapeImage, String> {
+        match s.rfind(',') {
+            Some(comma) => Ok(TapeImage {
+                path: s[..comma].to_string(),
+                offset: s[comma + 1..].parse().map_err(|_| format!("bad offset in `{}`", s))?,
+            }),
+            None => Ok(TapeImage {
+                path: s.to_string(),
+                offset: 0,
+            }),
+        }
+    }
+}
+
+/// Where to write the final tape, written `file.bin[:start..end]`. Without
+/// a range the whole tape is written.
+#[derive(Debug, Clone)]
+pub struct TapeDump {
+    pub path: String,
+    pub range: Option<(usize, usize)>,
+}
+
+impl FromStr for TapeDump {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<TapeDump, String> {
+        let split = s.rfind(':').and_then(|colon| parse_range(&s[colon + 1..]).map(|range| (colon, range)));
+        match split {
+            Some((_, (start, end))) if start > end => Err(format!("range {}..{} ends before it starts", start, end)),
+            Some((colon, range)) => Ok(TapeDump {
+                path: s[..colon].to_string(),
+                range: Some(range),
+            }),
+            None => Ok(TapeDump {
+                path: s.to_string(),
+                range: None,
+            }),
+        }
+    }
+}
+
+/// Parses a cell value written in decimal or, with a `0x` prefix, in hex.
+pub fn parse_cell_value(s: &str) -> Option<i32> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        i32::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// What a tape stores its cells as. Values go in and out as `i32` and are
+/// wrapped to the narrower of the type and the tape's `CellWidth`.
+pub trait Cell: Copy + Default + std::fmt::Debug {
+    /// The bits of an `i32` the type can hold.
+    const MASK: i32;
+
+    fn from_i32(x: i32) -> Self;
+    fn to_i32(self) -> i32;
+}
+
+impl Cell for Wrapping<u8> {
+    const MASK: i32 = 0xFF;
+
+    fn from_i32(x: i32) -> Wrapping<u8> {
+        Wrapping(x as u8)
+    }
+
+    fn to_i32(self) -> i32 {
+        self.0 as i32
+    }
+}
+
+impl Cell for u16 {
+    const MASK: i32 = 0xFFFF;
+
+    fn from_i32(x: i32) -> u16 {
+        x as u16
+    }
+
+    fn to_i32(self) -> i32 {
+        self as i32
+    }
+}
+
+impl Cell for u32 {
+    const MASK: i32 = !0;
+
+    fn from_i32(x: i32) -> u32 {
+        x as u32
+    }
+
+    fn to_i32(self) -> i32 {
+        self as i32
+    }
+}
+
+impl Cell for i32 {
+    const MASK: i32 = !0;
+
+    #[inline(always)]
+    fn from_i32(x: i32) -> i32 {
+        x
+    }
+
+    #[inline(always)]
+    fn to_i32(self) -> i32 {
+        self
+    }
+}
+
+/// The cells and the pointer into them. `Program` runs on a `Tape<i32>` by
+/// default and takes the width from its profile, so one binary behaves the
+/// same whatever the width; `Program::with_cells` runs on a narrower `C`,
+/// which saves memory where the width is fixed ahead of time.
+///
+/// ```
+/// use rustfuck::{CellWidth, Tape};
+/// use std::num::Wrapping;
+///
+/// let mut tape: Tape<Wrapping<u8>> = Tape::new(CellWidth::Bits32, 4);
+/// tape.incr(-1);
+/// assert_eq!(tape.get(), 255);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Tape<C: Cell = i32> {
+    pub loc: usize,
+    mask: i32,
+    pub tape: Vec<C>,
+    grow: bool,
+    max_loc: usize,
+}
+
+impl<C: Cell> Tape<C> {
+    pub fn new(cells: CellWidth, size: usize) -> Tape<C> {
+        Tape {
+            loc: 0,
+            mask: cells.mask() & C::MASK,
+            tape: vec![C::default(); size],
+            grow: false,
+            max_loc: 0,
+        }
+    }
+
+    /// A tape that starts with `size` cells and doubles whenever the
+    /// pointer moves past its right end.
+    pub fn growable(cells: CellWidth, size: usize) -> Tape<C> {
+        Tape {
+            grow: true,
+            ..Tape::new(cells, size.max(1))
+        }
+    }
+
+    /// Moving past the right end wraps around to the start, or extends a
+    /// growable tape. Moving left of the first cell is refused: the pointer
+    /// stays put and this returns false.
+    #[inline(always)]
+    pub fn move_(&mut self, move_: isize) -> bool {
+        let loc = self.loc.wrapping_add(move_ as usize);
+        if loc < self.tape.len() {
+            self.loc = loc;
+            self.max_loc = self.max_loc.max(loc);
+            true
+        } else {
+            self.wrap(loc, move_)
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn wrap(&mut self, loc: usize, move_: isize) -> bool {
+        if move_ < 0 {
+            return false;
+        }
+        if self.grow {
+            let len = (loc + 1).max(self.tape.len() * 2);
+            self.tape.resize(len, C::default());
+            self.loc = loc;
+            self.max_loc = loc;
+            return true;
+        }
+        self.loc = loc % self.tape.len();
+        true
+    }
+
+    /// The rightmost cell the pointer has been on.
+    pub fn max_loc(&self) -> usize {
+        self.max_loc
+    }
+
+    #[inline(always)]
+    pub fn incr(&mut self, inc: i32) {
+        let cell = &mut self.tape[self.loc];
+        *cell = C::from_i32(cell.to_i32().wrapping_add(inc) & self.mask);
+    }
+
+    pub fn get(&self) -> i32 {
+        self.tape[self.loc].to_i32()
+    }
+
+    /// The value of the cell at `idx`, wherever the pointer is.
+    #[cfg(feature = "std")]
+    fn value(&self, idx: usize) -> i32 {
+        self.tape[idx].to_i32()
+    }
+
+    /// The values of the cells from `start` up to `end`.
+    #[cfg(feature = "std")]
+    fn values(&self, start: usize, end: usize) -> Vec<i32> {
+        self.tape[start..end].iter().map(|&cell| cell.to_i32()).collect()
+    }
+
+    #[cfg(feature = "std")]
+    fn getc(&self) -> u8 {
+        self.get() as u8
+    }
+
+    /// `get`, without the bounds check.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be on the tape. Only `move_` changes it inside a run
+    /// and it never leaves the tape, so checking once at the start will do.
+    #[cfg(feature = "unchecked")]
+    #[inline(always)]
+    unsafe fn get_unchecked(&self) -> i32 {
+        self.tape.get_unchecked(self.loc).to_i32()
+    }
+
+    /// `put`, without the bounds check. Safe under the same condition as
+    /// `get_unchecked`.
+    #[cfg(feature = "unchecked")]
+    #[inline(always)]
+    unsafe fn put_unchecked(&mut self, x: i32) {
+        *self.tape.get_unchecked_mut(self.loc) = C::from_i32(x & self.mask);
+    }
+
+    /// `incr`, without the bounds check. Safe under the same condition as
+    /// `get_unchecked`.
+    #[cfg(feature = "unchecked")]
+    #[inline(always)]
+    unsafe fn incr_unchecked(&mut self, inc: i32) {
+        let cell = self.tape.get_unchecked_mut(self.loc);
+        *cell = C::from_i32(cell.to_i32().wrapping_add(inc) & self.mask);
+    }
+
+    pub fn put(&mut self, x: i32) {
+        self.tape[self.loc] = C::from_i32(x & self.mask);
+    }
+
+    pub fn fill(&mut self, x: i32) {
+        let x = C::from_i32(x & self.mask);
+        for cell in self.tape.iter_mut() {
+            *cell = x;
+        }
+    }
+
+    /// Copies `bytes` into the tape starting at cell `offset`, leaving the
+    /// tape alone if they don't fit.
+    pub fn load(&mut self, bytes: &[u8], offset: usize) -> std::result::Result<(), String> {
+        let end = offset.saturating_add(bytes.len());
+        if end > self.tape.len() {
+            return Err(format!(
+                "{} bytes at offset {} don't fit on a tape of {} cells",
+                bytes.len(),
+                offset,
+                self.tape.len()
+            ));
+        }
+
+        for (cell, &byte) in self.tape[offset..end].iter_mut().zip(bytes) {
+            *cell = C::from_i32(byte as i32 & self.mask);
+        }
+        Ok(())
+    }
+
+    /// The cells from `start` up to `end` as bytes, or an error if that
+    /// isn't a range of the tape.
+    pub fn dump(&self, start: usize, end: usize) -> std::result::Result<Vec<u8>, String> {
+        if start > end || end > self.tape.len() {
+            return Err(format!("range {}..{} is outside the tape of {} cells", start, end, self.tape.len()));
+        }
+
+        Ok(self.tape[start..end].iter().map(|&cell| cell.to_i32() as u8).collect())
+    }
+
+    /// Renders the cells within `radius` of the pointer, marking the current one.
+    pub fn window(&self, radius: usize) -> String {
+        let start = self.loc.saturating_sub(radius);
+        let end = (self.loc + radius + 1).min(self.tape.len());
+        let mut s = String::new();
+
+        for idx in start..end {
+            if idx == self.loc {
+                write!(s, "[{}:{}] ", idx, self.tape[idx].to_i32()).ok();
+            } else {
+                write!(s, "{}:{} ", idx, self.tape[idx].to_i32()).ok();
+            }
+        }
+
+        s.trim_end().to_string()
+    }
+}
+
+/// What an interpreter loop needs from the cells a program's pointer moves
+/// over, so other layouts than `Tape` can be run with `TapeWalker`.
+pub trait Memory {
+    /// The cell under the pointer.
+    fn get(&self) -> i32;
+
+    /// Stores `x` in the cell under the pointer, wrapped to the cell width.
+    fn put(&mut self, x: i32);
+
+    /// Moves the pointer, returning false and staying put if the memory
+    /// has no cell there.
+    fn move_by(&mut self, by: isize) -> bool;
+
+    fn incr(&mut self, by: i32) {
+        let x = self.get();
+        self.put(x.wrapping_add(by));
+    }
+
+    /// Why the last op was refused, if it was. `TapeWalker` halts with it as
+    /// a runtime error.
+    fn take_fault(&mut self) -> Option<String> {
+        None
+    }
+}
+
+impl<C: Cell> Memory for Tape<C> {
+    #[inline(always)]
+    fn get(&self) -> i32 {
+        Tape::get(self)
+    }
+
+    #[inline(always)]
+    fn put(&mut self, x: i32) {
+        Tape::put(self, x)
+    }
+
+    #[inline(always)]
+    fn move_by(&mut self, by: isize) -> bool {
+        self.move_(by)
+    }
+
+    #[inline(always)]
+    fn incr(&mut self, by: i32) {
+        Tape::incr(self, by)
+    }
+}
+
+/// Cells kept only once they're nonzero, unbounded in both directions, for
+/// programs that scatter a few values far apart.
+#[derive(Debug, Clone)]
+pub struct SparseTape {
+    pub loc: isize,
+    mask: i32,
+    cells: BTreeMap<isize, i32>,
+}
+
+impl SparseTape {
+    pub fn new(cells: CellWidth) -> SparseTape {
+        SparseTape {
+            loc: 0,
+            mask: cells.mask(),
+            cells: BTreeMap::new(),
+        }
+    }
+
+    /// How many cells are nonzero.
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+}
+
+impl Memory for SparseTape {
+    fn get(&self) -> i32 {
+        self.cells.get(&self.loc).cloned().unwrap_or(0)
+    }
+
+    fn put(&mut self, x: i32) {
+        match x & self.mask {
+            0 => self.cells.remove(&self.loc),
+            x => self.cells.insert(self.loc, x),
+        };
+    }
+
+    fn move_by(&mut self, by: isize) -> bool {
+        self.loc = self.loc.wrapping_add(by);
+        true
+    }
+}
+
+/// Host memory mapped onto part of a `MappedTape`.
+#[derive(Debug)]
+enum Host<'a> {
+    ReadOnly(&'a [u8]),
+    ReadWrite(&'a mut [u8]),
+}
+
+impl<'a> Host<'a> {
+    fn len(&self) -> usize {
+        match *self {
+            Host::ReadOnly(bytes) => bytes.len(),
+            Host::ReadWrite(ref bytes) => bytes.len(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Region<'a> {
+    offset: usize,
+    host: Host<'a>,
+}
+
+/// Why `MappedTape` refused to map a buffer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegionError {
+    /// The buffer would run past the end of the tape.
+    OutOfBounds { offset: usize, len: usize, tape: usize },
+    /// The buffer would share cells with the region mapped at `other`.
+    Overlaps { offset: usize, other: usize },
+}
+
+impl Display for RegionError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match *self {
+            RegionError::OutOfBounds { offset, len, tape } => {
+                write!(f, "{} cells at {} don't fit on a tape of {} cells", len, offset, tape)
+            }
+            RegionError::Overlaps { offset, other } => {
+                write!(f, "the region at {} overlaps the one mapped at {}", offset, other)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RegionError {}
+
+/// A `Tape` with host buffers mapped over some of its cells, so a program
+/// run by `TapeWalker` reads and writes them in place. Mapped cells are
+/// bytes whatever the tape's cell width. A write to a read-only one leaves
+/// it alone and halts the run with a runtime error.
+///
+/// ```
+/// use rustfuck::{optimize, parse, CellWidth, Eof, Fuel, HaltReason, MappedTape, Tape, TapeWalker, DEFAULT_PASSES};
+///
+/// let ir = optimize(parse("[>+<-]>>.", 100), DEFAULT_PASSES, &mut Fuel::unlimited());
+/// let (mut from, to) = ([3u8], [42u8]);
+/// let mut tape = MappedTape::new(Tape::new(CellWidth::Bits8, 30000));
+/// tape.map_region(0, &mut from).unwrap();
+/// tape.map_region_readonly(2, &to).unwrap();
+///
+/// let mut walker = TapeWalker::new(tape, Eof::Zero);
+/// let mut out = Vec::new();
+/// let result = walker.run_with(&ir, &mut [].iter(), &mut out);
+/// assert_eq!(result.halt, HaltReason::Completed);
+/// assert_eq!(out, [42]);
+/// assert_eq!(walker.memory.tape.tape[1], 3);
+///
+/// let ir = optimize(parse(">>+", 100), DEFAULT_PASSES, &mut Fuel::unlimited());
+/// walker.memory.tape.loc = 0;
+/// let result = walker.run_with(&ir, &mut [].iter(), &mut out);
+/// assert!(matches!(result.halt, HaltReason::RuntimeError(_)));
+/// drop(walker);
+/// assert_eq!((from, to), ([0], [42]));
+/// ```
+#[derive(Debug)]
+pub struct MappedTape<'a> {
+    pub tape: Tape,
+    regions: Vec<Region<'a>>,
+    fault: Option<String>,
+}
+
+impl<'a> MappedTape<'a> {
+    pub fn new(tape: Tape) -> MappedTape<'a> {
+        MappedTape {
+            tape: tape,
+            regions: Vec::new(),
+            fault: None,
+        }
+    }
+
+    /// Maps `bytes` over the cells from `offset`, for the program to read
+    /// and write.
+    pub fn map_region(&mut self, offset: usize, bytes: &'a mut [u8]) -> std::result::Result<(), RegionError> {
+        self.map(offset, Host::ReadWrite(bytes))
+    }
+
+    /// Maps `bytes` over the cells from `offset`, for the program to read
+    /// only.
+    pub fn map_region_readonly(&mut self, offset: usize, bytes: &'a [u8]) -> std::result::Result<(), RegionError> {
+        self.map(offset, Host::ReadOnly(bytes))
+    }
+
+    fn map(&mut self, offset: usize, host: Host<'a>) -> std::result::Result<(), RegionError> {
+        let len = host.len();
+        let end = offset.checked_add(len);
+        if end.map_or(true, |end| end > self.tape.tape.len()) {
+            return Err(RegionError::OutOfBounds {
+                offset: offset,
+                len: len,
+                tape: self.tape.tape.len(),
+            });
+        }
+        let end = offset + len;
+        if let Some(other) = self.regions.iter().find(|r| offset < r.offset + r.host.len() && r.offset < end) {
+            return Err(RegionError::Overlaps {
+                offset: offset,
+                other: other.offset,
+            });
+        }
+        self.regions.push(Region { offset: offset, host: host });
+        Ok(())
+    }
+
+    /// The region under the pointer, and where in it the pointer is.
+    fn region(&mut self) -> Option<(&mut Host<'a>, usize)> {
+        let loc = self.tape.loc;
+        self.regions
+            .iter_mut()
+            .find(|r| r.offset <= loc && loc < r.offset + r.host.len())
+            .map(|r| (&mut r.host, loc - r.offset))
+    }
+}
+
+impl<'a> Memory for MappedTape<'a> {
+    fn get(&self) -> i32 {
+        let loc = self.tape.loc;
+        let region = self.regions.iter().find(|r| r.offset <= loc && loc < r.offset + r.host.len());
+        match region {
+            Some(&Region { offset, host: Host::ReadOnly(bytes) }) => bytes[loc - offset] as i32,
+            Some(&Region { offset, host: Host::ReadWrite(ref bytes) }) => bytes[loc - offset] as i32,
+            None => self.tape.get(),
+        }
+    }
+
+    fn put(&mut self, x: i32) {
+        let loc = self.tape.loc;
+        match self.region() {
+            Some((&mut Host::ReadWrite(ref mut bytes), idx)) => bytes[idx] = x as u8,
+            Some((&mut Host::ReadOnly(_), _)) => self.fault = Some(format!("cell {} is mapped read-only", loc)),
+            None => self.tape.put(x),
+        }
+    }
+
+    fn move_by(&mut self, by: isize) -> bool {
+        self.tape.move_(by)
+    }
+
+    fn take_fault(&mut self) -> Option<String> {
+        self.fault.take()
+    }
+}
+
+/// Why a run stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HaltReason {
+    Completed,
+    StepLimit,
+    Timeout,
+    /// The debugger was told to quit.
+    Cancelled,
+    /// An `--on-cell ...=abort` trigger fired.
+    Aborted(String),
+    /// The `,` at this op ran out of input under `--eof strict`.
+    InputExhaustedStrict(usize),
+    /// The move at this op took the pointer left of the first cell.
+    OffTape(usize),
+    RuntimeError(String),
+    /// The output sink refused a write, e.g. a full pipe under
+    /// `Backpressure::Error`.
+    OutputError(String),
+}
+
+/// Process exit codes, so scripts can tell failures apart without reading
+/// the messages. Listed in the usage text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exit {
+    /// A bad command line, or a bad manifest for `run` and `test`.
+    Usage = 1,
+    /// The program was refused before running, with an error diagnostic.
+    Parse = 2,
+    Runtime = 3,
+    /// The program hit its step limit.
+    Limit = 4,
+    Timeout = 5,
+    /// A file couldn't be read or written.
+    Io = 6,
+    /// A check failed: `test`, --check-quine, --difftest-external,
+    /// --bisect-fuel or an `--on-cell ...=abort` trigger.
+    Assertion = 7,
+}
+
+impl Exit {
+    #[cfg(feature = "std")]
+    pub fn exit(self) -> ! {
+        std::process::exit(self as i32)
+    }
+}
+
+impl HaltReason {
+    /// How the process should exit after a run that stopped this way, or
+    /// `None` to exit normally.
+    pub fn exit(&self) -> Option<Exit> {
+        match *self {
+            HaltReason::Completed | HaltReason::Cancelled => None,
+            HaltReason::StepLimit => Some(Exit::Limit),
+            HaltReason::Timeout => Some(Exit::Timeout),
+            HaltReason::InputExhaustedStrict(_) | HaltReason::OffTape(_) | HaltReason::RuntimeError(_) => Some(Exit::Runtime),
+            HaltReason::Aborted(_) => Some(Exit::Assertion),
+            HaltReason::OutputError(_) => Some(Exit::Io),
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match *self {
+            HaltReason::Completed => "completed",
+            HaltReason::StepLimit => "step-limit",
+            HaltReason::Timeout => "timeout",
+            HaltReason::Cancelled => "cancelled",
+            HaltReason::Aborted(_) => "aborted",
+            HaltReason::InputExhaustedStrict(_) => "input-exhausted",
+            HaltReason::OffTape(_) | HaltReason::RuntimeError(_) => "runtime-error",
+            HaltReason::OutputError(_) => "output-error",
+        }
+    }
+}
+
+impl Display for HaltReason {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match *self {
+            HaltReason::Completed => write!(f, "completed"),
+            HaltReason::StepLimit => write!(f, "reached the step limit"),
+            HaltReason::Timeout => write!(f, "ran out of time"),
+            HaltReason::Cancelled => write!(f, "cancelled"),
+            HaltReason::Aborted(ref why) => write!(f, "aborted: {}", why),
+            HaltReason::InputExhaustedStrict(_) => write!(f, "read past the end of the input"),
+            HaltReason::OffTape(loc) => write!(f, "runtime error: {}", off_tape(loc)),
+            HaltReason::RuntimeError(ref e) => write!(f, "runtime error: {}", e),
+            HaltReason::OutputError(ref e) => write!(f, "couldn't write output: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunResult {
+    pub halt: HaltReason,
+    pub steps: u64,
+    /// Bytes written to the output during this run.
+    pub output_bytes: u64,
+}
+
+/// What one op did, as yielded by `Program::steps`. The pointer and cell
+/// are as the op left them.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct StepEvent {
+    /// Steps taken so far, this one included.
+    pub step: u64,
+    /// Where the op is in the program.
+    pub op: usize,
+    pub token: BrainFuckToken,
+    pub ptr: usize,
+    pub cell: i32,
+    /// Bytes the op wrote: none, or one for a plain `.`, but folded output
+    /// ops write several.
+    pub output: Vec<u8>,
+}
+
+/// Iterator over the ops a program runs, from `Program::steps`.
+#[cfg(feature = "std")]
+pub struct Steps<'p, C: Cell = i32> {
+    prog: &'p mut Program<C>,
+    input: Vec<i32>,
+    output: Vec<u8>,
+    halt: Option<HaltReason>,
+}
+
+#[cfg(feature = "std")]
+impl<'p, C: Cell> Steps<'p, C> {
+    /// Why the run stopped, once the iterator is done.
+    pub fn halt(&self) -> Option<&HaltReason> {
+        self.halt.as_ref()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'p, C: Cell> Iterator for Steps<'p, C> {
+    type Item = StepEvent;
+
+    /// Runs the instrumented loop with the step limit just past the next
+    /// op, so each step is exactly what `run` would have done.
+    fn next(&mut self) -> Option<StepEvent> {
+        if self.halt.is_some() {
+            return None;
+        }
+        let prog = &mut *self.prog;
+        if let Some(reason) = prog.limit_reached(prog.steps) {
+            self.halt = Some(reason);
+            return None;
+        }
+        let (op, step_limit) = (prog.loc, prog.step_limit);
+        prog.step_limit = prog.steps + 1;
+        self.output.clear();
+
+        let mut pending = self.input[prog.input_read.min(self.input.len())..].iter();
+        let halt = if prog.char_io {
+            prog.run_instrumented(&mut pending, &mut Latin1Sink { inner: &mut self.output })
+        } else {
+            prog.run_instrumented(&mut pending, &mut self.output)
+        };
+        prog.input_read = self.input.len() - pending.len();
+        prog.step_limit = step_limit;
+
+        match halt {
+            HaltReason::StepLimit => {}
+            HaltReason::Completed if prog.loc > prog.ops.len() && prog.ip_end == IpEnd::Error => {
+                self.halt = Some(HaltReason::RuntimeError("a `]` has no matching `[`".to_string()));
+                return None;
+            }
+            HaltReason::Completed => self.halt = Some(HaltReason::Completed),
+            reason => {
+                self.halt = Some(reason);
+                return None;
+            }
+        }
+        // Nothing was left to run.
+        if op >= prog.ops.len() {
+            return None;
+        }
+
+        Some(StepEvent {
+            step: prog.steps,
+            op: op,
+            token: prog.ops[op],
+            ptr: prog.tape.loc,
+            cell: prog.tape.get(),
+            output: self.output.clone(),
+        })
+    }
+}
+
+/// Where a running program's input comes from, one value per `,`.
+pub trait InputSource {
+    /// The next value, or `None` once the input has run out.
+    fn next_value(&mut self) -> Option<i32>;
+
+    /// Whether another value is waiting, without taking it.
+    fn has_more(&mut self) -> bool;
+
+    /// Why the source stopped giving input early, if it has.
+    #[cfg(feature = "std")]
+    fn take_error(&mut self) -> Option<io::Error> {
+        None
+    }
+}
+
+impl<'a> InputSource for std::slice::Iter<'a, i32> {
+    #[inline(always)]
+    fn next_value(&mut self) -> Option<i32> {
+        self.next().cloned()
+    }
+
+    fn has_more(&mut self) -> bool {
+        self.len() > 0
+    }
+}
+
+/// Reads a program's input from `reader` as it asks for it, so it can
+/// answer a prompt on stdin or a socket. A read error ends the input and
+/// is kept for `take_error`.
+#[cfg(feature = "std")]
+pub struct ReadSource<R: io::Read> {
+    reader: R,
+    chars: bool,
+    peeked: Option<Option<i32>>,
+    error: Option<io::Error>,
+}
+
+#[cfg(feature = "std")]
+impl<R: io::Read> ReadSource<R> {
+    /// One value per byte.
+    pub fn new(reader: R) -> ReadSource<R> {
+        ReadSource {
+            reader: reader,
+            chars: false,
+            peeked: None,
+            error: None,
+        }
+    }
+
+    /// One value per UTF-8 character, with invalid bytes read as U+FFFD,
+    /// as under the char I/O profile.
+    pub fn chars(reader: R) -> ReadSource<R> {
+        ReadSource {
+            chars: true,
+            ..ReadSource::new(reader)
+        }
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0];
+        while self.error.is_none() {
+            match self.reader.read(&mut byte) {
+                Ok(0) => return None,
+                Ok(_) => return Some(byte[0]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => self.error = Some(e),
+            }
+        }
+        None
+    }
+
+    fn read_char(&mut self) -> Option<i32> {
+        let first = self.read_byte()?;
+        let width = match first {
+            0x00..=0x7f => return Some(first as i32),
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => return Some(0xfffd),
+        };
+        let mut bytes = vec![first];
+        while bytes.len() < width {
+            match self.read_byte() {
+                Some(b) => bytes.push(b),
+                None => break,
+            }
+        }
+        let c = std::str::from_utf8(&bytes).ok().and_then(|s| s.chars().next());
+        Some(c.map_or(0xfffd, |c| c as i32))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: io::Read> InputSource for ReadSource<R> {
+    fn next_value(&mut self) -> Option<i32> {
+        if let Some(value) = self.peeked.take() {
+            return value;
+        }
+        if self.chars {
+            self.read_char()
+        } else {
+            self.read_byte().map(|b| b as i32)
+        }
+    }
+
+    fn has_more(&mut self) -> bool {
+        let value = self.next_value();
+        self.peeked = Some(value);
+        value.is_some()
+    }
+
+    #[cfg(feature = "std")]
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+}
+
+/// Where a running program's output goes.
+pub trait OutputSink {
+    fn push(&mut self, byte: u8);
+
+    fn push_repeated(&mut self, byte: u8, times: usize) {
+        for _ in 0..times {
+            self.push(byte);
+        }
+    }
+
+    /// How many bytes have been written so far.
+    fn written(&self) -> u64;
+
+    /// Why the sink stopped accepting output, if it has. The run halts with
+    /// `HaltReason::OutputError` after the op that caused it.
+    #[cfg(feature = "std")]
+    fn take_error(&mut self) -> Option<io::Error> {
+        None
+    }
+}
+
+impl OutputSink for Vec<u8> {
+    fn push(&mut self, byte: u8) {
+        Vec::push(self, byte);
+    }
+
+    fn push_repeated(&mut self, byte: u8, times: usize) {
+        self.extend(std::iter::repeat_n(byte, times));
+    }
+
+    fn written(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+/// Counts output without keeping it, for `--discard-output`.
+#[derive(Debug, Default)]
+pub struct CountingSink {
+    count: u64,
+}
+
+impl OutputSink for CountingSink {
+    fn push(&mut self, _: u8) {
+        self.count += 1;
+    }
+
+    fn push_repeated(&mut self, _: u8, times: usize) {
+        self.count += times as u64;
+    }
+
+    fn written(&self) -> u64 {
+        self.count
+    }
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256, for hashing a run's output as it's written so long runs can be
+/// checked against published hashes without keeping the output.
+///
+/// ```
+/// let mut hash = rustfuck::Sha256::new();
+/// hash.update(b"ab");
+/// hash.update(b"c");
+/// assert_eq!(
+///     rustfuck::hex(&hash.finish()),
+///     "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    filled: usize,
+    len: u64,
+}
+
+impl Sha256 {
+    pub fn new() -> Sha256 {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+            ],
+            block: [0; 64],
+            filled: 0,
+            len: 0,
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.len += bytes.len() as u64;
+        for &byte in bytes {
+            self.block[self.filled] = byte;
+            self.filled += 1;
+            if self.filled == 64 {
+                self.compress();
+                self.filled = 0;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> [u8; 32] {
+        let bits = self.len * 8;
+        self.update(&[0x80]);
+        while self.filled != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+
+        let mut digest = [0; 32];
+        for (chunk, word) in digest.chunks_mut(4).zip(self.state.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (i, chunk) in self.block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let mut v = self.state;
+        for i in 0..64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(SHA256_K[i]).wrapping_add(w[i]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v = [t1.wrapping_add(t2), v[0], v[1], v[2], v[3].wrapping_add(t1), v[4], v[5], v[6]];
+        }
+        for (state, x) in self.state.iter_mut().zip(v.iter()) {
+            *state = state.wrapping_add(*x);
+        }
+    }
+}
+
+pub fn sha256(bytes: &[u8]) -> [u8; 32] {
+    let mut hash = Sha256::new();
+    hash.update(bytes);
+    hash.finish()
+}
+
+/// Lowercase hex, two digits a byte.
+pub fn hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(s, "{:02x}", byte).ok();
+    }
+    s
+}
+
+/// Passes output on to `inner`, hashing it on the way when given a hash,
+/// so the hash is there even if `inner` throws the output away.
+pub struct HashingSink<'a, O: 'a> {
+    inner: &'a mut O,
+    hash: Option<Sha256>,
+}
+
+impl<'a, O: OutputSink> HashingSink<'a, O> {
+    pub fn new(inner: &'a mut O, hash: Option<Sha256>) -> HashingSink<'a, O> {
+        HashingSink {
+            inner: inner,
+            hash: hash,
+        }
+    }
+
+    /// The hash of everything written, if there is one.
+    pub fn finish(self) -> Option<[u8; 32]> {
+        self.hash.map(Sha256::finish)
+    }
+}
+
+impl<'a, O: OutputSink> OutputSink for HashingSink<'a, O> {
+    fn push(&mut self, byte: u8) {
+        if let Some(ref mut hash) = self.hash {
+            hash.update(&[byte]);
+        }
+        self.inner.push(byte);
+    }
+
+    fn push_repeated(&mut self, byte: u8, times: usize) {
+        if let Some(ref mut hash) = self.hash {
+            for _ in 0..times {
+                hash.update(&[byte]);
+            }
+        }
+        self.inner.push_repeated(byte, times);
+    }
+
+    fn written(&self) -> u64 {
+        self.inner.written()
+    }
+
+    #[cfg(feature = "std")]
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.inner.take_error()
+    }
+}
+
+/// Writes each byte as a Latin-1 character encoded in UTF-8, for
+/// `--char-io`.
+#[cfg(feature = "std")]
+struct Latin1Sink<'a, O: 'a> {
+    inner: &'a mut O,
+}
+
+#[cfg(feature = "std")]
+impl<'a, O: OutputSink> OutputSink for Latin1Sink<'a, O> {
+    fn push(&mut self, byte: u8) {
+        if byte < 0x80 {
+            self.inner.push(byte);
+        } else {
+            self.inner.push(0xc0 | byte >> 6);
+            self.inner.push(0x80 | byte & 0x3f);
+        }
+    }
+
+    fn written(&self) -> u64 {
+        self.inner.written()
+    }
+
+    #[cfg(feature = "std")]
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.inner.take_error()
+    }
+}
+
+/// Which line endings a program's input or output is converted to, for
+/// programs written for one convention but fed or read on another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Newlines {
+    /// Pass bytes through as they are.
+    Keep,
+    /// `\r\n` becomes `\n`.
+    Lf,
+    /// A `\n` without a `\r` before it becomes `\r\n`.
+    Crlf,
+}
+
+impl FromStr for Newlines {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Newlines, String> {
+        match s {
+            "keep" => Ok(Newlines::Keep),
+            "lf" => Ok(Newlines::Lf),
+            "crlf" => Ok(Newlines::Crlf),
+            _ => Err(format!("unknown newline convention `{}`", s)),
+        }
+    }
+}
+
+impl Newlines {
+    /// `bytes` with their line endings converted.
+    ///
+    /// ```
+    /// use rustfuck::Newlines;
+    ///
+    /// assert_eq!(Newlines::Lf.translate(b"a\r\nb\r"), b"a\nb\r");
+    /// assert_eq!(Newlines::Crlf.translate(b"a\nb\r\n"), b"a\r\nb\r\n");
+    /// ```
+    pub fn translate(self, bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(bytes.len());
+        {
+            let mut sink = NewlineSink::new(&mut out, self);
+            for &b in bytes {
+                sink.push(b);
+            }
+            sink.finish();
+        }
+        out
+    }
+}
+
+/// Converts line endings on their way into another sink. Under
+/// `Newlines::Lf` a `\r` is held back until the next byte shows whether it
+/// starts a `\r\n`, so call `finish` once the run is over.
+pub struct NewlineSink<'a, O: 'a> {
+    inner: &'a mut O,
+    newlines: Newlines,
+    /// The last byte was a `\r`. Under `Lf` it hasn't been passed on yet.
+    cr: bool,
+}
+
+impl<'a, O: OutputSink> NewlineSink<'a, O> {
+    pub fn new(inner: &'a mut O, newlines: Newlines) -> NewlineSink<'a, O> {
+        NewlineSink {
+            inner: inner,
+            newlines: newlines,
+            cr: false,
+        }
+    }
+
+    /// Passes on a `\r` still held back.
+    pub fn finish(&mut self) {
+        if self.newlines == Newlines::Lf && self.cr {
+            self.inner.push(b'\r');
+        }
+        self.cr = false;
+    }
+}
+
+impl<'a, O: OutputSink> OutputSink for NewlineSink<'a, O> {
+    fn push(&mut self, byte: u8) {
+        match self.newlines {
+            Newlines::Keep => self.inner.push(byte),
+            Newlines::Lf => {
+                if self.cr && byte != b'\n' {
+                    self.inner.push(b'\r');
+                }
+                self.cr = byte == b'\r';
+                if !self.cr {
+                    self.inner.push(byte);
+                }
+            }
+            Newlines::Crlf => {
+                if byte == b'\n' && !self.cr {
+                    self.inner.push(b'\r');
+                }
+                self.cr = byte == b'\r';
+                self.inner.push(byte);
+            }
+        }
+    }
+
+    fn written(&self) -> u64 {
+        self.inner.written()
+    }
+
+    #[cfg(feature = "std")]
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.inner.take_error()
+    }
+}
+
+/// What `WriteSink` does when its writer can't take more output right now,
+/// e.g. a non-blocking pipe or socket that returns `WouldBlock`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backpressure {
+    /// Wait for the writer and try again.
+    Block,
+    /// Hold on to up to this many unwritten bytes, and fail past that.
+    Buffer(usize),
+    /// Fail straight away.
+    Error,
+}
+
+#[cfg(feature = "std")]
+impl FromStr for Backpressure {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Backpressure, String> {
+        match s {
+            "block" => Ok(Backpressure::Block),
+            "error" => Ok(Backpressure::Error),
+            _ if s.starts_with("buffer:") => s["buffer:".len()..]
+                .parse()
+                .map(Backpressure::Buffer)
+                .map_err(|_| format!("bad buffer size in `{}`", s)),
+            _ => Err(format!("unknown backpressure policy `{}`", s)),
+        }
+    }
+}
+
+/// How many bytes `WriteSink` collects before writing, unless a newline
+/// comes first.
+#[cfg(feature = "std")]
+const WRITE_SINK_CHUNK: usize = 8192;
+
+/// How long `Backpressure::Block` waits before retrying a write.
+#[cfg(feature = "std")]
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(1);
+
+/// Streams output to `writer` as the program produces it, a line (or
+/// chunk) at a time, coping with partial writes and `WouldBlock` as the
+/// `Backpressure` policy says.
+#[cfg(feature = "std")]
+pub struct WriteSink<W: io::Write> {
+    writer: W,
+    policy: Backpressure,
+    /// Output the writer hasn't accepted yet.
+    pending: Vec<u8>,
+    written: u64,
+    error: Option<io::Error>,
+}
+
+#[cfg(feature = "std")]
+impl<W: io::Write> WriteSink<W> {
+    pub fn new(writer: W, policy: Backpressure) -> WriteSink<W> {
+        WriteSink {
+            writer: writer,
+            policy: policy,
+            pending: Vec::new(),
+            written: 0,
+            error: None,
+        }
+    }
+
+    /// Writes out whatever is still pending, waiting for the writer if it
+    /// has to whatever the policy, since nothing else will come to flush it.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.policy = Backpressure::Block;
+        self.drain();
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn drain(&mut self) {
+        while !self.pending.is_empty() && self.error.is_none() {
+            match self.writer.write(&self.pending) {
+                Ok(0) => self.error = Some(io::Error::new(io::ErrorKind::WriteZero, "the output was closed")),
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => match self.policy {
+                    Backpressure::Block => std::thread::sleep(WRITE_RETRY_DELAY),
+                    Backpressure::Buffer(cap) if self.pending.len() <= cap => return,
+                    Backpressure::Buffer(cap) => {
+                        let why = format!("more than {} bytes are waiting to be written", cap);
+                        self.error = Some(io::Error::new(io::ErrorKind::WouldBlock, why));
+                    }
+                    Backpressure::Error => {
+                        self.error = Some(io::Error::new(io::ErrorKind::WouldBlock, "the output is full"));
+                    }
+                },
+                Err(e) => self.error = Some(e),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: io::Write> OutputSink for WriteSink<W> {
+    fn push(&mut self, byte: u8) {
+        self.pending.push(byte);
+        self.written += 1;
+        if byte == b'\n' || self.pending.len() >= WRITE_SINK_CHUNK {
+            self.drain();
+        }
+    }
+
+    fn push_repeated(&mut self, byte: u8, times: usize) {
+        self.pending.extend(std::iter::repeat_n(byte, times));
+        self.written += times as u64;
+        if byte == b'\n' || self.pending.len() >= WRITE_SINK_CHUNK {
+            self.drain();
+        }
+    }
+
+    fn written(&self) -> u64 {
+        self.written
+    }
+
+    #[cfg(feature = "std")]
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+}
+
+/// How the uninstrumented engine picks the code for each op.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dispatch {
+    /// A `match` on the token, in `run_fast`.
+    Match,
+    /// A table of handler functions indexed by opcode, in `run_table`.
+    Table,
+    /// The `match` without bounds checks on the ops or the tape, in
+    /// `run_unchecked`, for programs `bounds_proven` accepts. Others run
+    /// through `Match`.
+    #[cfg(feature = "unchecked")]
+    Unchecked,
+}
+
+impl FromStr for Dispatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Dispatch, String> {
+        match s {
+            "match" => Ok(Dispatch::Match),
+            "table" => Ok(Dispatch::Table),
+            #[cfg(feature = "unchecked")]
+            "unchecked" => Ok(Dispatch::Unchecked),
+            _ => Err(format!("unknown dispatch `{}`", s)),
+        }
+    }
+}
+
+/// An op encoded for the handler-table engine: an index into `Handlers`
+/// and up to two operands.
+#[derive(Debug, Clone, Copy)]
+struct Instr {
+    op: u8,
+    a: i32,
+    b: i32,
+}
+
+impl Instr {
+    fn encode(token: &BrainFuckToken) -> Instr {
+        let (a, b) = match *token {
+            BrainFuckToken::JumpF(x) | BrainFuckToken::JumpB(x) => (x as i32, 0),
+            BrainFuckToken::Move(x) => (x as i32, 0),
+            BrainFuckToken::Incr(x) | BrainFuckToken::IncrOut(x) => (x, 0),
+            BrainFuckToken::StdIn | BrainFuckToken::StdOut | BrainFuckToken::ZeroOut => (0, 0),
+            BrainFuckToken::MoveIncr(x, y) => (x as i32, y),
+            BrainFuckToken::Out(n) => (n as i32, 0),
+            BrainFuckToken::OutConst(b, n) => (b as i32, n as i32),
+        };
+        Instr {
+            op: token.ir_op().opcode,
+            a: a,
+            b: b,
+        }
+    }
+
+    /// The token `encode` made this from. The opcode must be a known one.
+    #[cfg(feature = "std")]
+    fn decode(self) -> BrainFuckToken {
+        match self.op {
+            0 => BrainFuckToken::JumpF(self.a as usize),
+            1 => BrainFuckToken::JumpB(self.a as usize),
+            2 => BrainFuckToken::Move(self.a as isize),
+            3 => BrainFuckToken::Incr(self.a),
+            4 => BrainFuckToken::StdIn,
+            5 => BrainFuckToken::StdOut,
+            6 => BrainFuckToken::ZeroOut,
+            7 => BrainFuckToken::MoveIncr(self.a as isize, self.b),
+            8 => BrainFuckToken::IncrOut(self.a),
+            9 => BrainFuckToken::Out(self.a as usize),
+            10 => BrainFuckToken::OutConst(self.a as u8, self.b as usize),
+            op => panic!("unknown opcode {}", op),
+        }
+    }
+}
+
+/// Starts every bytecode blob, followed by `BYTECODE_VERSION`.
+pub const BYTECODE_MAGIC: &'static [u8; 4] = b"BFBC";
+pub const BYTECODE_VERSION: u8 = 1;
+const BYTECODE_HEADER: usize = 5;
+/// Bytes per op: the opcode, then both operands as little-endian `i32`s.
+const BYTECODE_OP: usize = 9;
+
+/// Encodes resolved ops as bytecode for `CompiledProgram`: a header, then
+/// one fixed-size record per op, each an `Instr` as the table engine sees
+/// it. Fixed records are what let a blob run in place.
+pub fn to_bytecode(ops: &Ir) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(BYTECODE_HEADER + ops.len() * BYTECODE_OP);
+    bytes.extend_from_slice(BYTECODE_MAGIC);
+    bytes.push(BYTECODE_VERSION);
+    for op in ops {
+        let instr = Instr::encode(op);
+        bytes.push(instr.op);
+        bytes.extend_from_slice(&instr.a.to_le_bytes());
+        bytes.extend_from_slice(&instr.b.to_le_bytes());
+    }
+    bytes
+}
+
+/// Why `CompiledProgram::from_bytes` refused a blob.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BytecodeError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// The ops don't fill a whole number of records.
+    Truncated,
+    BadOpcode { op: usize, opcode: u8 },
+    /// The jump at this op doesn't land on a jump back to it.
+    BadJump(usize),
+    /// The output op at this op repeats a negative number of times.
+    BadCount(usize),
+}
+
+impl Display for BytecodeError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match *self {
+            BytecodeError::BadMagic => write!(f, "not rustfuck bytecode"),
+            BytecodeError::UnsupportedVersion(v) => write!(f, "bytecode version {} isn't supported", v),
+            BytecodeError::Truncated => write!(f, "bytecode ends partway through an op"),
+            BytecodeError::BadOpcode { op, opcode } => write!(f, "op {} has unknown opcode {}", op, opcode),
+            BytecodeError::BadJump(op) => write!(f, "op {} jumps to an op that doesn't jump back", op),
+            BytecodeError::BadCount(op) => write!(f, "op {} outputs a negative number of times", op),
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError {}
+
+/// Where the table engine reads its ops from.
+#[cfg(feature = "std")]
+trait Code {
+    fn len(&self) -> usize;
+    fn get(&self, idx: usize) -> Option<Instr>;
+}
+
+#[cfg(feature = "std")]
+impl Code for [Instr] {
+    fn len(&self) -> usize {
+        <[Instr]>::len(self)
+    }
+
+    #[inline(always)]
+    fn get(&self, idx: usize) -> Option<Instr> {
+        <[Instr]>::get(self, idx).cloned()
+    }
+}
+
+/// A program run straight from bytecode made by `to_bytecode`, borrowed
+/// rather than decoded, so one embedded with `include_bytes!` starts
+/// without copying or allocating anything for its ops. The blob is checked
+/// once up front; each op is decoded as it runs, which makes a long run a
+/// little slower than `Dispatch::Table` on a `Program`.
+///
+/// ```
+/// use rustfuck::{to_bytecode, CompiledProgram, Profile};
+///
+/// let ir: rustfuck::Program = ",[.,]".parse().unwrap();
+/// let bytes = to_bytecode(&ir.ops);
+///
+/// let compiled = CompiledProgram::from_bytes(&bytes).unwrap();
+/// let mut out = Vec::new();
+/// compiled.run(Profile::default(), u64::max_value(), b"hi", &mut out);
+/// assert_eq!(out, b"hi");
+///
+/// // An `Out` that repeats -1 times is refused rather than run.
+/// let bad = b"BFBC\x01\x09\xff\xff\xff\xff\x00\x00\x00\x00";
+/// assert!(CompiledProgram::from_bytes(bad).is_err());
+/// ```
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy)]
+pub struct CompiledProgram<'a> {
+    code: &'a [u8],
+}
+
+#[cfg(feature = "std")]
+impl<'a> CompiledProgram<'a> {
+    pub fn from_bytes(bytes: &'a [u8]) -> std::result::Result<CompiledProgram<'a>, BytecodeError> {
+        if bytes.len() < BYTECODE_HEADER || &bytes[..4] != BYTECODE_MAGIC {
+            return Err(BytecodeError::BadMagic);
+        }
+        if bytes[4] != BYTECODE_VERSION {
+            return Err(BytecodeError::UnsupportedVersion(bytes[4]));
+        }
+        let code = &bytes[BYTECODE_HEADER..];
+        if code.len() % BYTECODE_OP != 0 {
+            return Err(BytecodeError::Truncated);
+        }
+
+        let compiled = CompiledProgram { code: code };
+        for idx in 0..compiled.len() {
+            let instr = compiled.instr(idx);
+            if instr.op as usize >= Handlers::<i32>::TABLE.len() {
+                return Err(BytecodeError::BadOpcode {
+                    op: idx,
+                    opcode: instr.op,
+                });
+            }
+            // The handlers trust jump targets, so they have to pair up.
+            let partner = match instr.op {
+                0 => Some(1),
+                1 => Some(0),
+                _ => None,
+            };
+            if let Some(partner) = partner {
+                let target = instr.a as usize;
+                let back = target < compiled.len() && {
+                    let other = compiled.instr(target);
+                    other.op == partner && other.a as usize == idx
+                };
+                if instr.a < 0 || !back {
+                    return Err(BytecodeError::BadJump(idx));
+                }
+            }
+            // Likewise the counts of `Out` and `OutConst`.
+            let count = match instr.op {
+                9 => instr.a,
+                10 => instr.b,
+                _ => 0,
+            };
+            if count < 0 {
+                return Err(BytecodeError::BadCount(idx));
+            }
+        }
+        Ok(compiled)
+    }
+
+    /// How many ops the program has.
+    pub fn len(&self) -> usize {
+        self.code.len() / BYTECODE_OP
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    fn instr(&self, idx: usize) -> Instr {
+        self.record(idx).expect("op out of range")
+    }
+
+    #[inline(always)]
+    fn record(&self, idx: usize) -> Option<Instr> {
+        // A stopped run leaves `idx` far past the end, where it would overflow.
+        let start = idx.checked_mul(BYTECODE_OP)?;
+        let record: &[u8; BYTECODE_OP] = self.code.get(start..)?.get(..BYTECODE_OP)?.try_into().unwrap();
+        Some(Instr {
+            op: record[0],
+            a: i32::from_le_bytes([record[1], record[2], record[3], record[4]]),
+            b: i32::from_le_bytes([record[5], record[6], record[7], record[8]]),
+        })
+    }
+
+    /// The ops, decoded one at a time, for tools that want to look at them.
+    pub fn ops(&self) -> impl Iterator<Item = BrainFuckToken> + 'a {
+        let compiled = *self;
+        (0..compiled.len()).map(move |idx| compiled.instr(idx).decode())
+    }
+
+    /// Runs the program on `input` with the table engine, appending its
+    /// output to `out`.
+    pub fn run<O: OutputSink>(&self, profile: Profile, step_limit: u64, input: &[u8], out: &mut O) -> RunResult {
+        let mut prog = Program::with_profile(Vec::new(), profile);
+        prog.step_limit = step_limit;
+        let input = prog.decode_input(input);
+        let mut pending = input.iter();
+        let start = out.written();
+
+        let halt = if prog.char_io {
+            prog.run_code(self, &mut pending, &mut Latin1Sink { inner: out })
+        } else {
+            prog.run_code(self, &mut pending, out)
+        };
+        RunResult {
+            halt: halt,
+            steps: prog.steps,
+            output_bytes: out.written() - start,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> Code for CompiledProgram<'a> {
+    fn len(&self) -> usize {
+        CompiledProgram::len(self)
+    }
+
+    #[inline(always)]
+    fn get(&self, idx: usize) -> Option<Instr> {
+        self.record(idx)
+    }
+}
+
+/// What the handlers of the table engine work on.
+#[cfg(feature = "std")]
+struct Machine<'a, C: Cell> {
+    prog: &'a mut Program<C>,
+    input: &'a mut dyn InputSource,
+    out: &'a mut dyn OutputSink,
+    halt: HaltReason,
+}
+
+#[cfg(feature = "std")]
+impl<'a, C: Cell> Machine<'a, C> {
+    /// Stops the run after the current op by moving far past the last one,
+    /// at an offset that still tells which op it was.
+    fn stop(&mut self, reason: HaltReason) {
+        self.halt = reason;
+        self.prog.loc += UNRESOLVED;
+    }
+    fn check_output(&mut self) {
+        if let Some(e) = self.out.take_error() {
+            self.stop(HaltReason::OutputError(e.to_string()));
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+type Handler<C> = fn(&mut Machine<C>, Instr);
+
+/// The handler for each opcode, for a program on cells of type `C`.
+#[cfg(feature = "std")]
+struct Handlers<C>(std::marker::PhantomData<C>);
+
+#[cfg(feature = "std")]
+impl<C: Cell> Handlers<C> {
+    const TABLE: [Handler<C>; 11] = [
+        op_jump_f,
+        op_jump_b,
+        op_move,
+        op_incr,
+        op_std_in,
+        op_std_out,
+        op_zero_out,
+        op_move_incr,
+        op_incr_out,
+        op_out,
+        op_out_const,
+    ];
+}
+
+#[cfg(feature = "std")]
+fn op_jump_f<C: Cell>(m: &mut Machine<C>, i: Instr) {
+    if m.prog.tape.get() == 0 {
+        m.prog.loc = i.a as usize;
+    } else {
+        m.prog.tracer.trace((m.prog.loc, i.a as usize), m.prog.steps);
+    }
+}
+
+#[cfg(feature = "std")]
+fn op_jump_b<C: Cell>(m: &mut Machine<C>, i: Instr) {
+    if m.prog.tape.get() != 0 {
+        m.prog.loc = i.a as usize;
+    } else {
+        m.prog.tracer.leave((i.a as usize, m.prog.loc), m.prog.steps);
+    }
+}
+
+#[cfg(feature = "std")]
+fn op_move<C: Cell>(m: &mut Machine<C>, i: Instr) {
+    if !m.prog.tape.move_(i.a as isize) {
+        let loc = m.prog.loc;
+        m.stop(HaltReason::OffTape(loc));
+    }
+}
+
+#[cfg(feature = "std")]
+fn op_incr<C: Cell>(m: &mut Machine<C>, i: Instr) {
+    m.prog.tape.incr(i.a);
+}
+
+#[cfg(feature = "std")]
+fn op_std_in<C: Cell>(m: &mut Machine<C>, _: Instr) {
+    match (m.input.next_value(), m.prog.eof) {
+        (Some(c), _) => m.prog.tape.put(c),
+        (None, Eof::Zero) => m.prog.tape.put(0),
+        (None, Eof::MinusOne) => m.prog.tape.put(-1),
+        (None, Eof::Unchanged) => {}
+        (None, Eof::Strict) => {
+            let loc = m.prog.loc;
+            m.stop(HaltReason::InputExhaustedStrict(loc));
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn op_std_out<C: Cell>(m: &mut Machine<C>, _: Instr) {
+    m.out.push(m.prog.tape.getc());
+    m.check_output();
+}
+
+#[cfg(feature = "std")]
+fn op_zero_out<C: Cell>(m: &mut Machine<C>, _: Instr) {
+    m.prog.tape.put(0);
+}
+
+#[cfg(feature = "std")]
+fn op_move_incr<C: Cell>(m: &mut Machine<C>, i: Instr) {
+    op_move(m, i);
+    m.prog.tape.incr(i.b);
+}
+
+#[cfg(feature = "std")]
+fn op_incr_out<C: Cell>(m: &mut Machine<C>, i: Instr) {
+    m.prog.tape.incr(i.a);
+    m.out.push(m.prog.tape.getc());
+    m.check_output();
+}
+
+#[cfg(feature = "std")]
+fn op_out<C: Cell>(m: &mut Machine<C>, i: Instr) {
+    m.out.push_repeated(m.prog.tape.getc(), i.a as usize);
+    m.check_output();
+}
+
+#[cfg(feature = "std")]
+fn op_out_const<C: Cell>(m: &mut Machine<C>, i: Instr) {
+    m.out.push_repeated(i.a as u8, i.b as usize);
+    m.check_output();
+}
+
+/// How much work `Program::run_for` may do before returning.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Budget {
+    Steps(u64),
+    /// Checked every few tens of thousands of steps, so a slice can run
+    /// slightly over.
+    Time(Duration),
+}
+
+impl From<u64> for Budget {
+    fn from(steps: u64) -> Budget {
+        Budget::Steps(steps)
+    }
+}
+
+impl From<Duration> for Budget {
+    fn from(time: Duration) -> Budget {
+        Budget::Time(time)
+    }
+}
+
+/// Where a program stands after `Program::run_for`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunState {
+    /// The budget ran out first. Call again to carry on.
+    Running,
+    /// The program is at a `,` past the end of the input given so far.
+    /// Call again with more, or after `Program::end_input`.
+    NeedsInput,
+    Halted(RunResult),
+}
+
+/// How many steps the fast path runs between looking at the clock.
+#[cfg(feature = "std")]
+const DEADLINE_CHECK_INTERVAL: u64 = 1 << 16;
+
+#[cfg(feature = "std")]
+pub struct Program<C: Cell = i32> {
+    loc: usize,
+    pub ops: Vec<BrainFuckToken>,
+    pub tape: Tape<C>,
+    pub tracer: Trace,
+    pub transcript: Option<Transcript>,
+    pub recording: Option<Recording>,
+    pub replay: Option<Replay>,
+    pub throttle: Option<Duration>,
+    #[cfg(feature = "cli")]
+    pub debugger: Option<Debugger>,
+    #[cfg(feature = "cli")]
+    pub trajectory: Option<Trajectory>,
+    #[cfg(feature = "cli")]
+    pub animation: Option<Animation>,
+    #[cfg(feature = "cli")]
+    pub checkpoints: Option<Checkpoints>,
+    #[cfg(feature = "cli")]
+    pub explainer: Option<Explainer>,
+    #[cfg(feature = "cli")]
+    pub triggers: Option<Triggers>,
+    #[cfg(feature = "cli")]
+    pub audit: Option<Audit>,
+    pub loop_capture: Option<LoopCapture>,
+    pub memo: Option<Memo>,
+    pub trail: Option<PointerTrail>,
+    /// How many times each op has run, one entry per op.
+    pub op_counts: Option<Vec<u64>>,
+    pub live_output: bool,
+    pub dispatch: Dispatch,
+    eof: Eof,
+    ip_end: IpEnd,
+    char_io: bool,
+    steps: u64,
+    /// How much of the input the run has read so far.
+    input_read: usize,
+    /// Whether `run_for` has been told the input is complete.
+    input_ended: bool,
+    pub step_limit: u64,
+    pub deadline: Option<Instant>,
+    /// Why the optimizer was given up on, if `Interpreter::compile` left
+    /// the program unoptimized.
+    pub unoptimized: Option<BudgetExceeded>,
+}
+
+#[cfg(feature = "std")]
+impl Program {
+    pub fn with_profile(ops: Vec<BrainFuckToken>, profile: Profile) -> Program {
+        Program::with_cells(ops, profile)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<C: Cell> Program<C> {
+    /// `with_profile` on a tape of `C` cells, which wrap at the narrower of
+    /// `C` and the profile's width.
+    ///
+    /// ```
+    /// use rustfuck::{optimize, parse, Fuel, Profile, Program, DEFAULT_PASSES};
+    /// use std::num::Wrapping;
+    ///
+    /// let ops = optimize(parse("-.", 100), DEFAULT_PASSES, &mut Fuel::unlimited());
+    /// let mut prog: Program<Wrapping<u8>> = Program::with_cells(ops, Profile::default());
+    /// let mut out = Vec::new();
+    /// prog.run(b"", &mut out);
+    /// assert_eq!((out, prog.tape.tape.len()), (vec![255], 30000));
+    /// ```
+    pub fn with_cells(ops: Vec<BrainFuckToken>, profile: Profile) -> Program<C> {
+        Program {
+            loc: 0,
+            ops: ops,
+            tape: if profile.grow {
+                Tape::growable(profile.cells, profile.tape)
+            } else {
+                Tape::new(profile.cells, profile.tape)
+            },
+            tracer: Trace::new(),
+            transcript: None,
+            recording: None,
+            replay: None,
+            throttle: None,
+            #[cfg(feature = "cli")]
+            debugger: None,
+            #[cfg(feature = "cli")]
+            trajectory: None,
+            #[cfg(feature = "cli")]
+            animation: None,
+            #[cfg(feature = "cli")]
+            checkpoints: None,
+            #[cfg(feature = "cli")]
+            explainer: None,
+            #[cfg(feature = "cli")]
+            triggers: None,
+            #[cfg(feature = "cli")]
+            audit: None,
+            loop_capture: None,
+            memo: None,
+            trail: None,
+            op_counts: None,
+            live_output: false,
+            dispatch: Dispatch::Match,
+            eof: profile.eof,
+            ip_end: profile.ip_end,
+            char_io: profile.char_io,
+            steps: 0,
+            input_read: 0,
+            input_ended: false,
+            step_limit: u64::max_value(),
+            deadline: None,
+            unoptimized: None,
+        }
+    }
+
+    /// How many input values the runs since the last `rewind` have read.
+    pub fn input_read(&self) -> usize {
+        self.input_read
+    }
+
+    /// Puts the program back at its first op with `tape`, for running it
+    /// again. Everything else, memoized loops included, carries over.
+    pub fn rewind(&mut self, tape: Tape<C>) {
+        self.loc = 0;
+        self.steps = 0;
+        self.input_read = 0;
+        self.tape = tape;
+    }
+
+    /// Runs the program, appending what it writes to `out`. Input and
+    /// output are bytes unless the profile asks for the old char I/O.
+    ///
+    /// A program stopped by its step limit or deadline picks up where it
+    /// left off when run again with the same input.
+    pub fn run<O: OutputSink>(&mut self, input: &[u8], out: &mut O) -> RunResult {
+        let input = self.decode_input(input);
+
+        // A run picked up where an earlier one stopped skips the input that
+        // one already read.
+        let mut pending = input[self.input_read.min(input.len())..].iter();
+        let result = self.run_from(&mut pending, out);
+        self.input_read = input.len() - pending.len();
+        result
+    }
+
+    /// Runs the program on a reader and a writer, e.g. stdin and stdout or
+    /// a socket, reading input only as `,` asks for it and writing output as
+    /// it is produced. An `Err` is from reading or writing; how the program
+    /// itself ended is in the result.
+    ///
+    /// ```
+    /// use rustfuck::{HaltReason, Interpreter};
+    ///
+    /// let mut prog = Interpreter::builder().build().unwrap().compile(",[.,]").unwrap();
+    /// let mut out = Vec::new();
+    /// let result = prog.run_io(&b"echo"[..], &mut out).unwrap();
+    /// assert_eq!(result.halt, HaltReason::Completed);
+    /// assert_eq!(out, b"echo");
+    /// ```
+    pub fn run_io<R: io::Read, W: io::Write>(&mut self, input: R, output: W) -> io::Result<RunResult> {
+        let mut source = self.input_from(input);
+        let mut sink = WriteSink::new(output, Backpressure::Block);
+        let result = self.run_from(&mut source, &mut sink);
+        if let Some(e) = source.take_error() {
+            return Err(e);
+        }
+        match result.halt {
+            HaltReason::OutputError(_) => {}
+            _ => {
+                sink.finish()?;
+            }
+        }
+        Ok(result)
+    }
+
+    /// Reads `reader` as input the way this program's profile says, for
+    /// `run_from`. Chaining readers gives a program scripted input for its
+    /// setup and then whatever the user types.
+    ///
+    /// ```
+    /// use rustfuck::Program;
+    /// use std::io::Read;
+    ///
+    /// let mut prog: Program = ",.,.".parse().unwrap();
+    /// let mut input = prog.input_from(b"a".chain(&b"b"[..]));
+    /// let mut out = Vec::new();
+    /// prog.run_from(&mut input, &mut out);
+    /// assert_eq!(out, b"ab");
+    /// ```
+    pub fn input_from<R: io::Read>(&self, reader: R) -> ReadSource<R> {
+        if self.char_io {
+            ReadSource::chars(reader)
+        } else {
+            ReadSource::new(reader)
+        }
+    }
+
+    /// Runs the program on any input source, appending what it writes to
+    /// `out`. Unlike `run`, a resumed run carries on from wherever `input`
+    /// has got to.
+    pub fn run_from<I: InputSource, O: OutputSink>(&mut self, input: &mut I, out: &mut O) -> RunResult {
+        if self.steps == 0 {
+            self.tracer.reset();
+        }
+
+        let start = out.written();
+
+        if self.ip_end == IpEnd::Error {
+            if let Err(e) = check_jumps(&self.ops) {
+                return RunResult {
+                    halt: HaltReason::RuntimeError(e),
+                    steps: self.steps,
+                    output_bytes: 0,
+                };
+            }
+        }
+
+        #[cfg(feature = "cli")]
+        let tools = self.debugger.is_some()
+            || self.trajectory.is_some()
+            || self.animation.is_some()
+            || self.checkpoints.is_some()
+            || self.explainer.is_some()
+            || self.triggers.is_some()
+            || self.audit.is_some();
+        #[cfg(not(feature = "cli"))]
+        let tools = false;
+        let instrumented = tools
+            || self.loop_capture.is_some()
+            || self.throttle.is_some()
+            || self.transcript.is_some()
+            || self.recording.is_some()
+            || self.replay.is_some()
+            || self.trail.is_some()
+            || self.op_counts.is_some()
+            || self.live_output;
+
+        let halt = match (instrumented, self.dispatch, self.char_io) {
+            (true, _, true) => self.run_instrumented(input, &mut Latin1Sink { inner: out }),
+            (true, _, false) => self.run_instrumented(input, out),
+            (false, Dispatch::Match, true) => self.run_fast(input, &mut Latin1Sink { inner: out }),
+            (false, Dispatch::Match, false) => self.run_fast(input, out),
+            (false, Dispatch::Table, true) => self.run_table(input, &mut Latin1Sink { inner: out }),
+            (false, Dispatch::Table, false) => self.run_table(input, out),
+            #[cfg(feature = "unchecked")]
+            (false, Dispatch::Unchecked, true) => self.run_unchecked(input, &mut Latin1Sink { inner: out }),
+            #[cfg(feature = "unchecked")]
+            (false, Dispatch::Unchecked, false) => self.run_unchecked(input, out),
+        };
+
+        // A last checkpoint with whatever changed since the previous one.
+        #[cfg(feature = "cli")]
+        if let Some(ref mut checkpoints) = self.checkpoints {
+            checkpoints.write(self.steps, &self.tape).ok();
+        }
+
+        // Jump targets were checked up front, so only an unresolved `]`
+        // jumps past the end.
+        let halt = if halt == HaltReason::Completed && self.loc > self.ops.len() && self.ip_end == IpEnd::Error {
+            HaltReason::RuntimeError("a `]` has no matching `[`".to_string())
+        } else {
+            halt
+        };
+
+        RunResult {
+            halt: halt,
+            steps: self.steps,
+            output_bytes: out.written() - start,
+        }
+    }
+
+    /// Runs the program for at most `budget` and returns, so it can share a
+    /// thread with other work, e.g. one slice per frame of a game loop.
+    /// Everything needed to carry on is kept in the program. Pass the same
+    /// input every time, with anything that has arrived since added to the
+    /// end.
+    ///
+    /// A `,` past the end of the input gives `NeedsInput` and is run again
+    /// on the next call, until `end_input` says no more is coming; from then
+    /// on it gets the profile's EOF behavior.
+    ///
+    /// ```
+    /// use rustfuck::{HaltReason, Program, RunState};
+    ///
+    /// let mut prog: Program = "+[,.]".parse().unwrap();
+    /// let mut out = Vec::new();
+    /// assert_eq!(prog.run_for(b"", &mut out, 10), RunState::NeedsInput);
+    /// assert_eq!(prog.run_for(b"hi", &mut out, 4), RunState::Running);
+    /// assert_eq!(prog.run_for(b"hi", &mut out, 100), RunState::NeedsInput);
+    /// assert_eq!(out, b"hi");
+    ///
+    /// prog.end_input();
+    /// match prog.run_for(b"hi", &mut out, 100) {
+    ///     RunState::Halted(result) => assert_eq!(result.halt, HaltReason::Completed),
+    ///     state => panic!("still {:?}", state),
+    /// }
+    /// ```
+    pub fn run_for<O: OutputSink, B: Into<Budget>>(&mut self, input: &[u8], out: &mut O, budget: B) -> RunState {
+        let (step_limit, deadline, eof) = (self.step_limit, self.deadline, self.eof);
+        if !self.input_ended {
+            self.eof = Eof::Strict;
+        }
+        match budget.into() {
+            Budget::Steps(steps) => self.step_limit = step_limit.min(self.steps.saturating_add(steps)),
+            Budget::Time(time) => {
+                let end = Instant::now() + time;
+                self.deadline = Some(deadline.map_or(end, |deadline| deadline.min(end)));
+            }
+        }
+
+        let result = self.run(input, out);
+        let budget_spent = match result.halt {
+            HaltReason::StepLimit => self.steps < step_limit,
+            HaltReason::Timeout => deadline.map_or(true, |deadline| Instant::now() < deadline),
+            _ => false,
+        };
+        self.step_limit = step_limit;
+        self.deadline = deadline;
+        self.eof = eof;
+
+        match result.halt {
+            _ if budget_spent => RunState::Running,
+            // Every engine stops on the `,` itself, so it runs again next
+            // time, but has already counted it.
+            HaltReason::InputExhaustedStrict(_) if !self.input_ended => {
+                self.steps -= 1;
+                RunState::NeedsInput
+            }
+            _ => RunState::Halted(result),
+        }
+    }
+
+    /// Tells `run_for` that the input it has been given is all there is.
+    pub fn end_input(&mut self) {
+        self.input_ended = true;
+    }
+
+    /// Runs the program one op at a time, yielding what each did. Built
+    /// for debuggers and visualizers; every hook `run` honours still fires,
+    /// but each step costs far more than in `run`. The iterator ends when
+    /// the run does, and `Steps::halt` then says why.
+    ///
+    /// ```
+    /// use rustfuck::{BrainFuckToken, HaltReason, Interpreter};
+    ///
+    /// let mut prog = Interpreter::builder().opt_level(0).build().unwrap().compile("++.").unwrap();
+    /// let mut steps = prog.steps(b"");
+    /// let events: Vec<_> = steps.by_ref().collect();
+    /// assert_eq!(events.len(), 3);
+    /// assert_eq!(events[1].cell, 2);
+    /// assert_eq!(events[2].output, b"\x02");
+    /// assert_eq!(steps.halt(), Some(&HaltReason::Completed));
+    /// ```
+    pub fn steps(&mut self, input: &[u8]) -> Steps<'_, C> {
+        if self.steps == 0 {
+            self.tracer.reset();
+        }
+        let input = self.decode_input(input);
+        let halt = match check_jumps(&self.ops) {
+            Err(e) if self.ip_end == IpEnd::Error => Some(HaltReason::RuntimeError(e)),
+            _ => None,
+        };
+
+        Steps {
+            prog: self,
+            input: input,
+            output: Vec::new(),
+            halt: halt,
+        }
+    }
+
+    fn decode_input(&self, input: &[u8]) -> Vec<i32> {
+        if self.char_io {
+            String::from_utf8_lossy(input).chars().map(|c| c as i32).collect()
+        } else {
+            input.iter().map(|&b| b as i32).collect()
+        }
+    }
+
+    /// The next step count at which a run has to stop and check its limits.
+    /// Without a deadline that is just the step limit, so the hot path pays
+    /// for a single comparison per step either way.
+    fn next_checkpoint(&self, steps: u64) -> u64 {
+        match self.deadline {
+            Some(_) => self.step_limit.min(steps.saturating_add(DEADLINE_CHECK_INTERVAL)),
+            None => self.step_limit,
+        }
+    }
+
+    fn limit_reached(&self, steps: u64) -> Option<HaltReason> {
+        if steps >= self.step_limit {
+            Some(HaltReason::StepLimit)
+        } else if self.deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+            Some(HaltReason::Timeout)
+        } else {
+            None
+        }
+    }
+
+    /// Char I/O treats each output byte as a Latin-1 character and writes it
+    /// as UTF-8, the way the interpreter used to.
+    fn encode_output(&self, bytes: &[u8]) -> Vec<u8> {
+        if self.char_io {
+            bytes.iter().map(|&b| b as char).collect::<String>().into_bytes()
+        } else {
+            bytes.to_vec()
+        }
+    }
+
+    /// The hot path: no per-step hooks, and the instruction pointer, step
+    /// count and innermost loop kept in locals rather than behind `self`.
+    fn run_fast<I: InputSource, O: OutputSink>(&mut self, input: &mut I, out: &mut O) -> HaltReason {
+        let mut loc = self.loc;
+        let mut steps = self.steps;
+        let mut checkpoint = self.next_checkpoint(steps);
+        let mut halt = HaltReason::Completed;
+
+        // `[` and `]` of the loop most recently entered. Loops spend nearly
+        // all their time jumping back from one to the other, so that jump can
+        // be taken from here without decoding the `]`'s operand.
+        let (mut loop_head, mut loop_tail) = (usize::max_value(), usize::max_value());
+
+        // With lazy jumps, leaves the loop below at each unresolved `[` to
+        // resolve it and carry on.
+        let mut unresolved = true;
+        while unresolved {
+            unresolved = false;
+            if !resolve_jump(&mut self.ops, loc) {
+                halt = HaltReason::RuntimeError(unmatched_jump(loc));
+                break;
+            }
+            let ops = &self.ops[..];
+
+            while let Some(instr) = ops.get(loc) {
+                if steps == checkpoint {
+                    if let Some(reason) = self.limit_reached(steps) {
+                        halt = reason;
+                        break;
+                    }
+                    checkpoint = self.next_checkpoint(steps);
+                }
+                steps += 1;
+
+                match *instr {
+                    BrainFuckToken::JumpF(UNRESOLVED) => {
+                        steps -= 1;
+                        unresolved = true;
+                        break;
+                    }
+                    BrainFuckToken::JumpF(x) => {
+                        if self.tape.get() == 0 {
+                            loc = x;
+                        } else {
+                            loop_head = loc;
+                            loop_tail = x;
+                            self.tracer.trace((loc, x), steps);
+                            if let Some(ref mut memo) = self.memo {
+                                if let Some(cost) = memo.enter(loc, &mut self.tape, steps, checkpoint) {
+                                    steps += cost;
+                                    self.tracer.leave((loc, x), steps);
+                                    loc = x;
+                                }
+                            }
+                        }
+                    }
+                    BrainFuckToken::JumpB(x) => {
+                        if self.tape.get() != 0 {
+                            loc = if loc == loop_tail { loop_head } else { x };
+                        } else {
+                            self.tracer.leave((x, loc), steps);
+                            if let Some(ref mut memo) = self.memo {
+                                memo.leave(x, &self.tape, steps);
+                            }
+                        }
+                    }
+                    BrainFuckToken::Move(x) => if !self.tape.move_(x) {
+                        halt = HaltReason::OffTape(loc);
+                        break;
+                    },
+                    BrainFuckToken::Incr(x) => self.tape.incr(x),
+                    BrainFuckToken::StdIn => match (input.next_value(), self.eof) {
+                        (Some(c), _) => self.tape.put(c),
+                        (None, Eof::Zero) => self.tape.put(0),
+                        (None, Eof::MinusOne) => self.tape.put(-1),
+                        (None, Eof::Unchanged) => {}
+                        (None, Eof::Strict) => {
+                            halt = HaltReason::InputExhaustedStrict(loc);
+                            break;
+                        }
+                    },
+                    BrainFuckToken::StdOut => out.push(self.tape.getc()),
+                    BrainFuckToken::ZeroOut => self.tape.put(0),
+                    BrainFuckToken::MoveIncr(x, y) => {
+                        if !self.tape.move_(x) {
+                            halt = HaltReason::OffTape(loc);
+                            break;
+                        }
+                        self.tape.incr(y);
+                    }
+                    BrainFuckToken::IncrOut(x) => {
+                        self.tape.incr(x);
+                        out.push(self.tape.getc());
+                    }
+                    BrainFuckToken::Out(n) => out.push_repeated(self.tape.getc(), n),
+                    BrainFuckToken::OutConst(b, n) => out.push_repeated(b, n),
+                }
+                // Free for sinks that can't fail, which is all but `WriteSink`.
+                if let Some(e) = out.take_error() {
+                    halt = HaltReason::OutputError(e.to_string());
+                    break;
+                }
+                loc += 1;
+            }
+        }
+
+        self.loc = loc;
+        self.steps = steps;
+        halt
+    }
+
+    /// Whether `run_unchecked` may skip bounds checks: every jump is
+    /// resolved and lands on its partner, so the op index never passes the
+    /// end unnoticed, and the pointer starts on the tape, which `move_`
+    /// keeps it on. Memoized loops write the tape behind the engine's back,
+    /// so a program with a memo is never accepted.
+    #[cfg(feature = "unchecked")]
+    fn bounds_proven(&self) -> bool {
+        let unresolved = self.ops.iter().any(|op| match *op {
+            BrainFuckToken::JumpF(UNRESOLVED) | BrainFuckToken::JumpB(UNRESOLVED) => true,
+            _ => false,
+        });
+        self.memo.is_none() && !unresolved && check_jumps(&self.ops).is_ok() && self.tape.loc < self.tape.tape.len()
+    }
+
+    /// `run_fast` without bounds checks when `bounds_proven` says they
+    /// can't fail, and `run_fast` itself otherwise.
+    #[cfg(feature = "unchecked")]
+    fn run_unchecked<I: InputSource, O: OutputSink>(&mut self, input: &mut I, out: &mut O) -> HaltReason {
+        if !self.bounds_proven() {
+            return self.run_fast(input, out);
+        }
+
+        let mut loc = self.loc;
+        let mut steps = self.steps;
+        let mut checkpoint = self.next_checkpoint(steps);
+        let mut halt = HaltReason::Completed;
+        let (mut loop_head, mut loop_tail) = (usize::max_value(), usize::max_value());
+        let ops = &self.ops[..];
+
+        while loc < ops.len() {
+            if steps == checkpoint {
+                if let Some(reason) = self.limit_reached(steps) {
+                    halt = reason;
+                    break;
+                }
+                checkpoint = self.next_checkpoint(steps);
+            }
+            steps += 1;
+
+            // Safe: `loc` was just checked, and `bounds_proven` showed the
+            // pointer is on the tape, where `move_` keeps it.
+            unsafe {
+                match *ops.get_unchecked(loc) {
+                    BrainFuckToken::JumpF(x) => {
+                        if self.tape.get_unchecked() == 0 {
+                            loc = x;
+                        } else {
+                            loop_head = loc;
+                            loop_tail = x;
+                            self.tracer.trace((loc, x), steps);
+                        }
+                    }
+                    BrainFuckToken::JumpB(x) => {
+                        if self.tape.get_unchecked() != 0 {
+                            loc = if loc == loop_tail { loop_head } else { x };
+                        } else {
+                            self.tracer.leave((x, loc), steps);
+                        }
+                    }
+                    BrainFuckToken::Move(x) => if !self.tape.move_(x) {
+                        halt = HaltReason::OffTape(loc);
+                        break;
+                    },
+                    BrainFuckToken::Incr(x) => self.tape.incr_unchecked(x),
+                    BrainFuckToken::StdIn => match (input.next_value(), self.eof) {
+                        (Some(c), _) => self.tape.put_unchecked(c),
+                        (None, Eof::Zero) => self.tape.put_unchecked(0),
+                        (None, Eof::MinusOne) => self.tape.put_unchecked(-1),
+                        (None, Eof::Unchanged) => {}
+                        (None, Eof::Strict) => {
+                            halt = HaltReason::InputExhaustedStrict(loc);
+                            break;
+                        }
+                    },
+                    BrainFuckToken::StdOut => out.push(self.tape.get_unchecked() as u8),
+                    BrainFuckToken::ZeroOut => self.tape.put_unchecked(0),
+                    BrainFuckToken::MoveIncr(x, y) => {
+                        if !self.tape.move_(x) {
+                            halt = HaltReason::OffTape(loc);
+                            break;
+                        }
+                        self.tape.incr_unchecked(y);
+                    }
+                    BrainFuckToken::IncrOut(x) => {
+                        self.tape.incr_unchecked(x);
+                        out.push(self.tape.get_unchecked() as u8);
+                    }
+                    BrainFuckToken::Out(n) => out.push_repeated(self.tape.get_unchecked() as u8, n),
+                    BrainFuckToken::OutConst(b, n) => out.push_repeated(b, n),
+                }
+            }
+            if let Some(e) = out.take_error() {
+                halt = HaltReason::OutputError(e.to_string());
+                break;
+            }
+            loc += 1;
+        }
+
+        self.loc = loc;
+        self.steps = steps;
+        halt
+    }
+
+    /// The same work as `run_fast`, dispatched through `Handlers` instead of
+    /// a `match`. Needs every jump resolved up front.
+    fn run_table(&mut self, input: &mut dyn InputSource, out: &mut dyn OutputSink) -> HaltReason {
+        let code: Vec<Instr> = self.ops.iter().map(Instr::encode).collect();
+        self.run_code(&code[..], input, out)
+    }
+
+    fn run_code<K: Code + ?Sized>(&mut self, code: &K, input: &mut dyn InputSource, out: &mut dyn OutputSink) -> HaltReason {
+        let mut checkpoint = self.next_checkpoint(self.steps);
+        let mut m = Machine {
+            prog: self,
+            input: input,
+            out: out,
+            halt: HaltReason::Completed,
+        };
+
+        while let Some(instr) = code.get(m.prog.loc) {
+            if m.prog.steps == checkpoint {
+                if let Some(reason) = m.prog.limit_reached(m.prog.steps) {
+                    m.halt = reason;
+                    break;
+                }
+                checkpoint = m.prog.next_checkpoint(m.prog.steps);
+            }
+            m.prog.steps += 1;
+
+            Handlers::<C>::TABLE[instr.op as usize](&mut m, instr);
+            m.prog.loc += 1;
+        }
+
+        let halt = m.halt;
+        if self.loc > code.len() {
+            self.loc -= UNRESOLVED + 1;
+        }
+        halt
+    }
+
+    fn run_instrumented<I: InputSource, O: OutputSink>(&mut self, input: &mut I, out: &mut O) -> HaltReason {
+        // A resumed run is paced from where this call picked it up.
+        let (start, base) = (Instant::now(), self.steps);
+        let mut checkpoint = self.next_checkpoint(self.steps);
+
+        while self.loc < self.ops.len() {
+            if !resolve_jump(&mut self.ops, self.loc) {
+                return HaltReason::RuntimeError(unmatched_jump(self.loc));
+            }
+            let instr = &self.ops[self.loc];
+
+            if self.steps == checkpoint {
+                if let Some(reason) = self.limit_reached(self.steps) {
+                    return reason;
+                }
+                checkpoint = self.next_checkpoint(self.steps);
+            }
+            #[cfg(feature = "cli")]
+            if let Some(ref mut checkpoints) = self.checkpoints {
+                if checkpoints.due(self.steps) {
+                    checkpoints.write(self.steps, &self.tape).ok();
+                }
+            }
+            self.steps += 1;
+
+            #[cfg(feature = "cli")]
+            if let Some(ref mut debugger) = self.debugger {
+                if !debugger.before_step(self.loc, instr, &self.tape) {
+                    return HaltReason::Cancelled;
+                }
+            }
+
+            #[cfg(feature = "cli")]
+            if let Some(ref mut trajectory) = self.trajectory {
+                trajectory.sample(self.steps, self.tape.loc);
+            }
+
+            #[cfg(feature = "cli")]
+            if let Some(ref mut audit) = self.audit {
+                // Only a `,` cares, and peeking may block on an interactive source.
+                let input_left = match *instr {
+                    BrainFuckToken::StdIn => input.has_more(),
+                    _ => true,
+                };
+                audit.before_step(self.steps, self.loc, instr, &self.tape, input_left);
+            }
+
+            #[cfg(feature = "cli")]
+            if let Some(ref mut animation) = self.animation {
+                animation.sample(self.steps, &self.tape);
+            }
+
+            if let Some(interval) = self.throttle {
+                let due = Duration::from_nanos((interval.as_nanos() as u64).saturating_mul(self.steps - base));
+                let elapsed = start.elapsed();
+                if due > elapsed {
+                    std::thread::sleep(due - elapsed);
+                }
+            }
+
+            let (op, ptr) = (self.loc, self.tape.loc);
+            #[cfg(feature = "cli")]
+            let cell = self.tape.get();
+            let mut step_output = Vec::new();
+            if let Some(ref mut counts) = self.op_counts {
+                counts[self.loc] += 1;
+            }
+            if let Some(ref mut trail) = self.trail {
+                match *instr {
+                    BrainFuckToken::Move(x) | BrainFuckToken::MoveIncr(x, _) => trail.record(self.steps, self.loc, ptr, x),
+                    _ => {}
+                }
+            }
+            match *instr {
+                BrainFuckToken::JumpF(x) => {
+                    if self.tape.get() == 0 {
+                        self.loc = x;
+                    } else {
+                        self.tracer.trace((self.loc, x), self.steps);
+                        if let Some(ref mut capture) = self.loop_capture {
+                            capture.enter(self.loc, &self.tape);
+                        }
+                    }
+                }
+                BrainFuckToken::JumpB(x) => {
+                    if self.tape.get() != 0 {
+                        self.loc = x;
+                    } else {
+                        self.tracer.leave((x, self.loc), self.steps);
+                        if let Some(ref mut capture) = self.loop_capture {
+                            capture.leave(x, &self.tape);
+                        }
+                    }
+                }
+                BrainFuckToken::Move(x) => if !self.tape.move_(x) {
+                    return HaltReason::OffTape(self.loc);
+                },
+                BrainFuckToken::Incr(x) => self.tape.incr(x),
+                BrainFuckToken::StdIn => {
+                    let c = input.next_value();
+                    if let Some(ref mut transcript) = self.transcript {
+                        let io = if c.is_some() { Io::Input } else { Io::Eof };
+                        transcript.record(self.steps, io, c.map(|c| c as u8));
+                    }
+
+                    match (c, self.eof) {
+                        (Some(c), _) => self.tape.put(c),
+                        (None, Eof::Zero) => self.tape.put(0),
+                        (None, Eof::MinusOne) => self.tape.put(-1),
+                        (None, Eof::Unchanged) => {}
+                        (None, Eof::Strict) => return HaltReason::InputExhaustedStrict(self.loc),
+                    }
+                }
+                BrainFuckToken::MoveIncr(x, y) => {
+                    if !self.tape.move_(x) {
+                        return HaltReason::OffTape(self.loc);
+                    }
+                    self.tape.incr(y);
+                }
+                BrainFuckToken::StdOut
+                | BrainFuckToken::IncrOut(_)
+                | BrainFuckToken::Out(_)
+                | BrainFuckToken::OutConst(_, _) => {
+                    let (b, times) = match *instr {
+                        BrainFuckToken::IncrOut(x) => {
+                            self.tape.incr(x);
+                            (self.tape.getc(), 1)
+                        }
+                        BrainFuckToken::Out(n) => (self.tape.getc(), n),
+                        BrainFuckToken::OutConst(b, n) => (b, n),
+                        _ => (self.tape.getc(), 1),
+                    };
+                    let bytes = vec![b; times];
+                    if let Some(ref mut transcript) = self.transcript {
+                        for _ in 0..times {
+                            transcript.record(self.steps, Io::Output, Some(b));
+                        }
+                    }
+                    #[cfg(feature = "cli")]
+                    if let Some(ref mut checkpoints) = self.checkpoints {
+                        checkpoints.output.extend_from_slice(&bytes);
+                    }
+                    if let Some(ref mut capture) = self.loop_capture {
+                        capture.output(&bytes);
+                    }
+                    if self.live_output {
+                        write_output(&self.encode_output(&bytes));
+                    }
+                    out.push_repeated(b, times);
+                    if let Some(e) = out.take_error() {
+                        return HaltReason::OutputError(e.to_string());
+                    }
+                    step_output = bytes;
+                }
+                BrainFuckToken::ZeroOut => self.tape.put(0),
+            }
+            if self.recording.is_some() || self.replay.is_some() {
+                let store = match *instr {
+                    BrainFuckToken::Incr(_)
+                    | BrainFuckToken::StdIn
+                    | BrainFuckToken::ZeroOut
+                    | BrainFuckToken::MoveIncr(_, _)
+                    | BrainFuckToken::IncrOut(_) => Some((self.tape.loc, self.tape.get())),
+                    _ => None,
+                };
+                let record = StepRecord {
+                    step: self.steps,
+                    op: op,
+                    store: store,
+                    output: step_output,
+                };
+                if let Some(ref mut replay) = self.replay {
+                    if !replay.check(record.clone()) {
+                        return HaltReason::Aborted(format!("diverged from the recording at step {}", self.steps));
+                    }
+                }
+                if let Some(ref mut recording) = self.recording {
+                    recording.steps.push(record);
+                }
+            }
+            #[cfg(feature = "cli")]
+            if let Some(ref mut explainer) = self.explainer {
+                status!("{}", explainer.explain(self.steps, instr, ptr, cell, &self.tape));
+            }
+            #[cfg(feature = "cli")]
+            if let Some(ref mut triggers) = self.triggers {
+                if let Some(why) = triggers.after_step(self.steps, &self.tape) {
+                    return HaltReason::Aborted(why);
+                }
+            }
+            self.loc += 1;
+        }
+
+        HaltReason::Completed
+    }
+}
+
+/// Compiles a program with the same defaults as `run_str`, refusing an
+/// unbalanced one rather than panicking.
+///
+/// ```
+/// use rustfuck::{ParseError, Program};
+///
+/// let mut prog: Program = "++[>+<-]>.".parse().unwrap();
+/// let mut out = Vec::new();
+/// prog.run(b"", &mut out);
+/// assert_eq!(out, [2]);
+///
+/// match "+[".parse::<Program>() {
+///     Err(ParseError::UnclosedOpen { .. }) => {}
+///     _ => panic!("an unclosed loop was accepted"),
+/// }
+/// ```
+#[cfg(feature = "std")]
+impl FromStr for Program {
+    type Err = ParseError;
+
+    fn from_str(source: &str) -> std::result::Result<Program, ParseError> {
+        // The defaults are always a valid interpreter.
+        Interpreter::builder().interpreter.parse_program(source)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> TryFrom<&'a str> for Program {
+    type Error = ParseError;
+
+    fn try_from(source: &'a str) -> std::result::Result<Program, ParseError> {
+        source.parse()
+    }
+}
+
+/// Names a program given to a `Scheduler`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct JobId(pub u64);
+
+/// A program the scheduler has finished running.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct Finished {
+    pub id: JobId,
+    /// How the run ended; `steps` counts the whole run.
+    pub result: RunResult,
+    pub output: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+struct Job {
+    id: JobId,
+    prog: Program,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    quota: u64,
+}
+
+/// Runs several programs in one thread by giving each a slice of steps in
+/// turn, for hosts like wasm that can't spawn threads. Each `poll` is one
+/// round over every program still running.
+///
+/// ```
+/// use rustfuck::{HaltReason, Program, Scheduler};
+///
+/// let mut scheduler = Scheduler::new(100);
+/// let slow = scheduler.submit("+[>+<+]".parse::<Program>().unwrap(), b"");
+/// let quick = scheduler.submit(",.".parse::<Program>().unwrap(), b"a");
+///
+/// let done = scheduler.poll();
+/// assert_eq!(done.len(), 1);
+/// assert_eq!(done[0].id, quick);
+/// assert_eq!(done[0].output, b"a");
+/// assert_eq!(scheduler.len(), 1);
+///
+/// let done = scheduler.cancel(slow).unwrap();
+/// assert_eq!(done.result.halt, HaltReason::Cancelled);
+/// assert!(scheduler.is_empty());
+/// ```
+#[cfg(feature = "std")]
+pub struct Scheduler {
+    quota: u64,
+    next_id: u64,
+    jobs: VecDeque<Job>,
+}
+
+#[cfg(feature = "std")]
+impl Scheduler {
+    /// A scheduler that gives each program `quota` steps per round unless
+    /// it was submitted with its own.
+    pub fn new(quota: u64) -> Scheduler {
+        Scheduler {
+            quota: quota.max(1),
+            next_id: 0,
+            jobs: VecDeque::new(),
+        }
+    }
+
+    /// Queues `prog` to run on `input` with the default quota.
+    pub fn submit(&mut self, prog: Program, input: &[u8]) -> JobId {
+        let quota = self.quota;
+        self.submit_with_quota(prog, input, quota)
+    }
+
+    /// Queues `prog` to run on `input`, `quota` steps per round.
+    pub fn submit_with_quota(&mut self, mut prog: Program, input: &[u8], quota: u64) -> JobId {
+        prog.end_input();
+        let id = JobId(self.next_id);
+        self.next_id += 1;
+        self.jobs.push_back(Job {
+            id: id,
+            prog: prog,
+            input: input.to_vec(),
+            output: Vec::new(),
+            quota: quota.max(1),
+        });
+        id
+    }
+
+    /// Runs every queued program for its quota, in the order they were
+    /// submitted, and hands back those that finished.
+    pub fn poll(&mut self) -> Vec<Finished> {
+        let mut finished = Vec::new();
+        for _ in 0..self.jobs.len() {
+            let mut job = self.jobs.pop_front().unwrap();
+            match job.prog.run_for(&job.input, &mut job.output, job.quota) {
+                RunState::Running | RunState::NeedsInput => self.jobs.push_back(job),
+                RunState::Halted(result) => finished.push(Finished {
+                    id: job.id,
+                    result: RunResult {
+                        output_bytes: job.output.len() as u64,
+                        ..result
+                    },
+                    output: job.output,
+                }),
+            }
+        }
+        finished
+    }
+
+    /// Drops a program before it finishes, handing back what it had
+    /// written so far.
+    pub fn cancel(&mut self, id: JobId) -> Option<Finished> {
+        let idx = self.jobs.iter().position(|job| job.id == id)?;
+        let job = self.jobs.remove(idx).unwrap();
+        Some(Finished {
+            id: id,
+            result: RunResult {
+                halt: HaltReason::Cancelled,
+                steps: job.prog.steps,
+                output_bytes: job.output.len() as u64,
+            },
+            output: job.output,
+        })
+    }
+
+    /// How many programs are still running.
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+}
+
+/// Most steps `run_str` lets a program take before giving up on it.
+#[cfg(feature = "std")]
+pub const RUN_STR_STEP_LIMIT: u64 = 1_000_000_000;
+
+/// Why `run_str` didn't return a program's output.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Parse(ParseError),
+    /// The run stopped before the program finished, with what it had
+    /// written so far.
+    Halted { reason: HaltReason, output: Vec<u8> },
+    /// An `InterpreterBuilder` setting that can't be used, from `build`.
+    Config(String),
+}
+
+#[cfg(feature = "std")]
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match *self {
+            Error::Parse(ref e) => write!(f, "{}", e),
+            Error::Halted { ref reason, .. } => write!(f, "program {}", reason),
+            Error::Config(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+#[cfg(feature = "std")]
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Error {
+        Error::Parse(e)
+    }
+}
+
+/// What `Interpreter::run` reports about a program that ran to completion.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExecutionResult {
+    pub output: Vec<u8>,
+    /// Instructions executed, counting each optimized op once.
+    pub steps: u64,
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    /// The rightmost cell the pointer reached.
+    pub max_ptr: usize,
+    /// How long the run took, not counting compiling the program.
+    pub elapsed: Duration,
+    /// Why the program ran unoptimized, if optimizing it went over the
+    /// interpreter's `OptBudget`.
+    ///
+    /// ```
+    /// use rustfuck::{BudgetExceeded, Interpreter, OptBudget};
+    ///
+    /// let budget = OptBudget { max_passes: 1, ..OptBudget::default() };
+    /// let result = Interpreter::builder().opt_budget(budget).build().unwrap().run("+.", b"").unwrap();
+    /// assert_eq!(result.unoptimized, Some(BudgetExceeded::Passes(2)));
+    /// ```
+    pub unoptimized: Option<BudgetExceeded>,
+}
+
+/// Runs `source` on `input` and returns what it writes. This is the whole
+/// pipeline with the usual defaults: 8-bit wrapping cells, a tape that grows
+/// to the right as needed, 0 on end of input, the default passes and
+/// `RUN_STR_STEP_LIMIT` steps. `Interpreter::builder()` changes any of them.
+///
+/// ```
+/// let out = rustfuck::run_str(",[.,]", b"echo").unwrap();
+/// assert_eq!(out, b"echo");
+/// ```
+#[cfg(feature = "std")]
+pub fn run_str(source: &str, input: &[u8]) -> std::result::Result<Vec<u8>, Error> {
+    Interpreter::builder().build()?.run(source, input).map(|result| result.output)
+}
+
+/// Everything that decides how a program is compiled and run, set up once
+/// with `Interpreter::builder()` and reused for any number of programs.
+///
+/// ```
+/// use rustfuck::{CellWidth, Eof, Interpreter};
+///
+/// let interpreter = Interpreter::builder()
+///     .cell_width(CellWidth::Bits16)
+///     .eof(Eof::MinusOne)
+///     .tape_size(1000)
+///     .opt_level(2)
+///     .build()
+///     .unwrap();
+/// assert_eq!(interpreter.run(",+[-.,+]", b"hi").unwrap().output, b"hi");
+/// ```
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct Interpreter {
+    profile: Profile,
+    parse: ParseOptions,
+    passes: Vec<Pass>,
+    budget: OptBudget,
+    step_limit: u64,
+}
+
+#[cfg(feature = "std")]
+impl Interpreter {
+    /// Starts from the same defaults as `run_str`.
+    pub fn builder() -> InterpreterBuilder {
+        InterpreterBuilder {
+            opt_level: None,
+            interpreter: Interpreter {
+                profile: Profile {
+                    cells: CellWidth::Bits8,
+                    grow: true,
+                    ..Profile::default()
+                },
+                parse: ParseOptions::default(),
+                passes: DEFAULT_PASSES.to_vec(),
+                budget: OptBudget::default(),
+                step_limit: RUN_STR_STEP_LIMIT,
+            },
+        }
+    }
+
+    /// Parses and optimizes `source` into a program ready to run.
+    pub fn compile(&self, source: &str) -> std::result::Result<Program, Error> {
+        Ok(self.parse_program(source)?)
+    }
+
+    fn parse_program(&self, source: &str) -> std::result::Result<Program, ParseError> {
+        let tokens = parse_with(source, &self.parse)?;
+        check_balanced(source)?;
+        let (ops, exceeded) = optimize_budgeted(tokens, &self.passes, &mut Fuel::unlimited(), self.budget, &mut Timings::default());
+        let mut prog = Program::with_profile(ops, self.profile);
+        prog.step_limit = self.step_limit;
+        prog.unoptimized = exceeded;
+        Ok(prog)
+    }
+
+    /// The interpreter as an `Engine`, with this profile and step limit.
+    pub fn engine(&self) -> Walker {
+        Walker {
+            profile: self.profile,
+            dispatch: Dispatch::Match,
+            step_limit: self.step_limit,
+        }
+    }
+
+    /// Compiles and runs `source` on `input`, returning what it writes
+    /// along with how the run went.
+    ///
+    /// ```
+    /// use rustfuck::Interpreter;
+    ///
+    /// let result = Interpreter::builder().build().unwrap().run(",[>+<-]>.", &[3]).unwrap();
+    /// assert_eq!(result.output, [3]);
+    /// assert_eq!((result.input_bytes, result.output_bytes), (1, 1));
+    /// assert_eq!(result.max_ptr, 1);
+    /// ```
+    pub fn run(&self, source: &str, input: &[u8]) -> std::result::Result<ExecutionResult, Error> {
+        let mut prog = self.compile(source)?;
+        let mut output = Vec::new();
+        let start = Instant::now();
+        let result = prog.run(input, &mut output);
+        let elapsed = start.elapsed();
+        match result.halt {
+            HaltReason::Completed => Ok(ExecutionResult {
+                output: output,
+                steps: result.steps,
+                input_bytes: prog.input_read() as u64,
+                output_bytes: result.output_bytes,
+                max_ptr: prog.tape.max_loc(),
+                elapsed: elapsed,
+                unoptimized: prog.unoptimized.take(),
+            }),
+            reason => Err(Error::Halted {
+                reason: reason,
+                output: output,
+            }),
+        }
+    }
+}
+
+/// Sets up an `Interpreter`. Every setting has a default, so only the ones
+/// that matter need calling.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct InterpreterBuilder {
+    interpreter: Interpreter,
+    /// A level `opt_level` was given that has no passes, for `build` to
+    /// refuse.
+    opt_level: Option<u8>,
+}
+
+#[cfg(feature = "std")]
+impl InterpreterBuilder {
+    /// Cells the tape starts with, at least one. It still grows unless
+    /// `grow_tape(false)`.
+    pub fn tape_size(mut self, cells: usize) -> InterpreterBuilder {
+        self.interpreter.profile.tape = cells;
+        self
+    }
+
+    /// Whether moving past the right end of the tape extends it, rather than
+    /// wrapping around to the start.
+    pub fn grow_tape(mut self, grow: bool) -> InterpreterBuilder {
+        self.interpreter.profile.grow = grow;
+        self
+    }
+
+    pub fn cell_width(mut self, cells: CellWidth) -> InterpreterBuilder {
+        self.interpreter.profile.cells = cells;
+        self
+    }
+
+    pub fn eof(mut self, eof: Eof) -> InterpreterBuilder {
+        self.interpreter.profile.eof = eof;
+        self
+    }
+
+    pub fn ip_end(mut self, ip_end: IpEnd) -> InterpreterBuilder {
+        self.interpreter.profile.ip_end = ip_end;
+        self
+    }
+
+    /// The passes of `-O0` to `-O3`. `build` refuses any other level.
+    pub fn opt_level(mut self, level: u8) -> InterpreterBuilder {
+        match opt_level_passes(level) {
+            Some(passes) => {
+                self.interpreter.passes = passes.to_vec();
+                self.opt_level = None;
+            }
+            None => self.opt_level = Some(level),
+        }
+        self
+    }
+
+    pub fn passes(mut self, passes: &[Pass]) -> InterpreterBuilder {
+        self.interpreter.passes = passes.to_vec();
+        self.opt_level = None;
+        self
+    }
+
+    /// Limits on optimizing, past which `compile` leaves the program
+    /// unoptimized and says why in `Program::unoptimized`.
+    pub fn opt_budget(mut self, budget: OptBudget) -> InterpreterBuilder {
+        self.interpreter.budget = budget;
+        self
+    }
+
+    pub fn step_limit(mut self, steps: u64) -> InterpreterBuilder {
+        self.interpreter.step_limit = steps;
+        self
+    }
+
+    /// Limits on the programs `compile` accepts.
+    pub fn parse_options(mut self, options: ParseOptions) -> InterpreterBuilder {
+        self.interpreter.parse = options;
+        self
+    }
+
+    /// The interpreter, or `Error::Config` if a setting can't be used.
+    ///
+    /// ```
+    /// use rustfuck::{Error, Interpreter};
+    ///
+    /// assert!(Interpreter::builder().opt_level(3).build().is_ok());
+    /// match Interpreter::builder().tape_size(0).build() {
+    ///     Err(Error::Config(e)) => assert_eq!(e, "a tape needs at least one cell"),
+    ///     _ => panic!("an empty tape was accepted"),
+    /// }
+    /// assert!(Interpreter::builder().opt_level(4).build().is_err());
+    /// ```
+    pub fn build(self) -> std::result::Result<Interpreter, Error> {
+        if self.interpreter.profile.tape == 0 {
+            return Err(Error::Config("a tape needs at least one cell".to_string()));
+        }
+        if let Some(level) = self.opt_level {
+            return Err(Error::Config(format!("there is no optimization level {}", level)));
+        }
+        Ok(self.interpreter)
+    }
+}
+
+/// The ops an `Engine` runs: optimized, with every jump resolved.
+pub type Ir = [BrainFuckToken];
+
+/// A backend that runs programs, so the interpreter and alternatives to it
+/// (a bytecode VM, a JIT) can stand in for each other and be benchmarked
+/// head to head.
+///
+/// ```
+/// use rustfuck::{optimize, parse, Dispatch, Engine, Fuel, Interpreter, DEFAULT_PASSES};
+///
+/// let ir = optimize(parse(",[.,]", 100), DEFAULT_PASSES, &mut Fuel::unlimited());
+/// let walker = Interpreter::builder().build().unwrap().engine();
+/// let table = rustfuck::Walker {
+///     dispatch: Dispatch::Table,
+///     ..walker
+/// };
+///
+/// let engines: Vec<Box<dyn Engine>> = vec![Box::new(walker), Box::new(table)];
+/// for mut engine in engines {
+///     let mut out = Vec::new();
+///     let stats = engine.run(&ir, &mut &b"hi"[..], &mut out).unwrap();
+///     assert_eq!(out, b"hi");
+///     assert_eq!(stats.output_bytes, 2);
+/// }
+/// ```
+#[cfg(feature = "std")]
+pub trait Engine {
+    /// Runs `ir` to the end, reading from `input` and writing to `output`.
+    /// An `Err` is from one of those two; how the program itself ended,
+    /// errors included, is in the result.
+    fn run(&mut self, ir: &Ir, input: &mut dyn io::Read, output: &mut dyn io::Write) -> io::Result<RunResult>;
+}
+
+/// The interpreter behind `Program::run` as an `Engine`. It reads input as
+/// the program asks for it and streams output as it goes.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy)]
+pub struct Walker {
+    pub profile: Profile,
+    pub dispatch: Dispatch,
+    pub step_limit: u64,
+}
+
+#[cfg(feature = "std")]
+impl Walker {
+    pub fn new(profile: Profile) -> Walker {
+        Walker {
+            profile: profile,
+            dispatch: Dispatch::Match,
+            step_limit: u64::max_value(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Engine for Walker {
+    fn run(&mut self, ir: &Ir, input: &mut dyn io::Read, output: &mut dyn io::Write) -> io::Result<RunResult> {
+        let mut prog = Program::with_profile(ir.to_vec(), self.profile);
+        prog.dispatch = self.dispatch;
+        prog.step_limit = self.step_limit;
+        prog.run_io(input, output)
+    }
+}
+
+/// An interpreter loop over any `Memory`, compiled separately for each
+/// kind so the `Tape` case costs nothing extra. It has none of `Program`'s
+/// hooks: only the EOF and end-of-program behavior and a step limit. Jumps
+/// are checked the way `Program` checks them, but never resolved lazily,
+/// so a `[` `--lazy-jumps` left unresolved is a runtime error when reached.
+///
+/// ```
+/// use rustfuck::{optimize, parse, CellWidth, Engine, Eof, Fuel, HaltReason, SparseTape, TapeWalker, DEFAULT_PASSES};
+///
+/// let ir = optimize(parse("<<<+++.", 100), DEFAULT_PASSES, &mut Fuel::unlimited());
+/// let mut walker = TapeWalker::new(SparseTape::new(CellWidth::Bits8), Eof::Zero);
+/// let mut out = Vec::new();
+/// let result = walker.run(&ir, &mut &b""[..], &mut out).unwrap();
+/// assert_eq!(result.halt, HaltReason::Completed);
+/// assert_eq!(out, [3]);
+/// assert_eq!(walker.memory.loc, -3);
+/// ```
+#[derive(Debug, Clone)]
+pub struct TapeWalker<M: Memory> {
+    pub memory: M,
+    pub eof: Eof,
+    pub ip_end: IpEnd,
+    pub step_limit: u64,
+}
+
+impl<M: Memory> TapeWalker<M> {
+    pub fn new(memory: M, eof: Eof) -> TapeWalker<M> {
+        TapeWalker {
+            memory: memory,
+            eof: eof,
+            ip_end: IpEnd::Error,
+            step_limit: u64::max_value(),
+        }
+    }
+
+    /// Runs `ir` on values from `input`, appending its output to `out`.
+    /// Unlike `Engine::run` this needs no `io`, so it is how a `no_std`
+    /// build runs programs.
+    ///
+    /// ```
+    /// use rustfuck::{optimize, parse, CellWidth, Eof, Fuel, HaltReason, Tape, TapeWalker, DEFAULT_PASSES};
+    ///
+    /// let ir = optimize(parse(",[.-]", 100), DEFAULT_PASSES, &mut Fuel::unlimited());
+    /// let tape: Tape = Tape::new(CellWidth::Bits8, 30000);
+    /// let mut walker = TapeWalker::new(tape, Eof::Zero);
+    /// let mut out = Vec::new();
+    /// let result = walker.run_with(&ir, &mut [3].iter(), &mut out);
+    /// assert_eq!(result.halt, HaltReason::Completed);
+    /// assert_eq!(out, [3, 2, 1]);
+    ///
+    /// // A jump table that doesn't pair up is refused before the first step.
+    /// let mut bad = ir.clone();
+    /// bad[1] = rustfuck::BrainFuckToken::JumpF(0);
+    /// match walker.run_with(&bad, &mut [3].iter(), &mut out).halt {
+    ///     HaltReason::RuntimeError(e) => assert!(e.contains("doesn't jump back")),
+    ///     halt => panic!("{:?}", halt),
+    /// }
+    /// ```
+    pub fn run_with<I: InputSource, O: OutputSink>(&mut self, ir: &Ir, input: &mut I, out: &mut O) -> RunResult {
+        let start = out.written();
+        let (halt, steps) = self.walk(ir, input, out);
+        RunResult {
+            halt: halt,
+            steps: steps,
+            output_bytes: out.written() - start,
+        }
+    }
+
+    fn walk<I: InputSource, O: OutputSink>(&mut self, ir: &Ir, input: &mut I, out: &mut O) -> (HaltReason, u64) {
+        if self.ip_end == IpEnd::Error {
+            if let Err(e) = check_jumps(ir) {
+                return (HaltReason::RuntimeError(e), 0);
+            }
+        }
+
+        let memory = &mut self.memory;
+        let (mut loc, mut steps) = (0, 0);
+
+        while let Some(instr) = ir.get(loc) {
+            if steps == self.step_limit {
+                return (HaltReason::StepLimit, steps);
+            }
+            steps += 1;
+
+            match *instr {
+                BrainFuckToken::JumpF(UNRESOLVED) => return (HaltReason::RuntimeError(unmatched_jump(loc)), steps - 1),
+                BrainFuckToken::JumpF(x) => if memory.get() == 0 {
+                    loc = x;
+                },
+                BrainFuckToken::JumpB(x) => if memory.get() != 0 {
+                    loc = x;
+                },
+                BrainFuckToken::Move(x) => if !memory.move_by(x) {
+                    return (HaltReason::OffTape(loc), steps);
+                },
+                BrainFuckToken::Incr(x) => memory.incr(x),
+                BrainFuckToken::StdIn => match (input.next_value(), self.eof) {
+                    (Some(c), _) => memory.put(c),
+                    (None, Eof::Zero) => memory.put(0),
+                    (None, Eof::MinusOne) => memory.put(-1),
+                    (None, Eof::Unchanged) => {}
+                    (None, Eof::Strict) => return (HaltReason::InputExhaustedStrict(loc), steps),
+                },
+                BrainFuckToken::StdOut => out.push(memory.get() as u8),
+                BrainFuckToken::ZeroOut => memory.put(0),
+                BrainFuckToken::MoveIncr(x, y) => {
+                    if !memory.move_by(x) {
+                        return (HaltReason::OffTape(loc), steps);
+                    }
+                    memory.incr(y);
+                }
+                BrainFuckToken::IncrOut(x) => {
+                    memory.incr(x);
+                    out.push(memory.get() as u8);
+                }
+                BrainFuckToken::Out(n) => out.push_repeated(memory.get() as u8, n),
+                BrainFuckToken::OutConst(b, n) => out.push_repeated(b, n),
+            }
+            if let Some(fault) = memory.take_fault() {
+                return (HaltReason::RuntimeError(fault), steps);
+            }
+            #[cfg(feature = "std")]
+            {
+                if let Some(e) = out.take_error() {
+                    return (HaltReason::OutputError(e.to_string()), steps);
+                }
+            }
+            loc += 1;
+        }
+
+        // As in `Program::run_from`, only an unresolved `]` jumps past the
+        // end once the targets have been checked.
+        if loc > ir.len() && self.ip_end == IpEnd::Error {
+            return (HaltReason::RuntimeError("a `]` has no matching `[`".to_string()), steps);
+        }
+        (HaltReason::Completed, steps)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<M: Memory> Engine for TapeWalker<M> {
+    fn run(&mut self, ir: &Ir, input: &mut dyn io::Read, output: &mut dyn io::Write) -> io::Result<RunResult> {
+        let mut source = ReadSource::new(input);
+        let mut sink = WriteSink::new(output, Backpressure::Block);
+        let (halt, steps) = self.walk(ir, &mut source, &mut sink);
+        if let Some(e) = source.take_error() {
+            return Err(e);
+        }
+        let output_bytes = sink.written();
+        match halt {
+            HaltReason::OutputError(_) => {}
+            _ => {
+                sink.finish()?;
+            }
+        }
+        Ok(RunResult {
+            halt: halt,
+            steps: steps,
+            output_bytes: output_bytes,
+        })
+    }
+}
+
+pub fn off_tape(loc: usize) -> String {
+    format!("op {} moved the pointer left of the first cell", loc)
+}
+
+fn unmatched_jump(loc: usize) -> String {
+    format!("the `[` at op {} has no matching `]`", loc)
+}
+
+/// Checks that every resolved jump lands on the bracket that jumps back to
+/// it, so a corrupted jump table is reported rather than quietly ending the
+/// run. Jumps `--lazy-jumps` hasn't resolved yet are skipped.
+fn check_jumps(ops: &[BrainFuckToken]) -> std::result::Result<(), String> {
+    for (loc, op) in ops.iter().enumerate() {
+        let (target, back) = match *op {
+            BrainFuckToken::JumpF(UNRESOLVED) | BrainFuckToken::JumpB(UNRESOLVED) => continue,
+            BrainFuckToken::JumpF(x) => (x, match ops.get(x) {
+                Some(&BrainFuckToken::JumpB(y)) => y == loc,
+                _ => false,
+            }),
+            BrainFuckToken::JumpB(x) => (x, match ops.get(x) {
+                Some(&BrainFuckToken::JumpF(y)) => y == loc,
+                _ => false,
+            }),
+            _ => continue,
+        };
+        if target >= ops.len() {
+            return Err(format!(
+                "op {} jumps to op {}, past the end of the {}-op program",
+                loc,
+                target,
+                ops.len()
+            ));
+        } else if !back {
+            return Err(format!("op {} jumps to op {}, which doesn't jump back to it", loc, target));
+        }
+    }
+    Ok(())
+}
+
+impl BrainFuckToken {
+    pub fn from_char(c: char) -> Option<BrainFuckToken> {
+        match c {
+            '+' => Some(BrainFuckToken::Incr(1)),
+            '-' => Some(BrainFuckToken::Incr(-1)),
+            '>' => Some(BrainFuckToken::Move(1)),
+            '<' => Some(BrainFuckToken::Move(-1)),
+            '.' => Some(BrainFuckToken::StdOut),
+            ',' => Some(BrainFuckToken::StdIn),
+            '[' => Some(BrainFuckToken::JumpF(0)),
+            ']' => Some(BrainFuckToken::JumpB(0)),
+            _ => None,
+        }
+    }
+
+    /// Writes the token back out as Brainfuck source.
+    fn write_source(&self, s: &mut String) {
+        fn repeat(s: &mut String, c: char, n: usize) {
+            s.extend(std::iter::repeat_n(c, n));
+        }
+
+        match *self {
+            BrainFuckToken::Incr(x) if x < 0 => repeat(s, '-', -x as usize),
+            BrainFuckToken::Incr(x) => repeat(s, '+', x as usize),
+            BrainFuckToken::Move(x) if x < 0 => repeat(s, '<', -x as usize),
+            BrainFuckToken::Move(x) => repeat(s, '>', x as usize),
+            BrainFuckToken::StdOut => s.push('.'),
+            BrainFuckToken::StdIn => s.push(','),
+            BrainFuckToken::JumpF(_) => s.push('['),
+            BrainFuckToken::JumpB(_) => s.push(']'),
+            BrainFuckToken::ZeroOut => s.push_str("[-]"),
+            BrainFuckToken::MoveIncr(x, y) => {
+                BrainFuckToken::Move(x).write_source(s);
+                BrainFuckToken::Incr(y).write_source(s);
+            }
+            BrainFuckToken::IncrOut(x) => {
+                BrainFuckToken::Incr(x).write_source(s);
+                s.push('.');
+            }
+            BrainFuckToken::Out(n) | BrainFuckToken::OutConst(_, n) => repeat(s, '.', n),
+        }
+    }
+}
+
+/// Limits on the programs `parse_with` accepts, so a caller can bound the
+/// work and memory a program costs before running any of it.
+#[derive(Debug, Clone, Copy)]
+pub struct ParseOptions {
+    /// Most commands the program may contain.
+    pub max_tokens: Option<usize>,
+    /// Largest source, in bytes, including comments.
+    pub max_source_bytes: Option<usize>,
+    /// Deepest loop nesting allowed.
+    pub max_depth: usize,
+}
+
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        ParseOptions {
+            max_tokens: None,
+            max_source_bytes: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+/// Why `parse_with` or `check_balanced` refused a program.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    SourceTooLarge { bytes: usize, max: usize },
+    TooManyTokens { max: usize },
+    TooDeep { depth: usize, max: usize, span: Span },
+    UnmatchedClose { span: Span },
+    UnclosedOpen { span: Span },
+    /// An Ook! word left over at the end, with nothing to pair with.
+    UnpairedOok { span: Span },
+    /// A pair of Ook! words that isn't a command, e.g. `Ook? Ook?`.
+    UnknownOok { span: Span },
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match *self {
+            ParseError::SourceTooLarge { bytes, max } => {
+                write!(f, "source is {} bytes, more than the maximum of {}", bytes, max)
+            }
+            ParseError::TooManyTokens { max } => write!(f, "program has more than {} commands", max),
+            ParseError::TooDeep { depth, max, span } => write!(
+                f,
+                "Loop nesting depth {} exceeds the maximum of {} at line {}, column {}",
+                depth, max, span.line, span.column
+            ),
+            ParseError::UnmatchedClose { span } => {
+                write!(f, "`]` at line {}, column {} has no matching `[`", span.line, span.column)
+            }
+            ParseError::UnclosedOpen { span } => {
+                write!(f, "`[` at line {}, column {} is never closed", span.line, span.column)
+            }
+            ParseError::UnpairedOok { span } => {
+                write!(f, "Ook! word at line {}, column {} has no partner", span.line, span.column)
+            }
+            ParseError::UnknownOok { span } => {
+                write!(f, "Ook! pair at line {}, column {} isn't a command", span.line, span.column)
+            }
+        }
+    }
+}
+
+impl ParseError {
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let (code, span) = match *self {
+            ParseError::SourceTooLarge { .. } => ("source-too-large", None),
+            ParseError::TooManyTokens { .. } => ("too-many-tokens", None),
+            ParseError::TooDeep { span, .. } => ("nesting-depth", Some(span)),
+            ParseError::UnmatchedClose { span } | ParseError::UnclosedOpen { span } => {
+                ("unbalanced-loops", Some(span))
+            }
+            ParseError::UnpairedOok { span } | ParseError::UnknownOok { span } => ("invalid-ook", Some(span)),
+        };
+        Diagnostic {
+            code: code,
+            level: Level::Deny,
+            span: span,
+            message: self.to_string(),
+            notes: Vec::new(),
+        }
+    }
+}
+
+/// Makes sure every `[` in `source` has a matching `]`, which `parse_with`
+/// leaves to the lints so that unbalanced programs can still be run with
+/// lazily resolved jumps. `build_jumps` panics on them.
+pub fn check_balanced(source: &str) -> std::result::Result<(), ParseError> {
+    let mut open = Vec::new();
+
+    for (span, token) in spanned_tokens(source) {
+        match token {
+            BrainFuckToken::JumpF(_) => open.push(span),
+            BrainFuckToken::JumpB(_) => {
+                open.pop().ok_or(ParseError::UnmatchedClose { span: span })?;
+            }
+            _ => {}
+        }
+    }
+
+    match open.first() {
+        Some(&span) => Err(ParseError::UnclosedOpen { span: span }),
+        None => Ok(()),
+    }
+}
+
+/// Lexes the source, refusing programs over any of the limits in `options`.
+pub fn parse_with(source: &str, options: &ParseOptions) -> std::result::Result<VecDeque<BrainFuckToken>, ParseError> {
+    if let Some(max) = options.max_source_bytes {
+        if source.len() > max {
+            return Err(ParseError::SourceTooLarge {
+                bytes: source.len(),
+                max: max,
+            });
+        }
+    }
+
+    let mut tokens = VecDeque::new();
+    let mut depth = 0;
+    let mut cursor = Cursor::default();
+
+    for c in source.chars() {
+        let span = cursor.advance(c);
+        let token = match BrainFuckToken::from_char(c) {
+            Some(token) => token,
+            None => continue,
+        };
+
+        match token {
+            BrainFuckToken::JumpF(_) => {
+                depth += 1;
+                if depth > options.max_depth {
+                    return Err(ParseError::TooDeep {
+                        depth: depth,
+                        max: options.max_depth,
+                        span: span.unwrap(),
+                    });
+                }
+            }
+            BrainFuckToken::JumpB(_) if depth > 0 => depth -= 1,
+            _ => {}
+        }
+
+        if options.max_tokens.map_or(false, |max| tokens.len() >= max) {
+            return Err(ParseError::TooManyTokens {
+                max: options.max_tokens.unwrap(),
+            });
+        }
+        tokens.push_back(token);
+    }
+
+    Ok(tokens)
+}
+
+/// Lexes the source, refusing programs whose loops nest deeper than
+/// `max_depth` so later passes never have to deal with absurd nesting.
+pub fn parse(source: &str, max_depth: usize) -> VecDeque<BrainFuckToken> {
+    let options = ParseOptions {
+        max_depth: max_depth,
+        ..ParseOptions::default()
+    };
+    parse_with(source, &options).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// A token along with the non-command text (comments, whitespace) that
+/// preceded it in the source.
+#[derive(Debug, Clone)]
+pub struct Lexeme {
+    pub trivia: String,
+    pub token: BrainFuckToken,
+}
+
+/// Lossless view of a source file for tooling; writing it back out
+/// reproduces the original text exactly. Execution never looks at trivia.
+#[derive(Debug)]
+pub struct Source {
+    pub lexemes: Vec<Lexeme>,
+    pub trailing: String,
+}
+
+impl Source {
+    pub fn emit(&self, keep_comments: bool) -> String {
+        let mut s = String::new();
+
+        for lexeme in &self.lexemes {
+            if keep_comments {
+                s.push_str(&lexeme.trivia);
+            }
+            lexeme.token.write_source(&mut s);
+        }
+
+        if keep_comments {
+            s.push_str(&self.trailing);
+        }
+
+        s
+    }
+}
+
+pub fn parse_lossless<T>(source: T) -> Source
+where
+    T: Iterator<Item = char>,
+{
+    let mut lexemes = Vec::new();
+    let mut trivia = String::new();
+
+    for c in source {
+        match BrainFuckToken::from_char(c) {
+            Some(token) => lexemes.push(Lexeme {
+                trivia: std::mem::take(&mut trivia),
+                token: token,
+            }),
+            None => trivia.push(c),
+        }
+    }
+
+    Source {
+        lexemes: lexemes,
+        trailing: trivia,
+    }
+}
+
+/// A position in a source file: the line and column counted from 1 the
+/// way editors do, and the byte offset from the start of the file.
+/// Comments count, so a span points into the file as written.
+///
+/// ```
+/// use rustfuck::{check_balanced, ParseError};
+///
+/// match check_balanced("comment: +\n]") {
+///     Err(ParseError::UnmatchedClose { span }) => {
+///         assert_eq!((span.line(), span.column(), span.offset()), (2, 1, 11));
+///     }
+///     _ => panic!("the stray `]` wasn't found"),
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    line: usize,
+    column: usize,
+    offset: usize,
+}
+
+impl Span {
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// In chars, not bytes.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// Tracks the position of each char while walking a source file. `\n`,
+/// `\r\n` and a lone `\r` each end a line, so files saved on any platform
+/// get the same spans.
+#[derive(Debug, Clone, Copy)]
+struct Cursor {
+    line: usize,
+    column: usize,
+    offset: usize,
+    after_cr: bool,
+}
+
+impl Default for Cursor {
+    fn default() -> Cursor {
+        Cursor {
+            line: 1,
+            column: 0,
+            offset: 0,
+            after_cr: false,
+        }
+    }
+}
+
+impl Cursor {
+    /// Moves past `c`, returning where it is, or `None` for a line break.
+    fn advance(&mut self, c: char) -> Option<Span> {
+        let after_cr = replace(&mut self.after_cr, c == '\r');
+        let offset = self.offset;
+        self.offset += c.len_utf8();
+        match c {
+            '\n' if after_cr => None,
+            '\n' | '\r' => {
+                self.line += 1;
+                self.column = 0;
+                None
+            }
+            _ => {
+                self.column += 1;
+                Some(Span {
+                    line: self.line,
+                    column: self.column,
+                    offset: offset,
+                })
+            }
+        }
+    }
+
+    /// Where the next char will be.
+    fn next_span(&self) -> Span {
+        Span {
+            line: self.line,
+            column: self.column + 1,
+            offset: self.offset,
+        }
+    }
+}
+
+/// Pairs each command in `source` with where it appears.
+pub fn spanned_tokens(source: &str) -> Vec<(Span, BrainFuckToken)> {
+    let mut cursor = Cursor::default();
+    let mut tokens = Vec::new();
+
+    for c in source.chars() {
+        let span = cursor.advance(c);
+        if let Some(token) = BrainFuckToken::from_char(c) {
+            tokens.push((span.unwrap(), token));
+        }
+    }
+
+    tokens
+}
+
+/// Breaks every line of `text` longer than `width` chars, so a program
+/// minified onto one huge line can be read again. New breaks use `\r\n` if
+/// the text already does.
+pub fn wrap(text: &str, width: usize) -> String {
+    let newline = if text.contains("\r\n") { "\r\n" } else { "\n" };
+    let mut wrapped = String::with_capacity(text.len() + text.len() / width.max(1) * 2);
+    let mut column = 0;
+
+    for c in text.chars() {
+        if c == '\n' || c == '\r' {
+            column = 0;
+        } else {
+            if column == width {
+                wrapped.push_str(newline);
+                column = 0;
+            }
+            column += 1;
+        }
+        wrapped.push(c);
+    }
+
+    wrapped
+}
+
+/// Suspicious but legal (or, for unbalanced loops, fatal) patterns `check`
+/// looks for in a program's source.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Lint {
+    UnbalancedLoops,
+    DeadCode,
+    CancelledOut,
+    PointerDrift,
+}
+
+const LINTS: &'static [Lint] = &[
+    Lint::UnbalancedLoops,
+    Lint::DeadCode,
+    Lint::CancelledOut,
+    Lint::PointerDrift,
+];
+
+impl Lint {
+    fn name(&self) -> &'static str {
+        match *self {
+            Lint::UnbalancedLoops => "unbalanced-loops",
+            Lint::DeadCode => "dead-code",
+            Lint::CancelledOut => "cancelled-out",
+            Lint::PointerDrift => "pointer-drift",
+        }
+    }
+
+    fn default_level(&self) -> Level {
+        match *self {
+            Lint::UnbalancedLoops => Level::Deny,
+            _ => Level::Warn,
+        }
+    }
+}
+
+impl FromStr for Lint {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Lint, String> {
+        LINTS.iter().cloned().find(|lint| lint.name() == s).ok_or_else(|| {
+            let names: Vec<_> = LINTS.iter().map(Lint::name).collect();
+            format!("unknown lint `{}`, expected `warnings` or one of: {}", s, names.join(", "))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match *self {
+            Level::Allow => write!(f, "allowed"),
+            Level::Warn => write!(f, "warning"),
+            Level::Deny => write!(f, "error"),
+        }
+    }
+}
+
+/// Lint levels requested on the command line, applied in order so later
+/// flags win. `None` stands for `warnings`, i.e. every lint that would
+/// otherwise only warn.
+#[derive(Debug, Clone, Default)]
+pub struct LintLevels {
+    overrides: Vec<(Option<Lint>, Level)>,
+}
+
+impl LintLevels {
+    pub fn set(&mut self, name: &str, level: Level) -> std::result::Result<(), String> {
+        let lint = if name == "warnings" { None } else { Some(name.parse()?) };
+        self.overrides.push((lint, level));
+        Ok(())
+    }
+
+    fn level(&self, lint: Lint) -> Level {
+        let find = |target: Option<Lint>| {
+            self.overrides.iter().rev().find(|&&(l, _)| l == target).map(|&(_, level)| level)
+        };
+
+        match find(Some(lint)).unwrap_or(lint.default_level()) {
+            Level::Warn => find(None).unwrap_or(Level::Warn),
+            level => level,
+        }
+    }
+}
+
+/// What to do with bytes in a program file that aren't valid UTF-8. None of
+/// them can be a command, so they only ever end up in comments.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InvalidUtf8 {
+    /// Replace them with U+FFFD.
+    Lossy,
+    /// Drop them.
+    Ignore,
+    /// Refuse the file, pointing at the first one.
+    Strict,
+}
+
+impl FromStr for InvalidUtf8 {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<InvalidUtf8, String> {
+        match s {
+            "lossy" => Ok(InvalidUtf8::Lossy),
+            "ignore" => Ok(InvalidUtf8::Ignore),
+            "strict" => Ok(InvalidUtf8::Strict),
+            _ => Err(format!("unknown invalid UTF-8 policy `{}`", s)),
+        }
+    }
+}
+
+/// What a program file holds, and so which front end reads it.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SourceFormat {
+    Brainfuck,
+    /// Bytecode from `to_bytecode`.
+    Bytecode,
+    /// Ook!, where each pair of `Ook.`, `Ook?` and `Ook!` words is one
+    /// Brainfuck command.
+    Ook,
+    /// An IR listing from `testing::ir_text`, as `rustfuck ir` prints it.
+    IrText,
+}
+
+#[cfg(feature = "std")]
+impl FromStr for SourceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<SourceFormat, String> {
+        match s {
+            "bf" => Ok(SourceFormat::Brainfuck),
+            "bytecode" => Ok(SourceFormat::Bytecode),
+            "ook" => Ok(SourceFormat::Ook),
+            "ir" => Ok(SourceFormat::IrText),
+            _ => Err(format!("unknown source format `{}`", s)),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl SourceFormat {
+    /// Guesses the format of the file at `path` holding `bytes`: by magic
+    /// bytes or an IR listing's `# passes:` header first, then by
+    /// extension, then by whether the text reads as Ook!. Anything else is
+    /// Brainfuck.
+    ///
+    /// ```
+    /// use rustfuck::SourceFormat;
+    ///
+    /// assert_eq!(SourceFormat::detect("hello.b", b"+[.+]"), SourceFormat::Brainfuck);
+    /// assert_eq!(SourceFormat::detect("hello", b"Ook. Ook? Ook! Ook."), SourceFormat::Ook);
+    /// assert_eq!(SourceFormat::detect("hello", b"# passes: clear\n@\n"), SourceFormat::IrText);
+    /// ```
+    pub fn detect(path: &str, bytes: &[u8]) -> SourceFormat {
+        if bytes.starts_with(BYTECODE_MAGIC) {
+            return SourceFormat::Bytecode;
+        }
+        if bytes.starts_with(testing::PASSES_HEADER.as_bytes()) {
+            return SourceFormat::IrText;
+        }
+        let extension = std::path::Path::new(path).extension().and_then(|e| e.to_str());
+        match extension {
+            Some("bfc") | Some("bfbc") => return SourceFormat::Bytecode,
+            Some("ir") => return SourceFormat::IrText,
+            Some("ook") => return SourceFormat::Ook,
+            Some("b") | Some("bf") => return SourceFormat::Brainfuck,
+            _ => {}
+        }
+
+        // Each `Ook.` has a `.` that would otherwise count as a command, so
+        // Ook! is text whose commands are mostly those.
+        let text = String::from_utf8_lossy(bytes);
+        let words = ook_words(&text);
+        let dots = words.iter().filter(|&&(_, c)| c == '.').count();
+        let commands = text.chars().filter(|&c| BrainFuckToken::from_char(c).is_some()).count();
+        if words.len() >= 2 && commands - dots < words.len() {
+            SourceFormat::Ook
+        } else {
+            SourceFormat::Brainfuck
+        }
+    }
+}
+
+/// Every `Ook` word in `text`, with where it is and its punctuation.
+fn ook_words(text: &str) -> Vec<(Span, char)> {
+    let mut cursor = Cursor::default();
+    let mut words = Vec::new();
+
+    for (idx, c) in text.char_indices() {
+        let span = cursor.advance(c);
+        if !text[idx..].starts_with("Ook") {
+            continue;
+        }
+        match text[idx + 3..].chars().next() {
+            Some(mark) if mark == '.' || mark == '?' || mark == '!' => words.push((span.unwrap(), mark)),
+            _ => {}
+        }
+    }
+
+    words
+}
+
+/// Translates an Ook! program into Brainfuck. Anything but the words is a
+/// comment. Each command lands on the line its first word is on, so line
+/// numbers in diagnostics still match the Ook! source.
+///
+/// ```
+/// let source = "Ook. Ook. Ook! Ook?\nOok. Ook? Ook? Ook!";
+/// assert_eq!(rustfuck::ook_to_brainfuck(source).unwrap(), "+[\n>]");
+/// ```
+pub fn ook_to_brainfuck(text: &str) -> std::result::Result<String, ParseError> {
+    let words = ook_words(text);
+    let mut out = String::new();
+    let mut line = 1;
+
+    for pair in words.chunks(2) {
+        let (span, first) = pair[0];
+        let second = match pair.get(1) {
+            Some(&(_, second)) => second,
+            None => return Err(ParseError::UnpairedOok { span: span }),
+        };
+        let command = match (first, second) {
+            ('.', '?') => '>',
+            ('?', '.') => '<',
+            ('.', '.') => '+',
+            ('!', '!') => '-',
+            ('!', '.') => '.',
+            ('.', '!') => ',',
+            ('!', '?') => '[',
+            ('?', '!') => ']',
+            _ => return Err(ParseError::UnknownOok { span: span }),
+        };
+        while line < span.line {
+            out.push('\n');
+            line += 1;
+        }
+        out.push(command);
+    }
+
+    Ok(out)
+}
+
+/// Decodes a program file according to `policy`.
+pub fn decode_source(bytes: Vec<u8>, policy: InvalidUtf8) -> std::result::Result<String, Diagnostic> {
+    let error = match String::from_utf8(bytes) {
+        Ok(s) => return Ok(s),
+        Err(e) => e,
+    };
+
+    match policy {
+        InvalidUtf8::Lossy => Ok(String::from_utf8_lossy(error.as_bytes()).into_owned()),
+        InvalidUtf8::Ignore => {
+            let mut s = String::new();
+            let mut rest = error.as_bytes();
+            loop {
+                match std::str::from_utf8(rest) {
+                    Ok(valid) => {
+                        s.push_str(valid);
+                        return Ok(s);
+                    }
+                    Err(e) => {
+                        let (valid, invalid) = rest.split_at(e.valid_up_to());
+                        s.push_str(std::str::from_utf8(valid).unwrap());
+                        rest = &invalid[e.error_len().unwrap_or(invalid.len())..];
+                    }
+                }
+            }
+        }
+        InvalidUtf8::Strict => {
+            let bytes = error.as_bytes();
+            let offset = error.utf8_error().valid_up_to();
+            let mut cursor = Cursor::default();
+            for c in std::str::from_utf8(&bytes[..offset]).unwrap().chars() {
+                cursor.advance(c);
+            }
+            Err(Diagnostic {
+                code: "invalid-utf8",
+                level: Level::Deny,
+                span: Some(cursor.next_span()),
+                message: format!("invalid UTF-8 byte 0x{:02x} at offset {}", bytes[offset], offset),
+                notes: Vec::new(),
+            })
+        }
+    }
+}
+
+/// How diagnostics are written to stderr.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageFormat {
+    Text,
+    /// One JSON object per line, for editors and other tools.
+    Json,
+}
+
+impl FromStr for MessageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<MessageFormat, String> {
+        match s {
+            "text" => Ok(MessageFormat::Text),
+            "json" => Ok(MessageFormat::Json),
+            _ => Err(format!("unknown message format `{}`", s)),
+        }
+    }
+}
+
+/// A problem found in a program: a lint, a parse error, or an error while
+/// running it. Runtime errors only have a span if the caller mapped the op
+/// back to the source, e.g. with `optimize_mapped`.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub code: &'static str,
+    pub level: Level,
+    pub span: Option<Span>,
+    pub message: String,
+    /// Extra lines of context, printed after the location.
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    #[cfg(feature = "cli")]
+    pub fn print(&self, path: &str, format: MessageFormat) {
+        match format {
+            MessageFormat::Text => {
+                eprintln!("{}[{}]: {}", self.level, self.code, self.message);
+                match self.span {
+                    Some(span) => eprintln!("  --> {}:{}", path, span),
+                    None => eprintln!("  --> {}", path),
+                }
+                for note in &self.notes {
+                    eprintln!("   = note: {}", note);
+                }
+            }
+            MessageFormat::Json => {
+                let span = self.span.map_or("null".to_string(), |span| {
+                    format!("{{\"line\":{},\"column\":{},\"offset\":{}}}", span.line, span.column, span.offset)
+                });
+                let notes: Vec<String> = self.notes.iter().map(|note| json_string(note)).collect();
+                eprintln!(
+                    "{{\"code\":{},\"severity\":{},\"message\":{},\"file\":{},\"span\":{},\"notes\":[{}]}}",
+                    json_string(self.code),
+                    json_string(&self.level.to_string()),
+                    json_string(&self.message),
+                    json_string(path),
+                    span,
+                    notes.join(",")
+                );
+            }
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}[{}]: {}", self.level, self.code, self.message)?;
+        match self.span {
+            Some(span) => write!(f, " at {}", span),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Finds everything wrong with `source` before it is run: lints that
+/// aren't allowed, loops nested deeper than `max_depth`, and malformed
+/// pragmas.
+pub fn check_source(source: &str, levels: &LintLevels, max_depth: usize) -> Vec<Diagnostic> {
+    let tokens = spanned_tokens(source);
+    let mut found = Vec::new();
+
+    if let Err(diagnostic) = Limits::from_pragmas(source) {
+        found.push(diagnostic);
+    }
+
+    let mut report = |lint: Lint, span: Span, message: String| {
+        found.push(Diagnostic {
+            code: lint.name(),
+            level: levels.level(lint),
+            span: Some(span),
+            message: message,
+            notes: Vec::new(),
+        })
+    };
+
+    // Unbalanced loops, and pointer positions that can be known statically:
+    // tracking stops after a loop that doesn't return to where it started.
+    let mut open: Vec<(Span, Option<isize>)> = Vec::new();
+    let mut position = Some(0);
+    let mut drifted = false;
+    let mut too_deep = None;
+
+    for &(span, token) in &tokens {
+        match token {
+            BrainFuckToken::JumpF(_) => {
+                open.push((span, position));
+                if open.len() > max_depth && too_deep.is_none() {
+                    too_deep = Some(span);
+                }
+            }
+            BrainFuckToken::JumpB(_) => match open.pop() {
+                Some((_, start)) if start != position => position = None,
+                Some(_) => {}
+                None => report(Lint::UnbalancedLoops, span, "`]` has no matching `[`".to_string()),
+            },
+            BrainFuckToken::Move(n) => {
+                position = position.map(|p| p + n);
+                if position.map_or(false, |p| p < 0) && !drifted {
+                    drifted = true;
+                    report(Lint::PointerDrift, span, "pointer moves left of the first cell".to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for &(span, _) in &open {
+        report(Lint::UnbalancedLoops, span, "`[` is never closed".to_string());
+    }
+
+    // A loop can't run when the cell is known to be zero: at the very start
+    // of the program, or straight after another loop exits.
+    for (idx, &(span, token)) in tokens.iter().enumerate() {
+        if let BrainFuckToken::JumpF(_) = token {
+            match idx.checked_sub(1).map(|prev| tokens[prev].1) {
+                None => report(Lint::DeadCode, span, "loop at the start of the program never runs".to_string()),
+                Some(BrainFuckToken::JumpB(_)) => report(
+                    Lint::DeadCode,
+                    span,
+                    "loop never runs, the previous loop left the cell at zero".to_string(),
+                ),
+                _ => {}
+            }
+        }
+    }
+
+    // Adjacent commands that undo each other.
+    let mut idx = 1;
+    while idx < tokens.len() {
+        let (span, first) = tokens[idx - 1];
+        let cancels = match (first, tokens[idx].1) {
+            (BrainFuckToken::Incr(a), BrainFuckToken::Incr(b)) => a + b == 0,
+            (BrainFuckToken::Move(a), BrainFuckToken::Move(b)) => a + b == 0,
+            _ => false,
+        };
+
+        if cancels {
+            let mut pair = String::new();
+            first.write_source(&mut pair);
+            tokens[idx].1.write_source(&mut pair);
+            report(Lint::CancelledOut, span, format!("`{}` cancels out", pair));
+            idx += 1;
+        }
+        idx += 1;
+    }
+
+    if let Some(span) = too_deep {
+        found.push(Diagnostic {
+            code: "nesting-depth",
+            level: Level::Deny,
+            span: Some(span),
+            message: format!("loops are nested more than {} deep", max_depth),
+            notes: Vec::new(),
+        });
+    }
+
+    found.retain(|d| d.level != Level::Allow);
+    found.sort_by_key(|d| d.span.map(|span| (span.line, span.column)));
+    found
+}
+
+/// Prints `diagnostics` for the program at `path`, returning whether any of
+/// them is an error.
+#[cfg(feature = "cli")]
+pub fn report_diagnostics(path: &str, diagnostics: &[Diagnostic], format: MessageFormat) -> bool {
+    for diagnostic in diagnostics {
+        diagnostic.print(path, format);
+    }
+
+    let errors = diagnostics.iter().filter(|d| d.level == Level::Deny).count();
+    let warnings = diagnostics.len() - errors;
+    if format == MessageFormat::Json {
+        // Tools count the diagnostics themselves.
+    } else if errors > 0 {
+        eprintln!("{}: {}, {}", path, plural(errors, "error"), plural(warnings, "warning"));
+    } else if warnings > 0 {
+        eprintln!("{}: {}", path, plural(warnings, "warning"));
+    }
+
+    errors > 0
+}
+
+pub fn plural(count: usize, noun: &str) -> String {
+    format!("{} {}{}", count, noun, if count == 1 { "" } else { "s" })
+}
+
+/// Budget of rewrites the optimizer may perform. Once it runs out, passes
+/// leave the remaining tokens as they are, which makes it possible to
+/// bisect a miscompile down to the single rewrite that caused it.
+#[derive(Debug)]
+pub struct Fuel {
+    remaining: Option<u64>,
+    pub spent: u64,
+    pub last: Option<String>,
+}
+
+impl Fuel {
+    pub fn unlimited() -> Fuel {
+        Fuel {
+            remaining: None,
+            spent: 0,
+            last: None,
+        }
+    }
+
+    pub fn new(amount: u64) -> Fuel {
+        Fuel {
+            remaining: Some(amount),
+            ..Fuel::unlimited()
+        }
+    }
+
+    /// Asks permission for one rewrite. The description of the final rewrite
+    /// a limited budget allows is kept, so bisection can name the culprit.
+    pub fn consume<F>(&mut self, describe: F) -> bool
+    where
+        F: FnOnce() -> String,
+    {
+        match self.remaining {
+            Some(0) => return false,
+            Some(ref mut n) => {
+                *n -= 1;
+                if *n == 0 {
+                    self.last = Some(describe());
+                }
+            }
+            None => {}
+        }
+
+        self.spent += 1;
+        true
+    }
+}
+
+/// Building blocks for optimization passes, the crate's own and those
+/// added to a `PassManager` with `push_fn`.
+pub mod optimizer {
+    /// Helpers for writing optimization passes, so each one doesn't have to
+    /// hand-roll its own scan over the tokens.
+    pub mod prelude {
+        #[cfg(not(feature = "std"))]
+        use alloc_prelude::*;
+        use {BTreeMap, BrainFuckToken, Sources};
+
+        /// Slides a window of `width` tokens over `tokens`. Wherever
+        /// `rewrite` returns a replacement for the window, given where it
+        /// would land in the output, the window is replaced and the scan
+        /// carries on after it; other tokens are kept as they are.
+        pub fn rewrite_windows<F>(tokens: Vec<BrainFuckToken>, width: usize, mut rewrite: F) -> (Vec<BrainFuckToken>, Sources)
+        where
+            F: FnMut(&[BrainFuckToken], usize) -> Option<Vec<BrainFuckToken>>,
+        {
+            let mut program = Vec::with_capacity(tokens.len());
+            let mut sources = Vec::with_capacity(tokens.len());
+            let mut idx = 0;
+
+            while idx < tokens.len() {
+                if idx + width <= tokens.len() {
+                    if let Some(replacement) = rewrite(&tokens[idx..idx + width], program.len()) {
+                        sources.extend(replacement.iter().map(|_| idx..idx + width));
+                        program.extend(replacement);
+                        idx += width;
+                        continue;
+                    }
+                }
+                program.push(tokens[idx]);
+                sources.push(idx..idx + 1);
+                idx += 1;
+            }
+
+            (program, sources)
+        }
+
+        /// Whether `window` is a loop that only counts its cell down, like
+        /// `[-]`, and so just sets it to zero.
+        pub fn is_clear_loop(window: &[BrainFuckToken]) -> bool {
+            match *window {
+                [BrainFuckToken::JumpF(_), BrainFuckToken::Incr(x), BrainFuckToken::JumpB(_)] => x < 0,
+                _ => false,
+            }
+        }
+
+        /// Index of the `]` matching the `[` at `open`, before jumps are
+        /// resolved.
+        pub fn matching_bracket(tokens: &[BrainFuckToken], open: usize) -> usize {
+            let mut depth = 0;
+            for (idx, token) in tokens.iter().enumerate().skip(open) {
+                match *token {
+                    BrainFuckToken::JumpF(_) => depth += 1,
+                    BrainFuckToken::JumpB(_) if depth == 1 => return idx,
+                    BrainFuckToken::JumpB(_) => depth -= 1,
+                    _ => {}
+                }
+            }
+            panic!("Unmatched bracket at: {}", open)
+        }
+
+        /// The body of the loop whose `[` is at `open`, and the index of its
+        /// `]`.
+        pub fn loop_body(tokens: &[BrainFuckToken], open: usize) -> (&[BrainFuckToken], usize) {
+            let close = matching_bracket(tokens, open);
+            (&tokens[open + 1..close], close)
+        }
+
+        /// What a block of code, or one iteration of a loop body, does to
+        /// the tape, relative to where the pointer starts. Loops nested in
+        /// the block can't be summed up, so cells they touch are only
+        /// flagged as clobbered.
+        #[derive(Debug, Clone, PartialEq)]
+        pub struct Effect {
+            /// Where the pointer ends up, or `None` if a nested loop moves
+            /// it by an amount that depends on how often it runs.
+            pub shift: Option<isize>,
+            /// Cells set to zero, before `deltas` are added.
+            pub cleared: Vec<isize>,
+            /// Net change to each cell touched, in order of first touch.
+            pub deltas: Vec<(isize, i32)>,
+            /// Whether the block reads input or writes output.
+            pub io: bool,
+            /// Whether the block sets cells to values it can't know: by
+            /// reading input or running a nested loop.
+            pub clobbers_unknown: bool,
+        }
+
+        impl Effect {
+            pub fn of(body: &[BrainFuckToken]) -> Effect {
+                let mut effect = Effect {
+                    shift: Some(0),
+                    cleared: Vec::new(),
+                    deltas: Vec::new(),
+                    io: false,
+                    clobbers_unknown: false,
+                };
+                let mut idx = 0;
+
+                while idx < body.len() {
+                    let at = effect.shift;
+                    match (body[idx], at) {
+                        (BrainFuckToken::JumpF(_), _) => {
+                            let (inner, close) = loop_body(body, idx);
+                            let inner = Effect::of(inner);
+                            if inner.shift != Some(0) {
+                                effect.shift = None;
+                            }
+                            effect.io |= inner.io;
+                            effect.clobbers_unknown = true;
+                            idx = close;
+                        }
+                        (BrainFuckToken::JumpB(_), _) => {}
+                        (BrainFuckToken::StdIn, _) => {
+                            effect.io = true;
+                            effect.clobbers_unknown = true;
+                        }
+                        (BrainFuckToken::StdOut, _) | (BrainFuckToken::Out(_), _) | (BrainFuckToken::OutConst(_, _), _) => {
+                            effect.io = true
+                        }
+                        (_, None) => effect.clobbers_unknown = true,
+                        (BrainFuckToken::Move(x), Some(at)) => effect.shift = Some(at + x),
+                        (BrainFuckToken::Incr(x), Some(at)) => effect.add(at, x),
+                        (BrainFuckToken::IncrOut(x), Some(at)) => {
+                            effect.add(at, x);
+                            effect.io = true;
+                        }
+                        (BrainFuckToken::MoveIncr(x, y), Some(at)) => {
+                            effect.shift = Some(at + x);
+                            effect.add(at + x, y);
+                        }
+                        (BrainFuckToken::ZeroOut, Some(at)) => {
+                            effect.deltas.retain(|d| d.0 != at);
+                            if !effect.cleared.contains(&at) {
+                                effect.cleared.push(at);
+                            }
+                        }
+                    }
+                    idx += 1;
+                }
+
+                effect
+            }
+
+            /// The net change to the cell at `offset`.
+            pub fn delta(&self, offset: isize) -> i32 {
+                self.deltas.iter().find(|d| d.0 == offset).map_or(0, |d| d.1)
+            }
+
+            /// Whether the block may change the cell at `offset`.
+            pub fn touches(&self, offset: isize) -> bool {
+                self.clobbers_unknown || self.cleared.contains(&offset) || self.delta(offset) != 0
+            }
+
+            fn add(&mut self, offset: isize, x: i32) {
+                match self.deltas.iter_mut().find(|d| d.0 == offset) {
+                    Some(delta) => delta.1 += x,
+                    None => self.deltas.push((offset, x)),
+                }
+            }
+        }
+
+        /// The effect of one iteration of every loop in `tokens`, keyed by
+        /// the position of its `[`, so passes can look them up instead of
+        /// rescanning bodies.
+        pub fn loop_effects(tokens: &[BrainFuckToken]) -> BTreeMap<usize, Effect> {
+            tokens
+                .iter()
+                .enumerate()
+                .filter(|&(_, token)| match *token {
+                    BrainFuckToken::JumpF(_) => true,
+                    _ => false,
+                })
+                .map(|(idx, _)| (idx, Effect::of(loop_body(tokens, idx).0)))
+                .collect()
+        }
+    }
+}
+
+/// Helpers for pinning what the optimizer makes of a program, so that
+/// changes to its output show up in review.
+pub mod testing {
+    #[cfg(not(feature = "std"))]
+    use alloc_prelude::*;
+    use std::fmt::Write;
+    use {optimize, parse, parse_passes, BrainFuckToken, Fuel, Pass, DEFAULT_MAX_DEPTH, DEFAULT_PASSES};
+    #[cfg(feature = "unchecked")]
+    use {Dispatch, Profile, Program};
+
+    /// The header line that records which passes produced an IR listing.
+    pub(crate) const PASSES_HEADER: &'static str = "# passes:";
+
+    /// Lists `ops` one per line, in the notation traces use, indented by
+    /// loop depth.
+    pub fn ir_text(ops: &[BrainFuckToken]) -> String {
+        let mut text = String::new();
+        let mut depth = 0;
+        for op in ops {
+            if let BrainFuckToken::JumpB(_) = *op {
+                depth -= 1;
+            }
+            writeln!(text, "{:width$}{}", "", op.to_string().trim(), width = depth * 2).ok();
+            if let BrainFuckToken::JumpF(_) = *op {
+                depth += 1;
+            }
+        }
+        text
+    }
+
+    /// Reads back what `ir_text` or `ir_listing` wrote, for running an
+    /// optimizer's output without the optimizer. `#` lines, blank lines and
+    /// indentation are ignored, and jumps are matched up again.
+    ///
+    /// ```
+    /// use rustfuck::{optimize, parse, Fuel, DEFAULT_PASSES};
+    /// use rustfuck::testing::{ir_text, parse_ir_text};
+    ///
+    /// let ops = optimize(parse("++[>+++<-]>.[-]", 100), DEFAULT_PASSES, &mut Fuel::unlimited());
+    /// let text = ir_text(&ops);
+    /// assert_eq!(ir_text(&parse_ir_text(&text).unwrap()), text);
+    /// assert_eq!(parse_ir_text("M1\nX2").unwrap_err(), "line 2: unknown op `X2`");
+    /// ```
+    pub fn parse_ir_text(text: &str) -> Result<Vec<BrainFuckToken>, String> {
+        let mut ops = Vec::new();
+        let mut open = Vec::new();
+
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let op = match parse_ir_op(line) {
+                Some(op) => op,
+                None => return Err(format!("line {}: unknown op `{}`", idx + 1, line)),
+            };
+            match op {
+                BrainFuckToken::JumpF(_) => open.push((ops.len(), idx + 1)),
+                BrainFuckToken::JumpB(_) => match open.pop() {
+                    Some((partner, _)) => {
+                        ops[partner] = BrainFuckToken::JumpF(ops.len());
+                        ops.push(BrainFuckToken::JumpB(partner));
+                        continue;
+                    }
+                    None => return Err(format!("line {}: `]` has no matching `[`", idx + 1)),
+                },
+                _ => {}
+            }
+            ops.push(op);
+        }
+
+        match open.pop() {
+            Some((_, line)) => Err(format!("line {}: `[` has no matching `]`", line)),
+            None => Ok(ops),
+        }
+    }
+
+    /// One op in the notation `BrainFuckToken`'s `Display` writes.
+    fn parse_ir_op(text: &str) -> Option<BrainFuckToken> {
+        let op = match text {
+            "[" => BrainFuckToken::JumpF(0),
+            "]" => BrainFuckToken::JumpB(0),
+            "I" => BrainFuckToken::StdIn,
+            "O" => BrainFuckToken::StdOut,
+            "@" => BrainFuckToken::ZeroOut,
+            _ if text.starts_with('M') => match text[1..].find('I') {
+                Some(at) => BrainFuckToken::MoveIncr(text[1..at + 1].parse().ok()?, text[at + 2..].parse().ok()?),
+                None => BrainFuckToken::Move(text[1..].parse().ok()?),
+            },
+            _ if text.starts_with('I') && text.ends_with('O') && text.len() > 2 => {
+                BrainFuckToken::IncrOut(text[1..text.len() - 1].parse().ok()?)
+            }
+            _ if text.starts_with('I') => BrainFuckToken::Incr(text[1..].parse().ok()?),
+            _ if text.starts_with('O') => match text[1..].find('x') {
+                Some(at) => BrainFuckToken::OutConst(text[1..at + 1].parse().ok()?, text[at + 2..].parse().ok()?),
+                None => BrainFuckToken::Out(text[1..].parse().ok()?),
+            },
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    /// The IR listing `ir` prints for `source` optimized with `passes`,
+    /// starting with the header `golden_ir` reads them back from.
+    pub fn ir_listing(source: &str, passes: &[Pass]) -> String {
+        let names: Vec<&str> = passes.iter().map(|pass| pass.name()).collect();
+        let ops = optimize(parse(source, DEFAULT_MAX_DEPTH), passes, &mut Fuel::unlimited());
+        format!("{} {}\n{}", PASSES_HEADER, names.join(","), ir_text(&ops))
+    }
+
+    /// Checks that `source` optimizes to `expected`, an IR listing as `ir`
+    /// prints it. The passes come from its `# passes:` line, or are the
+    /// defaults without one; other `#` lines, blank lines and indentation
+    /// are ignored. Fails with the first line that differs.
+    pub fn golden_ir(source: &str, expected: &str) -> Result<(), String> {
+        let header = expected.lines().find(|line| line.starts_with(PASSES_HEADER));
+        let passes = match header {
+            Some(line) => parse_passes(&line[PASSES_HEADER.len()..])?,
+            None => DEFAULT_PASSES.to_vec(),
+        };
+
+        let significant = |text: &str| -> Vec<String> {
+            text.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_string)
+                .collect()
+        };
+        let actual = significant(&ir_listing(source, &passes));
+        let expected = significant(expected);
+
+        for (idx, (a, e)) in actual.iter().zip(&expected).enumerate() {
+            if a != e {
+                return Err(format!("op {} is `{}`, expected `{}`", idx, a, e));
+            }
+        }
+        if actual.len() != expected.len() {
+            return Err(format!("{} ops, expected {}", actual.len(), expected.len()));
+        }
+        Ok(())
+    }
+
+    /// Runs `ops` with `Dispatch::Match` and with `Dispatch::Unchecked` and
+    /// checks they halt the same way after the same steps, with the same
+    /// output and tape. Fails with the first thing that differs.
+    ///
+    /// ```
+    /// use rustfuck::{optimize, parse, Fuel, Profile, DEFAULT_PASSES};
+    /// use rustfuck::testing::difftest_unchecked;
+    ///
+    /// let ops = optimize(parse(",[>++<-]>.", 100), DEFAULT_PASSES, &mut Fuel::unlimited());
+    /// assert_eq!(difftest_unchecked(&ops, Profile::default(), b"\x05", 1000), Ok(()));
+    /// ```
+    #[cfg(feature = "unchecked")]
+    pub fn difftest_unchecked(ops: &[BrainFuckToken], profile: Profile, input: &[u8], step_limit: u64) -> Result<(), String> {
+        let run = |dispatch| {
+            let mut prog = Program::with_profile(ops.to_vec(), profile);
+            prog.dispatch = dispatch;
+            prog.step_limit = step_limit;
+            let mut output = Vec::new();
+            let result = prog.run(input, &mut output);
+            (result, output, prog.tape)
+        };
+        let (safe, safe_output, safe_tape) = run(Dispatch::Match);
+        let (fast, fast_output, fast_tape) = run(Dispatch::Unchecked);
+
+        if safe.halt != fast.halt {
+            return Err(format!("halted with {}, expected {}", fast.halt, safe.halt));
+        }
+        if safe.steps != fast.steps {
+            return Err(format!("took {} steps, expected {}", fast.steps, safe.steps));
+        }
+        if safe_output != fast_output {
+            return Err(format!("wrote {:?}, expected {:?}", fast_output, safe_output));
+        }
+        if safe_tape.loc != fast_tape.loc || safe_tape.tape != fast_tape.tape {
+            return Err("left a different tape".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Walking and rewriting programs from outside the crate, so an analysis
+/// or an extra pass can work on `BrainFuckToken`s without re-parsing.
+pub mod visit {
+    #[cfg(not(feature = "std"))]
+    use alloc_prelude::*;
+    use {build_jumps, BrainFuckToken};
+
+    /// Called for each op of a program by `walk`, in order, with loops
+    /// reported as they are entered and left. Every method does nothing
+    /// by default, so a visitor only implements what it looks at.
+    ///
+    /// ```
+    /// use rustfuck::visit::{walk, Visitor};
+    /// use rustfuck::{optimize, parse, Fuel, DEFAULT_PASSES};
+    ///
+    /// struct Deepest(usize);
+    ///
+    /// impl Visitor for Deepest {
+    ///     fn enter_loop(&mut self, _open: usize, depth: usize) {
+    ///         self.0 = self.0.max(depth);
+    ///     }
+    /// }
+    ///
+    /// let ops = optimize(parse("+[>+[-]<-]", 16), DEFAULT_PASSES, &mut Fuel::unlimited());
+    /// let mut deepest = Deepest(0);
+    /// walk(&ops, &mut deepest);
+    /// assert_eq!(deepest.0, 1);
+    /// ```
+    pub trait Visitor {
+        /// The `[` at `open`, taking the walk `depth` loops deep; the
+        /// outermost loops are at depth 1.
+        fn enter_loop(&mut self, _open: usize, _depth: usize) {}
+
+        /// The `]` at `close`, closing a loop at `depth`.
+        fn leave_loop(&mut self, _close: usize, _depth: usize) {}
+
+        /// Any op other than a jump, inside `depth` loops.
+        fn visit_op(&mut self, _at: usize, _op: BrainFuckToken, _depth: usize) {}
+    }
+
+    /// Runs `visitor` over `ops`. Jump targets are never read, so this
+    /// works on programs before and after `build_jumps` alike.
+    pub fn walk<V: Visitor + ?Sized>(ops: &[BrainFuckToken], visitor: &mut V) {
+        let mut depth = 0;
+        for (at, &op) in ops.iter().enumerate() {
+            match op {
+                BrainFuckToken::JumpF(_) => {
+                    depth += 1;
+                    visitor.enter_loop(at, depth);
+                }
+                BrainFuckToken::JumpB(_) => {
+                    visitor.leave_loop(at, depth);
+                    depth -= 1;
+                }
+                _ => visitor.visit_op(at, op, depth),
+            }
+        }
+    }
+
+    /// Rewrites a program for `fold`, bottom up: each op is replaced by
+    /// what `fold_op` returns, and each loop, with its body already
+    /// folded, by what `fold_loop` returns. Either can keep, drop or
+    /// expand what it is given, as long as loops stay balanced.
+    ///
+    /// ```
+    /// use rustfuck::visit::{fold, Folder};
+    /// use rustfuck::{parse, BrainFuckToken};
+    ///
+    /// /// Drops loops that do nothing but spin on their cell.
+    /// struct EmptyLoops;
+    ///
+    /// impl Folder for EmptyLoops {
+    ///     fn fold_loop(&mut self, _open: usize, body: Vec<BrainFuckToken>) -> Vec<BrainFuckToken> {
+    ///         if body.is_empty() {
+    ///             return Vec::new();
+    ///         }
+    ///         let mut ops = vec![BrainFuckToken::JumpF(0)];
+    ///         ops.extend(body);
+    ///         ops.push(BrainFuckToken::JumpB(0));
+    ///         ops
+    ///     }
+    /// }
+    ///
+    /// let tokens: Vec<BrainFuckToken> = parse("+[]-[[]>]", 16).into();
+    /// assert_eq!(fold(&tokens, &mut EmptyLoops).len(), 5);
+    /// ```
+    pub trait Folder {
+        fn fold_op(&mut self, _at: usize, op: BrainFuckToken) -> Vec<BrainFuckToken> {
+            vec![op]
+        }
+
+        /// The loop whose `[` was at `open`. Jump targets in what this
+        /// returns don't matter; `fold` works them out afterwards.
+        fn fold_loop(&mut self, _open: usize, body: Vec<BrainFuckToken>) -> Vec<BrainFuckToken> {
+            let mut ops = Vec::with_capacity(body.len() + 2);
+            ops.push(BrainFuckToken::JumpF(0));
+            ops.extend(body);
+            ops.push(BrainFuckToken::JumpB(0));
+            ops
+        }
+    }
+
+    /// Runs `folder` over `ops` and returns the rewritten program, with
+    /// its jumps resolved and ready to run. Positions passed to `folder`
+    /// are those in `ops`. Panics if `ops` or the result has unbalanced
+    /// loops.
+    pub fn fold<F: Folder + ?Sized>(ops: &[BrainFuckToken], folder: &mut F) -> Vec<BrainFuckToken> {
+        // The loops being folded, each with its `[` and the body so far;
+        // the program itself is the bottom entry.
+        let mut open: Vec<(usize, Vec<BrainFuckToken>)> = vec![(0, Vec::new())];
+
+        for (at, &op) in ops.iter().enumerate() {
+            match op {
+                BrainFuckToken::JumpF(_) => open.push((at, Vec::new())),
+                BrainFuckToken::JumpB(_) if open.len() > 1 => {
+                    let (start, body) = open.pop().unwrap();
+                    let folded = folder.fold_loop(start, body);
+                    open.last_mut().unwrap().1.extend(folded);
+                }
+                BrainFuckToken::JumpB(_) => panic!("unmatched bracket at {}", at),
+                _ => {
+                    let folded = folder.fold_op(at, op);
+                    open.last_mut().unwrap().1.extend(folded);
+                }
+            }
+        }
+
+        if open.len() > 1 {
+            panic!("Unmatched brackets at: {:?}", open[1..].iter().map(|&(at, _)| at).collect::<Vec<_>>());
+        }
+        let mut program = open.pop().unwrap().1;
+        build_jumps(&mut program);
+        program
+    }
+}
+
+/// The program as a tree, with each loop a node holding its body, for
+/// rewrites that are awkward on flat ops with jump targets: moving,
+/// dropping or rebuilding whole loops. `lower` turns it back into ops
+/// ready to run.
+///
+/// ```
+/// use rustfuck::tree::{self, Node};
+/// use rustfuck::{parse, BrainFuckToken};
+///
+/// let ops: Vec<BrainFuckToken> = parse("+[>[-]<-]", 16).into();
+/// let nodes = tree::build(&ops);
+/// assert_eq!(nodes.len(), 2);
+/// match nodes[1] {
+///     Node::Loop(ref body) => assert_eq!(body.len(), 4),
+///     Node::Op(_) => panic!("expected the outer loop"),
+/// }
+/// assert_eq!(tree::lower(&nodes).len(), ops.len());
+/// ```
+pub mod tree {
+    #[cfg(not(feature = "std"))]
+    use alloc_prelude::*;
+    use {build_jumps, BrainFuckToken};
+
+    #[derive(Debug, Clone)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub enum Node {
+        /// Any op other than a jump.
+        Op(BrainFuckToken),
+        Loop(Vec<Node>),
+    }
+
+    /// Nests `ops` into loops. Jump targets are never read, so this works
+    /// before and after `build_jumps` alike. Panics if the loops are
+    /// unbalanced.
+    pub fn build(ops: &[BrainFuckToken]) -> Vec<Node> {
+        // Bodies of the loops being built; the program itself is the
+        // bottom entry.
+        let mut open: Vec<Vec<Node>> = vec![Vec::new()];
+
+        for (at, &op) in ops.iter().enumerate() {
+            match op {
+                BrainFuckToken::JumpF(_) => open.push(Vec::new()),
+                BrainFuckToken::JumpB(_) if open.len() > 1 => {
+                    let body = open.pop().unwrap();
+                    open.last_mut().unwrap().push(Node::Loop(body));
+                }
+                BrainFuckToken::JumpB(_) => panic!("unmatched bracket at {}", at),
+                _ => open.last_mut().unwrap().push(Node::Op(op)),
+            }
+        }
+
+        if open.len() > 1 {
+            panic!("{} unclosed loops", open.len() - 1);
+        }
+        open.pop().unwrap()
+    }
+
+    /// Flattens `nodes` back into ops, with jumps resolved.
+    pub fn lower(nodes: &[Node]) -> Vec<BrainFuckToken> {
+        let mut ops = Vec::new();
+        flatten(nodes, &mut ops);
+        build_jumps(&mut ops);
+        ops
+    }
+
+    fn flatten(nodes: &[Node], ops: &mut Vec<BrainFuckToken>) {
+        for node in nodes {
+            match *node {
+                Node::Op(op) => ops.push(op),
+                Node::Loop(ref body) => {
+                    ops.push(BrainFuckToken::JumpF(0));
+                    flatten(body, ops);
+                    ops.push(BrainFuckToken::JumpB(0));
+                }
+            }
+        }
+    }
+
+    /// Drops loops that can never run because their cell is known to be
+    /// zero: those at the very start of the program, and those right after
+    /// another loop or a `ZeroOut`. Returns how many were dropped.
+    ///
+    /// ```
+    /// use rustfuck::tree;
+    /// use rustfuck::{parse, BrainFuckToken};
+    ///
+    /// let ops: Vec<BrainFuckToken> = parse("[.]+[-][>]", 16).into();
+    /// let mut nodes = tree::build(&ops);
+    /// assert_eq!(tree::remove_dead_loops(&mut nodes), 2);
+    /// assert_eq!(tree::lower(&nodes).len(), 4);
+    /// ```
+    pub fn remove_dead_loops(nodes: &mut Vec<Node>) -> usize {
+        // Every cell starts at zero.
+        remove_dead(nodes, true)
+    }
+
+    fn remove_dead(nodes: &mut Vec<Node>, starts_zero: bool) -> usize {
+        let mut removed = 0;
+        let mut zero = starts_zero;
+
+        nodes.retain(|node| {
+            let dead = zero && match *node {
+                Node::Loop(_) => true,
+                Node::Op(_) => false,
+            };
+            if dead {
+                removed += 1;
+                return false;
+            }
+            zero = match *node {
+                Node::Loop(_) | Node::Op(BrainFuckToken::ZeroOut) => true,
+                Node::Op(_) => false,
+            };
+            true
+        });
+        for node in nodes.iter_mut() {
+            if let Node::Loop(ref mut body) = *node {
+                // A body only runs when its cell isn't zero.
+                removed += remove_dead(body, false);
+            }
+        }
+        removed
+    }
+}
+
+/// An optimization pass that can be named on the command line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pass {
+    Collapse,
+    ZeroOut,
+    /// Unrolls loops that run at most this many times.
+    Unroll(u32),
+    Fuse,
+    ConstOut,
+}
+
+const DEFAULT_UNROLL_THRESHOLD: u32 = 8;
+/// The most ops a single unrolled loop may expand to.
+const UNROLL_BUDGET: usize = 256;
+
+const PASSES: &'static [Pass] = &[
+    Pass::Collapse,
+    Pass::ZeroOut,
+    Pass::Unroll(DEFAULT_UNROLL_THRESHOLD),
+    Pass::Fuse,
+    Pass::ConstOut,
+];
+pub const DEFAULT_PASSES: &'static [Pass] = &[Pass::Collapse, Pass::ZeroOut];
+/// `-O2`. Fusing hides pairs from the other passes, so it goes last.
+pub const FUSED_PASSES: &'static [Pass] = &[Pass::Collapse, Pass::ZeroOut, Pass::ConstOut, Pass::Fuse];
+/// `-O3`: unrolled bodies can end up next to each other, so collapse again.
+pub const AGGRESSIVE_PASSES: &'static [Pass] = &[
+    Pass::Collapse,
+    Pass::ZeroOut,
+    Pass::Unroll(DEFAULT_UNROLL_THRESHOLD),
+    Pass::Collapse,
+    Pass::ConstOut,
+    Pass::Fuse,
+];
+
+/// The passes of optimization level `-O<level>`, for levels 0 to 3.
+pub fn opt_level_passes(level: u8) -> Option<&'static [Pass]> {
+    match level {
+        0 => Some(&[]),
+        1 => Some(DEFAULT_PASSES),
+        2 => Some(FUSED_PASSES),
+        3 => Some(AGGRESSIVE_PASSES),
+        _ => None,
+    }
+}
+
+/// A property of the token stream that a pass promises to leave behind.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Invariant {
+    /// Every `[` has a matching `]`.
+    BalancedLoops,
+    /// No two neighbouring tokens could have been collapsed into one, and no
+    /// `Incr`/`Move` is a no-op.
+    Collapsed,
+    /// No `[-]` style clearing loops remain.
+    NoClearLoops,
+    /// Every jump points at its partner.
+    JumpsResolved,
+}
+
+impl Invariant {
+    fn check(&self, ops: &[BrainFuckToken]) -> std::result::Result<(), String> {
+        match *self {
+            Invariant::BalancedLoops => {
+                let mut depth = 0usize;
+                for (idx, op) in ops.iter().enumerate() {
+                    match *op {
+                        BrainFuckToken::JumpF(_) => depth += 1,
+                        BrainFuckToken::JumpB(_) if depth == 0 => {
+                            return Err(format!("unmatched ] at {}", idx))
+                        }
+                        BrainFuckToken::JumpB(_) => depth -= 1,
+                        _ => {}
+                    }
+                }
+                if depth != 0 {
+                    return Err(format!("{} unclosed [", depth));
+                }
+            }
+            Invariant::Collapsed => {
+                for (idx, op) in ops.iter().enumerate() {
+                    match *op {
+                        BrainFuckToken::Incr(0) | BrainFuckToken::Move(0) => {
+                            return Err(format!("no-op{} at {}", op, idx))
+                        }
+                        _ => {}
+                    }
+                }
+                for (idx, pair) in ops.windows(2).enumerate() {
+                    match (pair[0], pair[1]) {
+                        (BrainFuckToken::Incr(_), BrainFuckToken::Incr(_))
+                        | (BrainFuckToken::Move(_), BrainFuckToken::Move(_))
+                        | (BrainFuckToken::StdOut, BrainFuckToken::StdOut)
+                        | (BrainFuckToken::Out(_), BrainFuckToken::StdOut) => {
+                            return Err(format!("{}{} left uncollapsed at {}", pair[0], pair[1], idx))
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Invariant::NoClearLoops => if let Some(idx) = ops.windows(3).position(is_clear_loop) {
+                return Err(format!("clear loop left at {}", idx));
+            },
+            Invariant::JumpsResolved => for (idx, op) in ops.iter().enumerate() {
+                let partner = match *op {
+                    BrainFuckToken::JumpF(x) => ops.get(x).map(|op| match *op {
+                        BrainFuckToken::JumpB(y) => y == idx,
+                        _ => false,
+                    }),
+                    BrainFuckToken::JumpB(x) => ops.get(x).map(|op| match *op {
+                        BrainFuckToken::JumpF(y) => y == idx,
+                        _ => false,
+                    }),
+                    _ => continue,
+                };
+                if partner != Some(true) {
+                    return Err(format!("jump{} at {} doesn't point at its partner", op, idx));
+                }
+            },
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks a stage's promises in debug builds, so a broken rewrite is caught
+/// where it happens instead of as wrong output much later. Invariants in
+/// `broken` were already broken by the input, so no stage is blamed for them.
+fn verify_contract(stage: &str, contract: &[Invariant], broken: &[Invariant], ops: &[BrainFuckToken]) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+
+    for invariant in contract.iter().filter(|invariant| !broken.contains(invariant)) {
+        if let Err(e) = invariant.check(ops) {
+            panic!("`{}` broke {:?}: {}", stage, invariant, e);
+        }
+    }
+}
+
+/// The invariants a pass keeps rather than makes true, of which those
+/// `tokens` already break, for `verify_contract` to let through.
+fn broken_invariants(tokens: &[BrainFuckToken]) -> Vec<Invariant> {
+    if !cfg!(debug_assertions) {
+        return Vec::new();
+    }
+    [Invariant::BalancedLoops].iter().cloned().filter(|invariant| invariant.check(tokens).is_err()).collect()
+}
+
+impl Pass {
+    /// What the pass guarantees about its output, given unlimited fuel.
+    fn contract(&self) -> &'static [Invariant] {
+        match *self {
+            Pass::Collapse => &[Invariant::BalancedLoops, Invariant::Collapsed],
+            Pass::ZeroOut => &[Invariant::BalancedLoops, Invariant::NoClearLoops],
+            Pass::Unroll(_) => &[Invariant::BalancedLoops],
+            Pass::Fuse => &[Invariant::BalancedLoops],
+            Pass::ConstOut => &[Invariant::BalancedLoops],
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Pass::Collapse => "collapse",
+            Pass::ZeroOut => "zero",
+            Pass::Unroll(_) => "unroll",
+            Pass::Fuse => "fuse",
+            Pass::ConstOut => "const-out",
+        }
+    }
+
+    pub fn apply(&self, tokens: Vec<BrainFuckToken>, fuel: &mut Fuel) -> Vec<BrainFuckToken> {
+        self.apply_with_sources(tokens, fuel).0
+    }
+
+    /// `apply`, also returning for each op of the result the range of
+    /// input tokens it was made from.
+    pub fn apply_with_sources(&self, tokens: Vec<BrainFuckToken>, fuel: &mut Fuel) -> (Vec<BrainFuckToken>, Sources) {
+        match *self {
+            Pass::Collapse => collapse_tokens(tokens, fuel),
+            Pass::ZeroOut => handle_zero_out(tokens, fuel),
+            Pass::Unroll(threshold) => unroll_loops(&tokens, 0, KnownCells::program_start(), threshold, fuel),
+            Pass::Fuse => fuse_pairs(tokens, fuel),
+            Pass::ConstOut => fold_constant_output(tokens, fuel),
+        }
+    }
+}
+
+impl FromStr for Pass {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Pass, String> {
+        PASSES.iter().cloned().find(|pass| pass.name() == s).ok_or_else(|| {
+            let names: Vec<&str> = PASSES.iter().map(|pass| pass.name()).collect();
+            format!("unknown pass `{}`, expected one of: {}", s, names.join(", "))
+        })
+    }
+}
+
+/// Parses a comma separated pipeline such as `collapse,zero,collapse`.
+/// Passes run in the order given and may repeat; an empty list runs none.
+pub fn parse_passes(s: &str) -> std::result::Result<Vec<Pass>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// An optimization pipeline that can be rearranged and have passes
+/// switched off without losing their place, for trying other orders or
+/// ruling passes out when chasing a miscompile.
+///
+/// ```
+/// use rustfuck::{parse, Fuel, Pass, PassManager, FUSED_PASSES};
+///
+/// let mut manager = PassManager::new(FUSED_PASSES);
+/// manager.disable("fuse");
+/// manager.insert(0, Pass::ConstOut);
+/// assert_eq!(manager.passes(), [Pass::ConstOut, Pass::Collapse, Pass::ZeroOut, Pass::ConstOut]);
+///
+/// let ops = manager.run(parse("++[->+<]", 16), &mut Fuel::unlimited());
+/// assert_eq!(ops.len(), 7);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PassManager {
+    /// Each pass in order, and whether it runs.
+    entries: Vec<(Stage, bool)>,
+}
+
+type PassFn = dyn Fn(Vec<BrainFuckToken>, &mut Fuel) -> Vec<BrainFuckToken>;
+
+/// A pass written outside the crate, added with `PassManager::push_fn`.
+#[derive(Clone)]
+struct CustomPass {
+    name: String,
+    run: Rc<PassFn>,
+}
+
+impl std::fmt::Debug for CustomPass {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "CustomPass({:?})", self.name)
+    }
+}
+
+/// One step of a `PassManager` pipeline.
+#[derive(Debug, Clone)]
+enum Stage {
+    Builtin(Pass),
+    Custom(CustomPass),
+}
+
+impl Stage {
+    fn name(&self) -> &str {
+        match *self {
+            Stage::Builtin(ref pass) => pass.name(),
+            Stage::Custom(ref pass) => &pass.name,
+        }
+    }
+}
+
+impl PassManager {
+    /// A pipeline of `passes`, all switched on.
+    pub fn new(passes: &[Pass]) -> PassManager {
+        PassManager {
+            entries: passes.iter().map(|&pass| (Stage::Builtin(pass), true)).collect(),
+        }
+    }
+
+    pub fn push(&mut self, pass: Pass) {
+        self.entries.push((Stage::Builtin(pass), true));
+    }
+
+    /// Adds a pass of your own, called `name` for `disable` and `enable`.
+    /// It gets the tokens with jumps unresolved and must keep loops
+    /// balanced; it should ask `fuel` before each rewrite so `--bisect-fuel`
+    /// style searches can see it. The `optimizer::prelude` helpers do the
+    /// scanning.
+    ///
+    /// ```
+    /// use rustfuck::optimizer::prelude::rewrite_windows;
+    /// use rustfuck::{parse, BrainFuckToken, Fuel, PassManager, DEFAULT_PASSES};
+    ///
+    /// // Adding to a cell that is cleared straight after is wasted work.
+    /// let mut manager = PassManager::new(DEFAULT_PASSES);
+    /// manager.push_fn("dead-incr", |tokens, fuel| {
+    ///     let (ops, _) = rewrite_windows(tokens, 2, |window, _| match *window {
+    ///         [BrainFuckToken::Incr(_), BrainFuckToken::ZeroOut] if fuel.consume(|| "dead incr".to_string()) => {
+    ///             Some(vec![BrainFuckToken::ZeroOut])
+    ///         }
+    ///         _ => None,
+    ///     });
+    ///     ops
+    /// });
+    ///
+    /// let ops = manager.run(parse("+++[-].", 16), &mut Fuel::unlimited());
+    /// assert_eq!(ops.len(), 2);
+    /// manager.disable("dead-incr");
+    /// assert_eq!(manager.run(parse("+++[-].", 16), &mut Fuel::unlimited()).len(), 3);
+    /// ```
+    pub fn push_fn<F>(&mut self, name: &str, pass: F)
+    where
+        F: Fn(Vec<BrainFuckToken>, &mut Fuel) -> Vec<BrainFuckToken> + 'static,
+    {
+        let custom = CustomPass {
+            name: name.to_string(),
+            run: Rc::new(pass),
+        };
+        self.entries.push((Stage::Custom(custom), true));
+    }
+
+    /// Puts `pass` at position `at`, counting disabled passes too.
+    pub fn insert(&mut self, at: usize, pass: Pass) {
+        self.entries.insert(at, (Stage::Builtin(pass), true));
+    }
+
+    /// Switches off every pass called `name`. Returns whether there was one.
+    pub fn disable(&mut self, name: &str) -> bool {
+        self.set_enabled(name, false)
+    }
+
+    /// Switches every pass called `name` back on. Returns whether there
+    /// was one.
+    pub fn enable(&mut self, name: &str) -> bool {
+        self.set_enabled(name, true)
+    }
+
+    fn set_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        let mut found = false;
+        for entry in self.entries.iter_mut().filter(|entry| entry.0.name() == name) {
+            entry.1 = enabled;
+            found = true;
+        }
+        found
+    }
+
+    /// The built-in passes that will run, in order. Passes added with
+    /// `push_fn` aren't listed, so only `run` runs the whole pipeline.
+    pub fn passes(&self) -> Vec<Pass> {
+        let enabled = self.entries.iter().filter(|entry| entry.1);
+        enabled
+            .filter_map(|entry| match entry.0 {
+                Stage::Builtin(pass) => Some(pass),
+                Stage::Custom(_) => None,
+            })
+            .collect()
+    }
+
+    /// Runs the enabled passes over `tokens` and resolves the jumps.
+    pub fn run(&self, tokens: VecDeque<BrainFuckToken>, fuel: &mut Fuel) -> Vec<BrainFuckToken> {
+        let mut program: Vec<BrainFuckToken> = tokens.into();
+        let broken = broken_invariants(&program);
+        for &(ref stage, enabled) in &self.entries {
+            if !enabled {
+                continue;
+            }
+            match *stage {
+                Stage::Builtin(pass) => {
+                    program = pass.apply(program, fuel);
+                    if fuel.remaining.is_none() {
+                        verify_contract(pass.name(), pass.contract(), &broken, &program);
+                    }
+                }
+                Stage::Custom(ref pass) => {
+                    program = (pass.run)(program, fuel);
+                    verify_contract(&pass.name, &[Invariant::BalancedLoops], &broken, &program);
+                }
+            }
+        }
+        build_jumps(&mut program);
+        verify_contract("build_jumps", &[Invariant::JumpsResolved], &[], &program);
+        program
+    }
+}
+
+#[cfg(feature = "std")]
+pub fn optimize(tokens: VecDeque<BrainFuckToken>, passes: &[Pass], fuel: &mut Fuel) -> Vec<BrainFuckToken> {
+    optimize_timed(tokens, passes, fuel, &mut Timings::default())
+}
+
+#[cfg(not(feature = "std"))]
+pub fn optimize(tokens: VecDeque<BrainFuckToken>, passes: &[Pass], fuel: &mut Fuel) -> Vec<BrainFuckToken> {
+    let mut program: Vec<BrainFuckToken> = tokens.into();
+    let broken = broken_invariants(&program);
+    for pass in passes {
+        program = pass.apply(program, fuel);
+        if fuel.remaining.is_none() {
+            verify_contract(pass.name(), pass.contract(), &broken, &program);
+        }
+    }
+    build_jumps(&mut program);
+    verify_contract("build_jumps", &[Invariant::JumpsResolved], &[], &program);
+    program
+}
+
+/// `optimize`, recording how long each pass and building the jumps took.
+#[cfg(feature = "std")]
+pub fn optimize_timed(
+    tokens: VecDeque<BrainFuckToken>,
+    passes: &[Pass],
+    fuel: &mut Fuel,
+    timings: &mut Timings,
+) -> Vec<BrainFuckToken> {
+    let unlimited = OptBudget {
+        max_growth: usize::max_value(),
+        max_passes: usize::max_value(),
+        max_time: Duration::from_secs(u64::max_value()),
+    };
+    optimize_budgeted(tokens, passes, fuel, unlimited, timings).0
+}
+
+/// Limits on the optimizer itself, so a hostile program can't make
+/// compiling it the expensive part. Past any of them the pipeline is given
+/// up and the program runs unoptimized, as at `-O0`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OptBudget {
+    /// Most ops the result may have for each parsed token.
+    pub max_growth: usize,
+    /// Most passes a pipeline may run.
+    pub max_passes: usize,
+    pub max_time: Duration,
+}
+
+#[cfg(feature = "std")]
+impl Default for OptBudget {
+    /// Well beyond what the built-in levels need on any program.
+    fn default() -> OptBudget {
+        OptBudget {
+            max_growth: 32,
+            max_passes: 64,
+            max_time: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Which limit of an `OptBudget` the optimizer ran into.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub enum BudgetExceeded {
+    Growth { tokens: usize, ops: usize },
+    Passes(usize),
+    /// Optimizing had taken this long after the pass that went over.
+    Time(Duration),
+}
+
+#[cfg(feature = "std")]
+impl Display for BudgetExceeded {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match *self {
+            BudgetExceeded::Growth { tokens, ops } => write!(f, "{} tokens grew to {} ops", tokens, ops),
+            BudgetExceeded::Passes(n) => write!(f, "the pipeline has {} passes", n),
+            BudgetExceeded::Time(elapsed) => write!(f, "optimizing took {:.3}s", elapsed.as_secs_f64()),
+        }
+    }
+}
+
+/// `optimize_timed` within `budget`. Once a limit is hit the optimized
+/// program is dropped and `tokens` come back with only their jumps built,
+/// along with the limit that was hit.
+///
+/// ```
+/// use rustfuck::{optimize_budgeted, parse, BudgetExceeded, Fuel, OptBudget, Pass, Timings};
+///
+/// let budget = OptBudget { max_passes: 1, ..OptBudget::default() };
+/// let passes = [Pass::Collapse, Pass::ZeroOut];
+/// let (ops, exceeded) = optimize_budgeted(parse("++[-]", 16), &passes, &mut Fuel::unlimited(), budget, &mut Timings::default());
+/// assert_eq!(exceeded, Some(BudgetExceeded::Passes(2)));
+/// assert_eq!(ops.len(), 5);
+/// ```
+#[cfg(feature = "std")]
+pub fn optimize_budgeted(
+    tokens: VecDeque<BrainFuckToken>,
+    passes: &[Pass],
+    fuel: &mut Fuel,
+    budget: OptBudget,
+    timings: &mut Timings,
+) -> (Vec<BrainFuckToken>, Option<BudgetExceeded>) {
+    let unoptimized = |tokens: Vec<BrainFuckToken>| {
+        let mut program = tokens;
+        build_jumps(&mut program);
+        program
+    };
+    if passes.len() > budget.max_passes {
+        return (unoptimized(tokens.into()), Some(BudgetExceeded::Passes(passes.len())));
+    }
+
+    let start = Instant::now();
+    let original: Vec<BrainFuckToken> = tokens.into();
+    let max_ops = original.len().max(1).saturating_mul(budget.max_growth);
+    let mut program = original.clone();
+    let broken = broken_invariants(&program);
+
+    for pass in passes {
+        program = timings.time(pass.name(), || pass.apply(program, fuel));
+        let exceeded = if program.len() > max_ops {
+            Some(BudgetExceeded::Growth {
+                tokens: original.len(),
+                ops: program.len(),
+            })
+        } else if start.elapsed() > budget.max_time {
+            Some(BudgetExceeded::Time(start.elapsed()))
+        } else {
+            None
+        };
+        if exceeded.is_some() {
+            return (unoptimized(original), exceeded);
+        }
+        if fuel.remaining.is_none() {
+            verify_contract(pass.name(), pass.contract(), &broken, &program);
+        }
+    }
+
+    timings.time("build_jumps", || build_jumps(&mut program));
+    verify_contract("build_jumps", &[Invariant::JumpsResolved], &[], &program);
+    (program, None)
+}
+
+/// `optimize`, also keeping track of which tokens of `tokens` each op of
+/// the result was made from.
+pub fn optimize_mapped(tokens: VecDeque<BrainFuckToken>, passes: &[Pass], fuel: &mut Fuel) -> (Vec<BrainFuckToken>, OpMap) {
+    let mut program: Vec<BrainFuckToken> = tokens.into();
+    let mut map = OpMap::identity(program.len());
+    let broken = broken_invariants(&program);
+
+    for pass in passes {
+        let (optimized, sources) = pass.apply_with_sources(program, fuel);
+        program = optimized;
+        map = map.then(&sources);
+        if fuel.remaining.is_none() {
+            verify_contract(pass.name(), pass.contract(), &broken, &program);
+        }
+    }
+
+    build_jumps(&mut program);
+    verify_contract("build_jumps", &[Invariant::JumpsResolved], &[], &program);
+    (program, map)
+}
+
+/// Where each op of an optimized program came from: the indices of the
+/// parsed tokens, i.e. of the commands in the source, it was made from.
+/// Folded ops have several, unrolled copies share theirs, and tokens that
+/// were optimized away appear nowhere.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpMap {
+    origins: Vec<Vec<usize>>,
+}
+
+impl OpMap {
+    fn identity(len: usize) -> OpMap {
+        OpMap {
+            origins: (0..len).map(|idx| vec![idx]).collect(),
+        }
+    }
+
+    /// This map followed by a pass that made each of its ops from `sources`.
+    fn then(&self, sources: &[std::ops::Range<usize>]) -> OpMap {
+        let origins = sources
+            .iter()
+            .map(|range| {
+                let mut origin: Vec<usize> = self.origins[range.clone()].iter().flatten().cloned().collect();
+                origin.sort();
+                origin.dedup();
+                origin
+            })
+            .collect();
+        OpMap { origins: origins }
+    }
+
+    /// The tokens op `op` was made from, in source order.
+    pub fn origins(&self, op: usize) -> &[usize] {
+        &self.origins[op]
+    }
+
+    pub fn len(&self) -> usize {
+        self.origins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.origins.is_empty()
+    }
+}
+
+/// Arbitrary values attached to ops by index, for tools built on the
+/// crate: coverage IDs, labels, cost weights. Attach them to the parsed
+/// tokens, optimize with `optimize_mapped`, and `remap` carries them onto
+/// the ops that run.
+///
+/// ```
+/// use rustfuck::{optimize_mapped, parse, Annotations, Fuel, DEFAULT_PASSES};
+///
+/// let tokens = parse("+++[-]", 16);
+/// let mut labels = Annotations::new();
+/// labels.add(0, "first +");
+/// labels.add(4, "the -");
+///
+/// let (ops, map) = optimize_mapped(tokens, DEFAULT_PASSES, &mut Fuel::unlimited());
+/// let labels = labels.remap(&map);
+/// assert_eq!(ops.len(), 2);
+/// assert_eq!(labels.get(0), &["first +"]);
+/// assert_eq!(labels.get(1), &["the -"]);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Annotations<T> {
+    by_op: BTreeMap<usize, Vec<T>>,
+}
+
+impl<T: Clone> Annotations<T> {
+    pub fn new() -> Annotations<T> {
+        Annotations { by_op: BTreeMap::new() }
+    }
+
+    pub fn add(&mut self, op: usize, value: T) {
+        self.by_op.entry(op).or_default().push(value);
+    }
+
+    /// What is attached to op `op`, in the order it was added.
+    pub fn get(&self, op: usize) -> &[T] {
+        self.by_op.get(&op).map_or(&[], |values| &values[..])
+    }
+
+    /// Every annotation with its op, in op order.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.by_op
+            .iter()
+            .flat_map(|(&op, values)| values.iter().map(move |value| (op, value)))
+    }
+
+    /// Moves each annotation onto the ops made from the token it was on.
+    /// An op made from several tokens gets all of their annotations, and
+    /// annotations on tokens that were optimized away are dropped.
+    pub fn remap(&self, map: &OpMap) -> Annotations<T> {
+        let mut remapped = Annotations::new();
+        for op in 0..map.len() {
+            for &origin in map.origins(op) {
+                for value in self.get(origin) {
+                    remapped.add(op, value.clone());
+                }
+            }
+        }
+        remapped
+    }
+}
+
+/// Wall time spent in each phase of getting a program from disk to done,
+/// for `--time-passes`. A phase that runs more than once, like a pass given
+/// twice in `--passes`, is listed each time.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct Timings {
+    phases: Vec<(&'static str, Duration)>,
+}
+
+#[cfg(feature = "std")]
+impl Timings {
+    pub fn time<T, F: FnOnce() -> T>(&mut self, name: &'static str, f: F) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.phases.push((name, start.elapsed()));
+        result
+    }
+
+    pub fn report(&self) -> String {
+        let total: Duration = self.phases.iter().map(|&(_, d)| d).sum();
+        let mut report = String::new();
+        for &(name, elapsed) in &self.phases {
+            let percent = 100.0 * elapsed.as_secs_f64() / total.as_secs_f64().max(1e-9);
+            writeln!(report, "{:<12}{:>10.4}s {:>5.1}%", name, elapsed.as_secs_f64(), percent).ok();
+        }
+        write!(report, "{:<12}{:>10.4}s", "total", total.as_secs_f64()).ok();
+        report
+    }
+}
+
+fn collapse_tokens(tokens: Vec<BrainFuckToken>, fuel: &mut Fuel) -> (Vec<BrainFuckToken>, Sources) {
+    let mut program = Vec::with_capacity(tokens.len());
+    let mut sources: Sources = Vec::with_capacity(tokens.len());
+
+    for (idx, token) in tokens.into_iter().enumerate() {
+        let (previous, from) = match (program.pop(), sources.pop()) {
+            (Some(previous), Some(from)) => (previous, from),
+            _ => {
+                program.push(token);
+                sources.push(idx..idx + 1);
+                continue;
+            }
+        };
+        let merged = from.start..idx + 1;
+
+        let describe = || format!("collapse{}{} at token {}", previous, token, program.len());
+
+        match (previous, token) {
+            (BrainFuckToken::Incr(x), BrainFuckToken::Incr(y)) if fuel.consume(describe) => {
+                let v = x + y;
+                if v != 0 {
+                    program.push(BrainFuckToken::Incr(v));
+                    sources.push(merged);
+                }
+            }
+            (BrainFuckToken::Move(x), BrainFuckToken::Move(y)) if fuel.consume(describe) => {
+                let v = x + y;
+                if v != 0 {
+                    program.push(BrainFuckToken::Move(v));
+                    sources.push(merged);
+                }
+            }
+            (BrainFuckToken::StdOut, BrainFuckToken::StdOut) if fuel.consume(describe) => {
+                program.push(BrainFuckToken::Out(2));
+                sources.push(merged);
+            }
+            (BrainFuckToken::Out(n), BrainFuckToken::StdOut) if fuel.consume(describe) => {
+                program.push(BrainFuckToken::Out(n + 1));
+                sources.push(merged);
+            }
+            _ => {
+                program.push(previous);
+                sources.push(from);
+                program.push(token);
+                sources.push(idx..idx + 1);
+            }
+        }
+    }
+
+    (program, sources)
+}
+
+fn handle_zero_out(tokens: Vec<BrainFuckToken>, fuel: &mut Fuel) -> (Vec<BrainFuckToken>, Sources) {
+    rewrite_windows(tokens, 3, |window, at| {
+        if is_clear_loop(window) && fuel.consume(|| format!("zero out [{} ] at token {}", window[1], at)) {
+            Some(vec![BrainFuckToken::ZeroOut])
+        } else {
+            None
+        }
+    })
+}
+
+/// Fuses `Move`+`Incr` and `Incr`+`StdOut` pairs into single tokens. No
+/// other pass understands the fused tokens, so this one should run last.
+fn fuse_pairs(tokens: Vec<BrainFuckToken>, fuel: &mut Fuel) -> (Vec<BrainFuckToken>, Sources) {
+    rewrite_windows(tokens, 2, |pair, at| {
+        let fused = match (pair[0], pair[1]) {
+            (BrainFuckToken::Move(x), BrainFuckToken::Incr(y)) => BrainFuckToken::MoveIncr(x, y),
+            (BrainFuckToken::Incr(x), BrainFuckToken::StdOut) => BrainFuckToken::IncrOut(x),
+            _ => return None,
+        };
+        if fuel.consume(|| format!("fuse{} at token {}", fused, at)) {
+            Some(vec![fused])
+        } else {
+            None
+        }
+    })
+}
+
+/// Turns output of a cell whose value constant analysis knows into output
+/// of that byte, so the run doesn't need to read the tape.
+fn fold_constant_output(tokens: Vec<BrainFuckToken>, fuel: &mut Fuel) -> (Vec<BrainFuckToken>, Sources) {
+    let effects = loop_effects(&tokens);
+    let mut known = KnownCells::program_start();
+    // What was known before each loop the walk is inside, and its `[`.
+    let mut outer: Vec<(KnownCells, usize)> = Vec::new();
+    let mut program = Vec::with_capacity(tokens.len());
+
+    for (idx, token) in tokens.into_iter().enumerate() {
+        let times = match token {
+            BrainFuckToken::StdOut => Some(1),
+            BrainFuckToken::Out(n) => Some(n),
+            _ => None,
+        };
+        let at = program.len();
+
+        match (times, known.current()) {
+            (Some(n), Some(value))
+                if fuel.consume(|| format!("output constant {} at token {}", value as u8, at)) =>
+            {
+                program.push(BrainFuckToken::OutConst(value as u8, n));
+            }
+            _ => program.push(token),
+        }
+
+        match token {
+            // The body starts out unknown since it's also reached from the
+            // end of the previous iteration.
+            BrainFuckToken::JumpF(_) => outer.push((replace(&mut known, KnownCells::unknown()), idx)),
+            BrainFuckToken::JumpB(_) => {
+                known = match outer.pop() {
+                    Some((before, open)) => before.after(&effects[&open]),
+                    None => KnownCells::after_loop(),
+                }
+            }
+            _ => known.apply(token),
+        }
+    }
+
+    let sources = (0..program.len()).map(|idx| idx..idx + 1).collect();
+    (program, sources)
+}
+
+/// What constant analysis knows about the tape while walking straight-line
+/// code. Positions are relative to where the walk started.
+#[derive(Debug, Clone)]
+struct KnownCells {
+    cells: BTreeMap<isize, Option<i32>>,
+    pos: isize,
+    /// Whether cells never touched so far are still zero.
+    untouched_zero: bool,
+}
+
+impl KnownCells {
+    fn program_start() -> KnownCells {
+        KnownCells {
+            cells: BTreeMap::new(),
+            pos: 0,
+            untouched_zero: true,
+        }
+    }
+
+    /// Just after a loop exits, only the current cell is known: it is zero.
+    fn after_loop() -> KnownCells {
+        let mut known = KnownCells {
+            cells: BTreeMap::new(),
+            pos: 0,
+            untouched_zero: false,
+        };
+        known.cells.insert(0, Some(0));
+        known
+    }
+
+    /// After a loop that was entered knowing `self`: a loop that ends
+    /// where it started only loses what it touches, as well as leaving the
+    /// current cell at zero.
+    fn after(mut self, effect: &Effect) -> KnownCells {
+        if effect.shift != Some(0) || effect.clobbers_unknown {
+            return KnownCells::after_loop();
+        }
+
+        let touched = effect.cleared.iter().chain(effect.deltas.iter().map(|d| &d.0));
+        for &offset in touched.filter(|&&offset| effect.touches(offset)) {
+            self.cells.insert(self.pos + offset, None);
+        }
+        self.cells.insert(self.pos, Some(0));
+        self
+    }
+
+    fn unknown() -> KnownCells {
+        KnownCells {
+            cells: BTreeMap::new(),
+            pos: 0,
+            untouched_zero: false,
+        }
+    }
+
+    fn current(&self) -> Option<i32> {
+        match self.cells.get(&self.pos) {
+            Some(&value) => value,
+            None if self.untouched_zero => Some(0),
+            None => None,
+        }
+    }
+
+    fn apply(&mut self, token: BrainFuckToken) {
+        let value = match token {
+            BrainFuckToken::Move(x) => {
+                self.pos += x;
+                return;
+            }
+            BrainFuckToken::Incr(x) | BrainFuckToken::IncrOut(x) => self.current().and_then(|v| v.checked_add(x)),
+            BrainFuckToken::MoveIncr(x, y) => {
+                self.pos += x;
+                self.current().and_then(|v| v.checked_add(y))
+            }
+            BrainFuckToken::ZeroOut => Some(0),
+            BrainFuckToken::StdIn => None,
+            _ => return,
+        };
+        self.cells.insert(self.pos, value);
+    }
+}
+
+/// How many times a loop with this body runs when entered with the current
+/// cell at `value`, if that is known and small enough to unroll. Only loops
+/// without nested loops that return to their starting cell and count it
+/// down by one each iteration qualify.
+fn trip_count(body: &[BrainFuckToken], value: Option<i32>, threshold: u32) -> Option<usize> {
+    let value = value.filter(|&v| v > 0 && v as u32 <= threshold)? as usize;
+    // Reading input clobbers, and copying a `,` would break `input_span`'s
+    // count of them anyway.
+    let effect = Effect::of(body);
+
+    if !effect.clobbers_unknown
+        && effect.shift == Some(0)
+        && !effect.cleared.contains(&0)
+        && effect.delta(0) == -1
+        && value * body.len() <= UNROLL_BUDGET
+    {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+/// Replaces loops whose trip count constant analysis can work out with that
+/// many copies of their body, e.g. the `++++[>++<-]` setup loops that open
+/// most programs. Loops it can't unroll are searched for inner ones.
+fn unroll_loops(
+    tokens: &[BrainFuckToken],
+    offset: usize,
+    mut known: KnownCells,
+    threshold: u32,
+    fuel: &mut Fuel,
+) -> (Vec<BrainFuckToken>, Sources) {
+    let mut program = Vec::with_capacity(tokens.len());
+    let mut sources = Vec::with_capacity(tokens.len());
+    let mut idx = 0;
+
+    while idx < tokens.len() {
+        let token = tokens[idx];
+        let from = offset + idx;
+        idx += 1;
+
+        if let BrainFuckToken::JumpF(_) = token {
+            let (body, close) = loop_body(tokens, idx - 1);
+            let at = program.len();
+            idx = close + 1;
+
+            let trips = trip_count(body, known.current(), threshold)
+                .filter(|&n| fuel.consume(|| format!("unroll loop {} times at token {}", n, at)));
+            match trips {
+                Some(n) => for _ in 0..n {
+                    for (op_idx, &op) in body.iter().enumerate() {
+                        known.apply(op);
+                        program.push(op);
+                        sources.push(from + 1 + op_idx..from + 2 + op_idx);
+                    }
+                },
+                None => {
+                    let (inner, inner_sources) = unroll_loops(body, from + 1, KnownCells::unknown(), threshold, fuel);
+                    program.push(token);
+                    sources.push(from..from + 1);
+                    program.extend(inner);
+                    sources.extend(inner_sources);
+                    program.push(tokens[close]);
+                    sources.push(offset + close..offset + close + 1);
+                    known = KnownCells::after_loop();
+                }
+            }
+        } else {
+            known.apply(token);
+            program.push(token);
+            sources.push(from..from + 1);
+        }
+    }
+
+    (program, sources)
+}
+
+/// Leaves every jump unresolved, for `--lazy-jumps`.
+pub fn defer_jumps(tokens: &mut [BrainFuckToken]) {
+    for token in tokens.iter_mut() {
+        match *token {
+            BrainFuckToken::JumpF(_) => *token = BrainFuckToken::JumpF(UNRESOLVED),
+            BrainFuckToken::JumpB(_) => *token = BrainFuckToken::JumpB(UNRESOLVED),
+            _ => {}
+        }
+    }
+}
+
+/// If `ops[loc]` is an unresolved `[`, finds its `]` and points the two at
+/// each other. Loops nested inside stay unresolved until they are reached.
+/// Returns false if there is no matching `]`.
+#[cfg(feature = "std")]
+fn resolve_jump(ops: &mut [BrainFuckToken], loc: usize) -> bool {
+    match ops.get(loc) {
+        Some(&BrainFuckToken::JumpF(UNRESOLVED)) => {}
+        _ => return true,
+    }
+
+    let mut depth = 0;
+    for idx in loc..ops.len() {
+        match ops[idx] {
+            BrainFuckToken::JumpF(_) => depth += 1,
+            BrainFuckToken::JumpB(_) => {
+                depth -= 1;
+                if depth == 0 {
+                    ops[loc] = BrainFuckToken::JumpF(idx);
+                    ops[idx] = BrainFuckToken::JumpB(loc);
+                    return true;
+                }
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+pub fn build_jumps(tokens: &mut [BrainFuckToken]) {
+    let mut brackets = Vec::new();
+
+    for idx in 0..tokens.len() {
+        match tokens[idx] {
+            BrainFuckToken::JumpF(_) => brackets.push(idx),
+            BrainFuckToken::JumpB(_) => {
+                let partner = brackets
+                    .pop()
+                    .unwrap_or_else(|| panic!("unmatched bracket at {}", idx));
+                tokens[idx] = BrainFuckToken::JumpB(partner);
+                tokens[partner] = BrainFuckToken::JumpF(idx);
+            }
+            _ => {}
+        }
+    }
+
+    if !brackets.is_empty() {
+        panic!("Unmatched brackets at: {:?}", brackets);
+    }
+}
+
+/// A top-level block of a program: either a run of straight-line tokens or
+/// a single outermost loop. Jump targets in `ops` are relative to the block.
+pub struct Chunk {
+    key: String,
+    ops: Vec<BrainFuckToken>,
+}
+
+impl Chunk {
+    fn new(key: String, tokens: Vec<BrainFuckToken>, passes: &[Pass]) -> Chunk {
+        Chunk {
+            key: key,
+            ops: optimize(tokens.into(), passes, &mut Fuel::unlimited()),
+        }
+    }
+}
+
+/// Re-optimizes a program after an edit by only recompiling the top-level
+/// blocks that changed; untouched blocks are reused and their jump targets
+/// shifted into place.
+///
+/// Most passes only look within a block, so optimizing each one on its own
+/// gives the same program as optimizing the whole thing at once. The ones
+/// that don't, `unroll` and `const-out`, are left out.
+pub struct IncrementalOptimizer {
+    pub chunks: Vec<Chunk>,
+    passes: Vec<Pass>,
+    pub reoptimized: usize,
+}
+
+impl IncrementalOptimizer {
+    pub fn new(passes: &[Pass]) -> IncrementalOptimizer {
+        // Unrolling and folding constant output depend on values set up by
+        // earlier blocks, so they can't run on one block at a time.
+        let passes = passes.iter().cloned().filter(|pass| pass.name() != "unroll" && pass.name() != "const-out");
+
+        IncrementalOptimizer {
+            chunks: Vec::new(),
+            passes: passes.collect(),
+            reoptimized: 0,
+        }
+    }
+
+    pub fn update(&mut self, tokens: VecDeque<BrainFuckToken>) -> Vec<BrainFuckToken> {
+        let blocks = split_blocks(tokens);
+        let mut keys: Vec<String> = blocks.iter().map(|block| block_key(block)).collect();
+
+        let mut old = replace(&mut self.chunks, Vec::with_capacity(blocks.len()));
+        let prefix = old.iter()
+            .zip(keys.iter())
+            .take_while(|&(chunk, key)| chunk.key == *key)
+            .count();
+        let suffix = old[prefix..]
+            .iter()
+            .rev()
+            .zip(keys[prefix..].iter().rev())
+            .take_while(|&(chunk, key)| chunk.key == *key)
+            .count();
+
+        let tail = old.split_off(old.len() - suffix);
+        old.truncate(prefix);
+        self.reoptimized = blocks.len() - prefix - suffix;
+
+        self.chunks.extend(old);
+        let changed = keys.drain(prefix..prefix + self.reoptimized);
+        for (key, block) in changed.zip(blocks.into_iter().skip(prefix)) {
+            self.chunks.push(Chunk::new(key, block, &self.passes));
+        }
+        self.chunks.extend(tail);
+
+        self.assemble()
+    }
+
+    fn assemble(&self) -> Vec<BrainFuckToken> {
+        let mut program = Vec::with_capacity(self.chunks.iter().map(|c| c.ops.len()).sum());
+
+        for chunk in &self.chunks {
+            let base = program.len();
+            program.extend(chunk.ops.iter().map(|op| match *op {
+                BrainFuckToken::JumpF(x) => BrainFuckToken::JumpF(x + base),
+                BrainFuckToken::JumpB(x) => BrainFuckToken::JumpB(x + base),
+                op => op,
+            }));
+        }
+
+        program
+    }
+}
+
+fn block_key(block: &[BrainFuckToken]) -> String {
+    let mut key = String::with_capacity(block.len());
+    for token in block {
+        token.write_source(&mut key);
+    }
+    key
+}
+
+/// Splits a token stream into top-level blocks. Anything after an unmatched
+/// bracket ends up in the last block so `build_jumps` can report it.
+fn split_blocks(tokens: VecDeque<BrainFuckToken>) -> Vec<Vec<BrainFuckToken>> {
+    let mut blocks = Vec::new();
+    let mut current = Vec::new();
+    let mut depth = 0usize;
+
+    for token in tokens {
+        match token {
+            BrainFuckToken::JumpF(_) => {
+                if depth == 0 && !current.is_empty() {
+                    blocks.push(std::mem::take(&mut current));
+                }
+                depth += 1;
+                current.push(token);
+            }
+            BrainFuckToken::JumpB(_) if depth > 0 => {
+                depth -= 1;
+                current.push(token);
+                if depth == 0 {
+                    blocks.push(std::mem::take(&mut current));
+                }
+            }
+            _ => current.push(token),
+        }
+    }
+
+    if !current.is_empty() {
+        blocks.push(current);
+    }
+
+    blocks
+}
@@ -0,0 +1,3432 @@
+extern crate rustfuck;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter, Result, Write};
+use std::io;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use rustfuck::*;
+
+/// Everything the interpreter says about a run, as opposed to what the
+/// program writes, goes to stderr through here. That keeps stdout to the
+/// program's output alone, so it is always safe to pipe.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        eprintln!($($arg)*)
+    };
+}
+
+/// Allocation counting for contributors, built with
+/// `cargo build --features profiling`. Counts every allocation the
+/// process makes, so `AllocReport` can split them up by phase.
+#[cfg(feature = "profiling")]
+mod profiling {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    static BYTES: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counting;
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            COUNT.fetch_add(1, Ordering::Relaxed);
+            BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        // Growing a Vec is churn too, so a realloc counts as an allocation
+        // of the new size.
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            COUNT.fetch_add(1, Ordering::Relaxed);
+            BYTES.fetch_add(new_size, Ordering::Relaxed);
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    /// Allocations and bytes allocated so far.
+    pub fn totals() -> (usize, usize) {
+        (COUNT.load(Ordering::Relaxed), BYTES.load(Ordering::Relaxed))
+    }
+}
+
+/// Allocations made by each phase of a run. Does nothing unless built with
+/// the `profiling` feature.
+#[derive(Default)]
+struct AllocReport {
+    #[cfg(feature = "profiling")]
+    phases: Vec<(&'static str, usize, usize)>,
+    #[cfg(feature = "profiling")]
+    last: (usize, usize),
+}
+
+impl AllocReport {
+    fn start() -> AllocReport {
+        #[cfg(feature = "profiling")]
+        return AllocReport { phases: Vec::new(), last: profiling::totals() };
+        #[cfg(not(feature = "profiling"))]
+        AllocReport::default()
+    }
+
+    /// Charges the allocations since the previous phase ended to `name`.
+    #[cfg_attr(not(feature = "profiling"), allow(unused_variables))]
+    fn phase(&mut self, name: &'static str) {
+        #[cfg(feature = "profiling")]
+        {
+            let (count, bytes) = profiling::totals();
+            self.phases.push((name, count - self.last.0, bytes - self.last.1));
+            self.last = (count, bytes);
+        }
+    }
+
+    fn print(&self) {
+        #[cfg(feature = "profiling")]
+        {
+            status!("\nAllocations:\n");
+            for &(name, count, bytes) in &self.phases {
+                status!("{:<10}{:>8} allocations {:>12} bytes", name, count, bytes);
+            }
+        }
+    }
+}
+
+/// Prints the `IR_OPS` reference as a Markdown table, or as a JSON array
+/// for tools that need to read the IR.
+fn ir_doc(format: MessageFormat) {
+    match format {
+        MessageFormat::Text => {
+            println!("| Opcode | Name | Notation | Operands | Semantics | Introduced by | Engines |");
+            println!("|-------:|------|----------|----------|-----------|---------------|---------|");
+            for op in IR_OPS {
+                println!(
+                    "| {} | {} | `{}` | {} | {} | {} | {} |",
+                    op.opcode,
+                    op.name,
+                    op.notation,
+                    if op.operands.is_empty() { "none" } else { op.operands },
+                    op.semantics,
+                    op.introduced_by,
+                    op.engines.join(", ")
+                );
+            }
+        }
+        MessageFormat::Json => {
+            let ops: Vec<String> = IR_OPS
+                .iter()
+                .map(|op| {
+                    let engines: Vec<String> = op.engines.iter().map(|e| json_string(e)).collect();
+                    format!(
+                        "{{\"opcode\":{},\"name\":{},\"notation\":{},\"operands\":{},\"semantics\":{},\"introduced_by\":{},\"engines\":[{}]}}",
+                        op.opcode,
+                        json_string(op.name),
+                        json_string(op.notation),
+                        json_string(op.operands),
+                        json_string(op.semantics),
+                        json_string(op.introduced_by),
+                        engines.join(",")
+                    )
+                })
+                .collect();
+            println!("[{}]", ops.join(",\n "));
+        }
+    }
+}
+
+/// A few sentences on where a run spent its steps: the costliest loops,
+/// loops against straight-line code, and the pass most likely to help.
+fn hot_summary(trace: &Trace, ops: &[BrainFuckToken], steps: u64, passes: &[Pass]) -> String {
+    let percent = |n: u64| 100.0 * n as f64 / steps.max(1) as f64;
+
+    let mut loops: Vec<(&JumpLocs, &u64)> = trace.cost.iter().collect();
+    if loops.is_empty() {
+        return "No loops ran.".to_string();
+    }
+    loops.sort_by(|&(a, x), &(b, y)| y.cmp(x).then(a.cmp(b)));
+
+    let hottest: Vec<String> = loops
+        .iter()
+        .take(3)
+        .map(|&(locs, &cost)| {
+            let mut name = token_run_to_string(locs, &ops.to_vec()).trim().to_string();
+            if name.chars().count() > 30 {
+                name = name.chars().take(29).collect::<String>() + "…";
+            }
+            format!("`{}` ({:.1}%)", name, percent(cost))
+        })
+        .collect();
+    let in_loops: u64 = loops.iter().map(|&(_, &cost)| cost).sum();
+
+    let mut summary = format!(
+        "Hot loops: {}. {:.1}% of steps were spent in loops and {:.1}% in straight-line code.",
+        hottest.join(", "),
+        percent(in_loops),
+        100.0 - percent(in_loops)
+    );
+
+    // Estimate what each pass that isn't enabled would save by running it on
+    // every loop's own ops and scaling the loop's cost by how many it drops.
+    let candidates = FUSED_PASSES.iter().filter(|pass| !passes.contains(pass));
+    let best = candidates
+        .map(|&pass| {
+            let saved: f64 = loops
+                .iter()
+                .map(|&(&locs, &cost)| {
+                    let own = loop_own_ops(locs, ops);
+                    let after = pass.apply(own.clone(), &mut Fuel::unlimited());
+                    cost as f64 * (own.len() - after.len().min(own.len())) as f64 / own.len() as f64
+                })
+                .sum();
+            (pass, saved)
+        })
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+
+    match best {
+        Some((pass, saved)) if percent(saved as u64) >= 1.0 => {
+            let level = if DEFAULT_PASSES.contains(&pass) { "-O1" } else { "-O2" };
+            write!(
+                summary,
+                " Suggestion: enable {} ({}), which would eliminate ~{:.0}% of steps.",
+                level,
+                pass.name(),
+                percent(saved as u64)
+            ).ok();
+        }
+        _ => summary.push_str(" The enabled passes already cover the hot loops."),
+    }
+
+    summary
+}
+
+/// How often a loop was entered across several runs of the same program.
+#[derive(Debug)]
+struct LoopStats {
+    mean: f64,
+    stddev: f64,
+    min: u32,
+    max: u32,
+}
+
+/// Combines the trace reports of several runs; loops missing from a run's
+/// report count as zero for that run.
+fn aggregate_reports(reports: &[HashMap<String, u32>]) -> Vec<(String, LoopStats)> {
+    let mut names: Vec<&String> = reports.iter().flat_map(|r| r.keys()).collect();
+    names.sort();
+    names.dedup();
+
+    let n = reports.len() as f64;
+    let mut stats: Vec<(String, LoopStats)> = names
+        .into_iter()
+        .map(|name| {
+            let counts: Vec<u32> = reports
+                .iter()
+                .map(|r| r.get(name).cloned().unwrap_or(0))
+                .collect();
+            let mean = counts.iter().map(|&c| c as f64).sum::<f64>() / n;
+            let variance = counts
+                .iter()
+                .map(|&c| (c as f64 - mean).powi(2))
+                .sum::<f64>() / n;
+
+            (
+                name.clone(),
+                LoopStats {
+                    mean: mean,
+                    stddev: variance.sqrt(),
+                    min: *counts.iter().min().unwrap(),
+                    max: *counts.iter().max().unwrap(),
+                },
+            )
+        })
+        .collect();
+
+    stats.sort_by(|&(_, ref a), &(_, ref b)| b.mean.partial_cmp(&a.mean).unwrap());
+    stats
+}
+
+const LOOP_TEST_STEP_LIMIT: u64 = 1_000_000_000;
+
+/// Runs each loop test `--emit-loop-tests` wrote to `dir`, optimized with
+/// `passes`, and checks it leaves the tape, pointer and output as it did
+/// when it was captured.
+fn loop_tests(dir: &str, passes: &[Pass]) {
+    fn field(table: &TomlTable, key: &str) -> std::result::Result<i64, String> {
+        match table.get(key) {
+            Some(&TomlValue::Int(n)) => Ok(n),
+            _ => Err(format!("`{}` must be an integer", key)),
+        }
+    }
+
+    fn ints(table: &TomlTable, key: &str) -> std::result::Result<Vec<i64>, String> {
+        match table.get(key) {
+            Some(&TomlValue::Array(ref items)) => items
+                .iter()
+                .map(|item| match *item {
+                    TomlValue::Int(n) => Some(n),
+                    _ => None,
+                })
+                .collect::<Option<_>>()
+                .ok_or_else(|| format!("`{}` must be a list of integers", key)),
+            _ => Err(format!("`{}` must be a list of integers", key)),
+        }
+    }
+
+    let check = |text: &str| -> std::result::Result<(), String> {
+        let table = parse_toml(text)?.remove(0).1;
+        let source = table.get("loop").and_then(TomlValue::as_str).ok_or("`loop` must be a string")?;
+        let tape = ints(&table, "tape")?;
+        let expected_tape = ints(&table, "expected_tape")?;
+        let expected_output: Vec<u8> = ints(&table, "expected_output")?.iter().map(|&b| b as u8).collect();
+
+        let profile = Profile {
+            cells: field(&table, "cells")?.to_string().parse()?,
+            tape: tape.len(),
+            ..Profile::default()
+        };
+        let ops = optimize(parse(source, DEFAULT_MAX_DEPTH), passes, &mut Fuel::unlimited());
+        let mut prog = Program::with_profile(ops, profile);
+        prog.tape.tape = tape.iter().map(|&cell| cell as i32).collect();
+        prog.tape.loc = field(&table, "ptr")? as usize;
+        prog.step_limit = LOOP_TEST_STEP_LIMIT;
+        let mut output = Vec::new();
+        let result = prog.run(&[], &mut output);
+
+        let cells: Vec<i64> = prog.tape.tape.iter().map(|&cell| cell as i64).collect();
+        if result.halt != HaltReason::Completed {
+            Err(format!("stopped after {} steps: {}", result.steps, result.halt))
+        } else if cells != expected_tape {
+            let idx = cells.iter().zip(&expected_tape).position(|(a, b)| a != b).unwrap_or(0);
+            Err(format!("cell {} is {}, expected {}", idx, cells[idx], expected_tape[idx]))
+        } else if prog.tape.loc as i64 != field(&table, "expected_ptr")? {
+            Err(format!("pointer ended at {}, expected {}", prog.tape.loc, field(&table, "expected_ptr")?))
+        } else {
+            compare_output(&expected_output, &output, Whitespace::Exact)
+        }
+    };
+
+    let mut paths: Vec<_> = io_or_exit(std::fs::read_dir(dir), dir)
+        .map(|entry| io_or_exit(entry, dir).path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "toml"))
+        .collect();
+    paths.sort();
+
+    let mut failed = 0;
+    for path in &paths {
+        let name = path.to_str().unwrap();
+        let text = String::from_utf8_lossy(&read_bytes(name)).into_owned();
+        match check(&text) {
+            Ok(()) => status!("ok    {}", name),
+            Err(reason) => {
+                status!("FAIL  {}: {}", name, reason);
+                failed += 1;
+            }
+        }
+    }
+
+    status!("\n{} passed, {} failed", paths.len() - failed, failed);
+    if failed > 0 {
+        Exit::Assertion.exit();
+    }
+}
+
+/// A program shipped inside the binary, along with the input it should be
+/// fed, so there's always something to run.
+struct Demo {
+    name: &'static str,
+    description: &'static str,
+    source: &'static str,
+    input: &'static str,
+}
+
+const DEMOS: &'static [Demo] = &[
+    Demo {
+        name: "hello",
+        description: "prints Hello World!",
+        source: include_str!("../bfprogs/helloworld.bf"),
+        input: "",
+    },
+    Demo {
+        name: "rot13",
+        description: "ROT13-encodes its input",
+        source: include_str!("../bfprogs/rot13.bf"),
+        input: "Hello, World!\n",
+    },
+    Demo {
+        name: "fibonacci",
+        description: "prints the Fibonacci numbers below one hundred",
+        source: include_str!("../bfprogs/fib.bf"),
+        input: "",
+    },
+    Demo {
+        name: "mandelbrot",
+        description: "renders the Mandelbrot set in ASCII (slow)",
+        source: include_str!("../bfprogs/mandel.bf"),
+        input: "",
+    },
+];
+
+fn find_demo(name: &str) -> Option<&'static Demo> {
+    DEMOS.iter().find(|demo| demo.name == name)
+}
+
+/// How whitespace is treated when comparing a program's output to its source.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Whitespace {
+    Exact,
+    Trailing,
+    Ignore,
+}
+
+impl FromStr for Whitespace {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Whitespace, String> {
+        match s {
+            "exact" => Ok(Whitespace::Exact),
+            "trailing" => Ok(Whitespace::Trailing),
+            "ignore" => Ok(Whitespace::Ignore),
+            _ => Err(format!("unknown whitespace policy `{}`", s)),
+        }
+    }
+}
+
+impl Whitespace {
+    fn normalize(&self, bytes: &[u8]) -> Vec<u8> {
+        match *self {
+            Whitespace::Exact => bytes.to_vec(),
+            Whitespace::Trailing => {
+                let end = bytes
+                    .iter()
+                    .rposition(|b| !b.is_ascii_whitespace())
+                    .map_or(0, |i| i + 1);
+                bytes[..end].to_vec()
+            }
+            Whitespace::Ignore => bytes
+                .iter()
+                .cloned()
+                .filter(|b| !b.is_ascii_whitespace())
+                .collect(),
+        }
+    }
+}
+
+/// Compares two outputs under a whitespace policy, describing the first
+/// mismatch.
+fn compare_output(expected: &[u8], produced: &[u8], policy: Whitespace) -> std::result::Result<(), String> {
+    let expected = policy.normalize(expected);
+    let produced = policy.normalize(produced);
+
+    match expected.iter().zip(produced.iter()).position(|(a, b)| a != b) {
+        Some(offset) => Err(format!(
+            "output differs at byte {}: expected {:?}, got {:?}",
+            offset, expected[offset] as char, produced[offset] as char
+        )),
+        None if expected.len() != produced.len() => Err(format!(
+            "output is {} bytes, expected {} bytes",
+            produced.len(),
+            expected.len()
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Checks that `output` reproduces `source`, describing the first mismatch.
+fn check_quine(source: &str, output: &[u8], policy: Whitespace) -> std::result::Result<(), String> {
+    compare_output(source.as_bytes(), output, policy)
+}
+
+/// Runs `command` (split on whitespace, with the program path appended)
+/// feeding it `input`, and returns what it wrote to stdout. The process is
+/// killed if it runs longer than `timeout`.
+fn run_external(command: &str, path: &str, input: &[u8], timeout: Duration) -> std::result::Result<Vec<u8>, String> {
+    use std::io::{Read, Write as IoWrite};
+    use std::process::{Command, Stdio};
+    use std::thread;
+
+    let mut words = command.split_whitespace();
+    let program = words.next().ok_or("empty external command")?;
+    let mut child = Command::new(program)
+        .args(words)
+        .arg(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("couldn't start `{}`: {}", command, e))?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    let writer = thread::spawn(move || stdin.write_all(&input).ok());
+
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut buf = Vec::new();
+        stdout.read_to_end(&mut buf).ok();
+        buf
+    });
+
+    let start = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break,
+            Ok(None) if start.elapsed() > timeout => {
+                child.kill().ok();
+                child.wait().ok();
+                return Err(format!("`{}` timed out after {:?}", command, timeout));
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(10)),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+
+    writer.join().ok();
+    reader.join().map_err(|_| "couldn't read external output".to_string())
+}
+
+/// A small program with known output used to probe interpreter semantics.
+struct Conformance {
+    name: &'static str,
+    source: &'static str,
+    input: &'static str,
+    expected: &'static str,
+}
+
+const CONFORMANCE_STEP_LIMIT: u64 = 10_000_000;
+
+const CONFORMANCE_SUITE: &'static [Conformance] = &[
+    Conformance {
+        name: "hello",
+        source: include_str!("../bfprogs/helloworld.bf"),
+        input: "",
+        expected: "Hello World!\n",
+    },
+    Conformance {
+        name: "echo (EOF = 0)",
+        source: ",[.,]",
+        input: "echo",
+        expected: "echo",
+    },
+    Conformance {
+        name: "echo (EOF = -1)",
+        source: ",+[-.,+]",
+        input: "echo",
+        expected: "echo",
+    },
+    Conformance {
+        name: "echo (EOF = 0 or unchanged)",
+        source: ",[.[-],]",
+        input: "echo",
+        expected: "echo",
+    },
+    Conformance {
+        name: "rot13",
+        source: include_str!("../bfprogs/rot13.bf"),
+        input: "Hello, World!",
+        expected: "Uryyb, Jbeyq!",
+    },
+    Conformance {
+        name: "8 bit wraparound",
+        source: "++++++++[>++++++++<-]>[<++++>-]<>+<[>-<[-]]>[<++++++++++[>+++++++++++<-]>.----.[-]]",
+        input: "",
+        expected: "ok",
+    },
+    Conformance {
+        name: "wider than 8 bits",
+        source: "++++++++[>++++++++<-]>[<++++>-]<[>++++++++++[>+++++++++++<-]>+.----.[-]<<[-]]",
+        input: "",
+        expected: "ok",
+    },
+];
+
+/// Runs the built-in suite under every profile, then through `run_str`, and
+/// prints a table of which programs behave as expected where.
+fn conformance(max_depth: usize) {
+    let widths = [CellWidth::Bits8, CellWidth::Bits16, CellWidth::Bits32];
+    let eofs = [
+        (Eof::Zero, "0"),
+        (Eof::MinusOne, "-1"),
+        (Eof::Unchanged, "="),
+    ];
+
+    let mut header = format!("{:<30}", "cells/EOF");
+    for width in &widths {
+        for &(_, label) in &eofs {
+            header.push_str(&format!("{:>6}", format!("{}/{}", width.mask().count_ones(), label)));
+        }
+    }
+    header.push_str(&format!("{:>9}", "run_str"));
+    status!("{}", header);
+
+    for test in CONFORMANCE_SUITE {
+        let ops = optimize(parse(test.source, max_depth), DEFAULT_PASSES, &mut Fuel::unlimited());
+        let mut row = format!("{:<30}", test.name);
+
+        for &cells in &widths {
+            for &(eof, _) in &eofs {
+                let profile = Profile {
+                    cells: cells,
+                    eof: eof,
+                    ..Profile::default()
+                };
+                let mut prog = Program::with_profile(ops.clone(), profile);
+                let mut output = Vec::new();
+                prog.step_limit = CONFORMANCE_STEP_LIMIT;
+                prog.run(test.input.as_bytes(), &mut output);
+
+                // `!!` when the unchecked engine disagrees with this one.
+                #[cfg(feature = "unchecked")]
+                {
+                    if let Err(e) = testing::difftest_unchecked(&ops, profile, test.input.as_bytes(), CONFORMANCE_STEP_LIMIT) {
+                        status!("{}: unchecked engine {}", test.name, e);
+                        row.push_str("    !!");
+                        continue;
+                    }
+                }
+                row.push_str(if output == test.expected.as_bytes() { "    ok" } else { "    --" });
+            }
+        }
+
+        let ok = run_str(test.source, test.input.as_bytes()).map_or(false, |out| out == test.expected.as_bytes());
+        row.push_str(if ok { "       ok" } else { "       --" });
+
+        status!("{}", row);
+    }
+}
+
+const MANIFEST: &'static str = "rustfuck.toml";
+
+/// Language extensions a manifest may require. None are implemented yet, so
+/// any requirement is refused rather than silently ignored.
+const EXTENSIONS: &'static [&'static str] = &[];
+
+/// A value in the small subset of TOML that manifests use.
+#[derive(Debug, Clone, PartialEq)]
+enum TomlValue {
+    Str(String),
+    Int(i64),
+    Array(Vec<TomlValue>),
+}
+
+impl TomlValue {
+    fn parse(text: &str) -> std::result::Result<TomlValue, String> {
+        let text = text.trim();
+
+        if text.starts_with('"') {
+            if text.len() < 2 || !text.ends_with('"') {
+                return Err(format!("unterminated string {}", text));
+            }
+            let mut s = String::new();
+            let mut chars = text[1..text.len() - 1].chars();
+            while let Some(c) = chars.next() {
+                s.push(match (c, c == '\\') {
+                    (_, false) => c,
+                    (_, true) => match chars.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        other => return Err(format!("bad escape {:?} in {}", other, text)),
+                    },
+                });
+            }
+            Ok(TomlValue::Str(s))
+        } else if text.starts_with('[') && text.ends_with(']') {
+            let inner = text[1..text.len() - 1].trim();
+            let items: std::result::Result<Vec<_>, _> = split_toml_array(inner)
+                .into_iter()
+                .filter(|item| !item.trim().is_empty())
+                .map(TomlValue::parse)
+                .collect();
+            items.map(TomlValue::Array)
+        } else {
+            text.parse().map(TomlValue::Int).map_err(|_| format!("unsupported value `{}`", text))
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match *self {
+            TomlValue::Str(ref s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+/// Splits the inside of a TOML array on commas that aren't inside strings.
+fn split_toml_array(inner: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let (mut start, mut in_string, mut escaped) = (0, false, false);
+
+    for (idx, c) in inner.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ',' if !in_string => {
+                items.push(&inner[start..idx]);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+
+    items.push(&inner[start..]);
+    items
+}
+
+/// Drops a `#` comment from a line, leaving `#` inside strings alone.
+fn strip_toml_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (idx, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..idx],
+            _ => {}
+        }
+    }
+
+    line
+}
+
+type TomlTable = HashMap<String, TomlValue>;
+
+/// Parses manifest TOML into its top-level table followed by its named
+/// tables, in file order. `[[name]]` headers may repeat; `[name]` may not.
+fn parse_toml(text: &str) -> std::result::Result<Vec<(String, TomlTable)>, String> {
+    let mut tables = vec![(String::new(), TomlTable::new())];
+
+    for (line_no, line) in text.lines().enumerate() {
+        let line = strip_toml_comment(line).trim();
+        let at = |msg: String| format!("{} line {}: {}", MANIFEST, line_no + 1, msg);
+
+        if line.is_empty() {
+            continue;
+        } else if line.starts_with("[[") && line.ends_with("]]") {
+            tables.push((line[2..line.len() - 2].trim().to_string(), TomlTable::new()));
+        } else if line.starts_with('[') && line.ends_with(']') {
+            let name = line[1..line.len() - 1].trim().to_string();
+            if tables.iter().any(|&(ref existing, _)| *existing == name) {
+                return Err(at(format!("table [{}] defined twice", name)));
+            }
+            tables.push((name, TomlTable::new()));
+        } else {
+            let eq = line.find('=').ok_or_else(|| at(format!("expected `key = value`, got `{}`", line)))?;
+            let key = line[..eq].trim().to_string();
+            let value = TomlValue::parse(&line[eq + 1..]).map_err(&at)?;
+            let table = &mut tables.last_mut().unwrap().1;
+            if table.insert(key.clone(), value).is_some() {
+                return Err(at(format!("key `{}` defined twice", key)));
+            }
+        }
+    }
+
+    Ok(tables)
+}
+
+/// Profile settings a manifest table may give, each falling back to what
+/// it is applied on top of.
+#[derive(Debug, Clone, Copy, Default)]
+struct ProfileOverrides {
+    cells: Option<CellWidth>,
+    eof: Option<Eof>,
+    tape: Option<usize>,
+}
+
+impl ProfileOverrides {
+    fn parse(table: &TomlTable) -> std::result::Result<ProfileOverrides, String> {
+        let mut overrides = ProfileOverrides::default();
+        match table.get("cells") {
+            Some(&TomlValue::Int(n)) => overrides.cells = Some(n.to_string().parse()?),
+            Some(&TomlValue::Str(ref width)) => overrides.cells = Some(width.parse()?),
+            Some(_) => return Err("`cells` must be 8, 16 or 32".to_string()),
+            None => {}
+        }
+        match table.get("eof") {
+            Some(&TomlValue::Str(ref eof)) => overrides.eof = Some(eof.parse()?),
+            Some(_) => return Err("`eof` must be a string".to_string()),
+            None => {}
+        }
+        match table.get("tape") {
+            Some(&TomlValue::Int(n)) if n > 0 => overrides.tape = Some(n as usize),
+            Some(_) => return Err("`tape` must be a positive integer".to_string()),
+            None => {}
+        }
+        Ok(overrides)
+    }
+
+    fn apply(&self, mut profile: Profile) -> Profile {
+        profile.cells = self.cells.unwrap_or(profile.cells);
+        profile.eof = self.eof.unwrap_or(profile.eof);
+        profile.tape = self.tape.unwrap_or(profile.tape);
+        profile
+    }
+}
+
+/// One sample run of a project: its input, if any, and the output it must
+/// produce. Paths are relative to the manifest.
+struct ProjectTest {
+    input: Option<String>,
+    expected: String,
+    /// Changes to the project's profile for this test alone.
+    profile: ProfileOverrides,
+    /// Overrides the main file's `@limit steps=`.
+    steps: Option<u64>,
+    /// Wall clock limit for each attempt.
+    seconds: Option<u64>,
+    /// How many more times to run the test if it fails; defaults to the
+    /// manifest's `retries`.
+    retries: Option<u32>,
+}
+
+/// The contents of a `rustfuck.toml`, e.g.
+///
+///     main = "hello.bf"
+///     includes = ["lib/print.bf"]
+///
+///     [profile]
+///     cells = 8
+///     eof = "unchanged"
+///
+///     [[test]]
+///     input = "samples/in.txt"
+///     expected = "samples/out.txt"
+///     steps = 1000000
+///     seconds = 5
+///     retries = 2
+///     eof = "zero"
+///
+/// Includes are prepended to the main file in the order given. A test may
+/// set its own `cells`, `eof` and `tape`, limit its `steps` and `seconds`,
+/// and be retried if it fails; a top-level `retries` applies to every test.
+struct Manifest {
+    main: String,
+    includes: Vec<String>,
+    profile: Profile,
+    retries: u32,
+    tests: Vec<ProjectTest>,
+}
+
+impl Manifest {
+    fn parse(text: &str) -> std::result::Result<Manifest, String> {
+        fn string(table: &TomlTable, key: &str) -> std::result::Result<Option<String>, String> {
+            match table.get(key) {
+                None => Ok(None),
+                Some(value) => value
+                    .as_str()
+                    .map(|s| Some(s.to_string()))
+                    .ok_or_else(|| format!("`{}` must be a string", key)),
+            }
+        }
+
+        fn strings(table: &TomlTable, key: &str) -> std::result::Result<Vec<String>, String> {
+            match table.get(key) {
+                None => Ok(Vec::new()),
+                Some(&TomlValue::Array(ref items)) => items
+                    .iter()
+                    .map(|item| item.as_str().map(str::to_string))
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| format!("`{}` must be a list of strings", key)),
+                Some(_) => Err(format!("`{}` must be a list of strings", key)),
+            }
+        }
+
+        fn count(table: &TomlTable, key: &str) -> std::result::Result<Option<u64>, String> {
+            match table.get(key) {
+                None => Ok(None),
+                Some(&TomlValue::Int(n)) if n >= 0 => Ok(Some(n as u64)),
+                Some(_) => Err(format!("`{}` must be a non-negative integer", key)),
+            }
+        }
+
+        let mut manifest = Manifest {
+            main: String::new(),
+            includes: Vec::new(),
+            profile: Profile::default(),
+            retries: 0,
+            tests: Vec::new(),
+        };
+
+        for (name, table) in parse_toml(text)? {
+            match name.as_str() {
+                "" => {
+                    manifest.main = string(&table, "main")?.ok_or("`main` is required")?;
+                    manifest.includes = strings(&table, "includes")?;
+                    manifest.retries = count(&table, "retries")?.unwrap_or(0) as u32;
+                    for ext in strings(&table, "extensions")? {
+                        if !EXTENSIONS.contains(&ext.as_str()) {
+                            return Err(format!("unsupported extension `{}`", ext));
+                        }
+                    }
+                }
+                "profile" => manifest.profile = ProfileOverrides::parse(&table)?.apply(Profile::default()),
+                "test" => manifest.tests.push(ProjectTest {
+                    input: string(&table, "input")?,
+                    expected: string(&table, "expected")?.ok_or("every [[test]] needs `expected`")?,
+                    profile: ProfileOverrides::parse(&table)?,
+                    steps: count(&table, "steps")?,
+                    seconds: count(&table, "seconds")?,
+                    retries: count(&table, "retries")?.map(|n| n as u32),
+                }),
+                _ => return Err(format!("unknown table [{}]", name)),
+            }
+        }
+
+        Ok(manifest)
+    }
+}
+
+/// Command line settings for `run` and `test`.
+#[derive(Debug)]
+struct ProjectOptions {
+    dir: String,
+    whitespace: Whitespace,
+    output: Option<String>,
+    stats_out: Option<String>,
+    /// Tests to run at once.
+    jobs: usize,
+    /// Where to write the JSON report of a `test` run.
+    report: Option<String>,
+}
+
+impl Default for ProjectOptions {
+    fn default() -> ProjectOptions {
+        ProjectOptions {
+            dir: ".".to_string(),
+            whitespace: Whitespace::Trailing,
+            output: None,
+            stats_out: None,
+            jobs: 1,
+            report: None,
+        }
+    }
+}
+
+/// How one project test went, after any retries.
+struct TestOutcome {
+    name: String,
+    attempts: u32,
+    result: RunResult,
+    failure: Option<String>,
+}
+
+impl TestOutcome {
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"name\":{},\"status\":{},\"attempts\":{},\"halt\":{},\"steps\":{},\"reason\":{}}}",
+            json_string(&self.name),
+            json_string(if self.failure.is_some() { "fail" } else { "ok" }),
+            self.attempts,
+            json_string(self.result.halt.code()),
+            self.result.steps,
+            self.failure.as_ref().map_or("null".to_string(), |reason| json_string(reason))
+        )
+    }
+}
+
+/// Runs the project described by `dir/rustfuck.toml`, or with `test` checks
+/// each of its sample inputs against the expected output.
+fn project(command: &str, opts: &ProjectOptions) {
+    use std::path::Path;
+
+    let dir = Path::new(&opts.dir);
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    let file = |name: &str| {
+        let path = path(name);
+        read_source(&path, None, InvalidUtf8::Lossy).unwrap_or_else(|d| {
+            report_diagnostics(&path, &[d], MessageFormat::Text);
+            Exit::Usage.exit()
+        })
+    };
+    let bytes = |name: &str| read_bytes(&path(name));
+    let manifest = Manifest::parse(&file(MANIFEST)).unwrap_or_else(|e| {
+        eprintln!("{}: {}", dir.join(MANIFEST).display(), e);
+        Exit::Usage.exit()
+    });
+
+    // Each file is checked on its own, so that errors point into it, and
+    // the whole project is refused if any of them has errors.
+    let mut lints = LintLevels::default();
+    lints.set("warnings", Level::Allow).unwrap();
+    let checked = |name: &str| {
+        let source = file(name);
+        if report_diagnostics(&path(name), &check_source(&source, &lints, DEFAULT_MAX_DEPTH), MessageFormat::Text) {
+            Exit::Parse.exit();
+        }
+        source
+    };
+
+    let main_source = checked(&manifest.main);
+    // Already checked along with the lints above.
+    let limits = Limits::from_pragmas(&main_source).unwrap_or_default();
+    let mut source: String = manifest.includes.iter().map(|path| checked(path)).collect();
+    source.push_str(&main_source);
+
+    let mut profile = manifest.profile;
+    profile.tape = limits.tape.unwrap_or(profile.tape);
+    let ops = optimize(parse(&source, DEFAULT_MAX_DEPTH), DEFAULT_PASSES, &mut Fuel::unlimited());
+
+    let run = |input: &[u8], profile: Profile, steps: Option<u64>, seconds: Option<u64>| {
+        let mut prog = Program::with_profile(ops.clone(), profile);
+        let mut output = Vec::new();
+        if let Some(steps) = steps.or(limits.steps) {
+            prog.step_limit = steps;
+        }
+        if let Some(secs) = seconds {
+            prog.deadline = Some(Instant::now() + Duration::from_secs(secs));
+        }
+        let result = prog.run(input, &mut output);
+        (result, output)
+    };
+
+    if command == "run" {
+        let (result, bytes) = run(&[], profile, None, None);
+        match opts.output {
+            Some(ref path) => write_file(path, |file| io::Write::write_all(file, &bytes)),
+            None => write_output(&bytes),
+        }
+        if let Some(ref path) = opts.stats_out {
+            write_file(path, |file| io::Write::write_all(file, (stats_json(&result, &manifest.main, &Metadata::from_header(&main_source), Some(sha256(&bytes))) + "\n").as_bytes()));
+        }
+        return;
+    }
+
+    // A test fails if it stops for any reason other than finishing, or
+    // finishes with the wrong output; either way it gets its retries.
+    let run_test = |test: &ProjectTest| {
+        let input = test.input.as_ref().map_or(Vec::new(), |path| bytes(path));
+        let expected = bytes(&test.expected);
+        let retries = test.retries.unwrap_or(manifest.retries);
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let (result, output) = run(&input, test.profile.apply(profile), test.steps, test.seconds);
+            let failure = if result.halt != HaltReason::Completed {
+                Some(format!("stopped after {} steps: {}", result.steps, result.halt))
+            } else {
+                compare_output(&expected, &output, opts.whitespace).err()
+            };
+            if failure.is_none() || attempts > retries {
+                return TestOutcome {
+                    name: test.input.as_ref().unwrap_or(&test.expected).clone(),
+                    attempts: attempts,
+                    result: result,
+                    failure: failure,
+                };
+            }
+        }
+    };
+
+    // Workers take the next test off a shared counter until none are left;
+    // outcomes are put back in manifest order before reporting.
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    let outcomes = std::sync::Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..opts.jobs.max(1).min(manifest.tests.len()) {
+            scope.spawn(|| loop {
+                let idx = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                match manifest.tests.get(idx) {
+                    Some(test) => {
+                        let outcome = run_test(test);
+                        outcomes.lock().unwrap().push((idx, outcome));
+                    }
+                    None => break,
+                }
+            });
+        }
+    });
+    let mut outcomes = outcomes.into_inner().unwrap();
+    outcomes.sort_by_key(|&(idx, _)| idx);
+
+    let mut failed = 0;
+    for &(_, ref outcome) in &outcomes {
+        let retried = if outcome.attempts > 1 {
+            format!(" (after {})", plural(outcome.attempts as usize, "attempt"))
+        } else {
+            String::new()
+        };
+        match outcome.failure {
+            None => status!("ok    {}{}", outcome.name, retried),
+            Some(ref reason) => {
+                status!("FAIL  {}: {}{}", outcome.name, reason, retried);
+                failed += 1;
+            }
+        }
+    }
+
+    status!("\n{} passed, {} failed", outcomes.len() - failed, failed);
+    if let Some(ref path) = opts.report {
+        let tests: Vec<String> = outcomes.iter().map(|&(_, ref outcome)| outcome.to_json()).collect();
+        let report = format!(
+            "{{\"passed\":{},\"failed\":{},\"tests\":[{}]}}\n",
+            outcomes.len() - failed,
+            failed,
+            tests.join(",")
+        );
+        write_file(path, |file| io::Write::write_all(file, report.as_bytes()));
+    }
+    if failed > 0 {
+        Exit::Assertion.exit();
+    }
+}
+
+/// Settings for `equiv`.
+#[derive(Debug)]
+struct EquivOptions {
+    paths: Vec<String>,
+    inputs: Option<String>,
+    random: usize,
+    seed: u64,
+    step_limit: u64,
+    whitespace: Whitespace,
+}
+
+impl Default for EquivOptions {
+    fn default() -> EquivOptions {
+        EquivOptions {
+            paths: Vec::new(),
+            inputs: None,
+            random: 0,
+            seed: 1,
+            step_limit: EQUIV_STEP_LIMIT,
+            whitespace: Whitespace::Exact,
+        }
+    }
+}
+
+const EQUIV_STEP_LIMIT: u64 = 100_000_000;
+/// Longest random input `equiv` tries.
+const EQUIV_RANDOM_LEN: usize = 64;
+
+/// A xorshift generator; plenty for making up test inputs, and the same
+/// seed always gives the same inputs.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn bytes(&mut self, max_len: usize) -> Vec<u8> {
+        let len = self.next() as usize % (max_len + 1);
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+/// Runs two programs on the same inputs, from a directory and made up at
+/// random, and reports the first input they disagree on: different output,
+/// or one stopping in a way the other doesn't. The empty input is always
+/// tried first.
+fn equiv(opts: &EquivOptions) {
+    let mut lints = LintLevels::default();
+    lints.set("warnings", Level::Allow).unwrap();
+    let programs: Vec<Vec<BrainFuckToken>> = opts.paths
+        .iter()
+        .map(|path| {
+            let source = read_source(path, None, InvalidUtf8::Lossy).unwrap_or_else(|d| {
+                report_diagnostics(path, &[d], MessageFormat::Text);
+                Exit::Parse.exit()
+            });
+            if report_diagnostics(path, &check_source(&source, &lints, DEFAULT_MAX_DEPTH), MessageFormat::Text) {
+                Exit::Parse.exit();
+            }
+            optimize(parse(&source, DEFAULT_MAX_DEPTH), DEFAULT_PASSES, &mut Fuel::unlimited())
+        })
+        .collect();
+
+    let mut inputs: Vec<(String, Vec<u8>)> = vec![("the empty input".to_string(), Vec::new())];
+    if let Some(ref dir) = opts.inputs {
+        let mut paths: Vec<_> = io_or_exit(std::fs::read_dir(dir), dir)
+            .map(|entry| io_or_exit(entry, dir).path())
+            .filter(|path| path.is_file())
+            .collect();
+        paths.sort();
+        for path in paths {
+            let path = path.to_str().unwrap().to_string();
+            inputs.push((path.clone(), read_bytes(&path)));
+        }
+    }
+    let mut rng = Rng(opts.seed.max(1));
+    for _ in 0..opts.random {
+        let input = rng.bytes(EQUIV_RANDOM_LEN);
+        let escaped: String = input.iter().flat_map(|&b| std::ascii::escape_default(b)).map(|b| b as char).collect();
+        inputs.push((format!("random input \"{}\"", escaped), input));
+    }
+
+    let run = |ops: &Vec<BrainFuckToken>, input: &[u8]| {
+        let mut prog = Program::with_profile(ops.clone(), Profile::default());
+        prog.step_limit = opts.step_limit;
+        let mut output = Vec::new();
+        let result = prog.run(input, &mut output);
+        (result.halt, output)
+    };
+
+    for (name, input) in &inputs {
+        let (halt_a, out_a) = run(&programs[0], input);
+        let (halt_b, out_b) = run(&programs[1], input);
+
+        let difference = if halt_a.code() != halt_b.code() {
+            Some(format!("{} {}, but {} {}", opts.paths[0], halt_a, opts.paths[1], halt_b))
+        } else {
+            compare_output(&out_a, &out_b, opts.whitespace).err()
+        };
+        if let Some(difference) = difference {
+            status!("{} and {} differ on {}: {}", opts.paths[0], opts.paths[1], name, difference);
+            Exit::Assertion.exit();
+        }
+    }
+
+    status!("{} and {} agree on {}", opts.paths[0], opts.paths[1], plural(inputs.len(), "input"));
+}
+
+/// What `slice` is asked to explain.
+#[derive(Debug, Clone, Copy)]
+enum Criterion {
+    /// The byte at this offset in the program's output.
+    Output(usize),
+    /// The value this cell is left with.
+    Cell(usize),
+}
+
+impl FromStr for Criterion {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Criterion, String> {
+        let bad = || format!("expected `output[N]` or `cell[N]`, got `{}`", s);
+        if !s.ends_with(']') {
+            return Err(bad());
+        }
+        let open = s.find('[').ok_or_else(&bad)?;
+        let n = s[open + 1..s.len() - 1].parse().map_err(|_| bad())?;
+        match &s[..open] {
+            "output" => Ok(Criterion::Output(n)),
+            "cell" => Ok(Criterion::Cell(n)),
+            _ => Err(bad()),
+        }
+    }
+}
+
+impl Display for Criterion {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match *self {
+            Criterion::Output(n) => write!(f, "output[{}]", n),
+            Criterion::Cell(n) => write!(f, "cell[{}]", n),
+        }
+    }
+}
+
+struct SliceOptions {
+    path: Option<String>,
+    criterion: Option<Criterion>,
+    input: Option<String>,
+    step_limit: u64,
+}
+
+impl Default for SliceOptions {
+    fn default() -> SliceOptions {
+        SliceOptions {
+            path: None,
+            criterion: None,
+            input: None,
+            step_limit: SLICE_STEP_LIMIT,
+        }
+    }
+}
+
+/// Every step is journaled, so slicing stops well before a normal run would.
+const SLICE_STEP_LIMIT: u64 = 10_000_000;
+const NO_GUARD: u32 = u32::MAX;
+
+/// One step of a run recorded for slicing.
+#[derive(Debug, Clone, Copy)]
+struct JournalEntry {
+    op: u32,
+    ptr: u32,
+    /// The step of the `[` or `]` that decided this op would run, or
+    /// `NO_GUARD` outside any loop.
+    guard: u32,
+}
+
+/// Runs the unoptimized program, journaling every step, then walks the
+/// journal backwards from the criterion to find the ops that influenced it:
+/// the ones that wrote a cell or moved the pointer it depends on, and the
+/// loop tests that decided they would run. Prints the source with every
+/// other op dimmed, or blanked out when stdout isn't a terminal.
+fn slice(opts: &SliceOptions) {
+    use std::io::IsTerminal;
+
+    let path = opts.path.as_ref().unwrap();
+    let criterion = opts.criterion.unwrap();
+    let source = read_source(path, None, InvalidUtf8::Lossy).unwrap_or_else(|d| {
+        report_diagnostics(path, &[d], MessageFormat::Text);
+        Exit::Parse.exit()
+    });
+    let mut lints = LintLevels::default();
+    lints.set("warnings", Level::Allow).unwrap();
+    if report_diagnostics(path, &check_source(&source, &lints, DEFAULT_MAX_DEPTH), MessageFormat::Text) {
+        Exit::Parse.exit();
+    }
+    let mut ops: Vec<BrainFuckToken> = spanned_tokens(&source).into_iter().map(|(_, op)| op).collect();
+    build_jumps(&mut ops);
+    let input = opts.input.as_ref().map(|path| read_bytes(path)).unwrap_or_default();
+
+    let limit = opts.step_limit.min(NO_GUARD as u64);
+    let mut tape: Tape = Tape::new(Profile::default().cells, DEFAULT_TAPE_SIZE);
+    let mut input = input.iter();
+    let mut journal: Vec<JournalEntry> = Vec::new();
+    let mut guards: Vec<u32> = Vec::new();
+    let mut outputs: Vec<usize> = Vec::new();
+    let mut loc = 0;
+
+    while loc < ops.len() {
+        if journal.len() as u64 == limit {
+            status!("{} stopped after {} steps before {} was decided", path, limit, criterion);
+            Exit::Limit.exit();
+        }
+        let step = journal.len();
+        journal.push(JournalEntry {
+            op: loc as u32,
+            ptr: tape.loc as u32,
+            guard: *guards.last().unwrap_or(&NO_GUARD),
+        });
+
+        match ops[loc] {
+            BrainFuckToken::Incr(x) => tape.incr(x),
+            BrainFuckToken::Move(x) => if !tape.move_(x) {
+                status!("{}: {}", path, off_tape(loc));
+                Exit::Runtime.exit();
+            },
+            BrainFuckToken::StdIn => tape.put(input.next().map_or(0, |&b| b as i32)),
+            BrainFuckToken::StdOut => {
+                outputs.push(step);
+                match criterion {
+                    Criterion::Output(n) if n + 1 == outputs.len() => break,
+                    _ => {}
+                }
+            }
+            BrainFuckToken::JumpF(x) => if tape.get() == 0 {
+                loc = x;
+            } else {
+                guards.push(step as u32);
+            },
+            BrainFuckToken::JumpB(x) => if tape.get() != 0 {
+                *guards.last_mut().unwrap() = step as u32;
+                loc = x;
+            } else {
+                guards.pop();
+            },
+            _ => unreachable!(),
+        }
+        loc += 1;
+    }
+
+    let mut live_cells = HashSet::new();
+    let mut live_ptr = false;
+    let mut wanted = vec![false; journal.len()];
+    match criterion {
+        Criterion::Output(n) => match outputs.get(n) {
+            Some(&step) => wanted[step] = true,
+            None => {
+                status!("{} only wrote {}", path, plural(outputs.len(), "byte"));
+                Exit::Usage.exit();
+            }
+        },
+        Criterion::Cell(n) => {
+            if n >= tape.tape.len() {
+                status!("{} has no cell {}; the tape has {} cells", path, n, tape.tape.len());
+                Exit::Usage.exit();
+            }
+            live_cells.insert(n);
+        }
+    }
+
+    let mut in_slice = vec![false; ops.len()];
+    for step in (0..journal.len()).rev() {
+        let entry = journal[step];
+        let (op, ptr) = (entry.op as usize, entry.ptr as usize);
+        let needed = wanted[step] || match ops[op] {
+            BrainFuckToken::Incr(_) | BrainFuckToken::StdIn => live_cells.contains(&ptr),
+            BrainFuckToken::Move(_) => live_ptr,
+            _ => false,
+        };
+        if !needed {
+            continue;
+        }
+
+        in_slice[op] = true;
+        match ops[op] {
+            BrainFuckToken::StdIn => {
+                live_cells.remove(&ptr);
+            }
+            BrainFuckToken::Move(_) => {}
+            _ => {
+                live_cells.insert(ptr);
+            }
+        }
+        live_ptr = true;
+        if entry.guard != NO_GUARD {
+            wanted[entry.guard as usize] = true;
+        }
+    }
+
+    let terminal = io::stdout().is_terminal();
+    let mut sliced = String::with_capacity(source.len());
+    let (mut idx, mut dimmed) = (0, false);
+    for c in source.chars() {
+        let relevant = BrainFuckToken::from_char(c).is_some() && {
+            idx += 1;
+            in_slice[idx - 1]
+        };
+        if terminal {
+            if relevant == dimmed {
+                sliced.push_str(if relevant { "\x1b[0m" } else { "\x1b[2m" });
+                dimmed = !relevant;
+            }
+            sliced.push(c);
+        } else {
+            sliced.push(if relevant || BrainFuckToken::from_char(c).is_none() { c } else { ' ' });
+        }
+    }
+    if dimmed {
+        sliced.push_str("\x1b[0m");
+    }
+    print!("{}", sliced);
+
+    let kept = in_slice.iter().filter(|&&kept| kept).count();
+    status!(
+        "{} of {} are in the slice for {}, from a {}-step run",
+        kept,
+        plural(ops.len(), "op"),
+        criterion,
+        journal.len()
+    );
+}
+
+/// Presets for comparing against the interpreters this binary replaced:
+/// `bf.rs` and `bf2.rs` folded runs of the same command into one op, and
+/// `bf3.rs` ran the default passes. `Simple` folds nothing, as a baseline.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Preset {
+    Simple,
+    Collapsed,
+    Optimized,
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Preset, String> {
+        match s {
+            "simple" => Ok(Preset::Simple),
+            "collapsed" => Ok(Preset::Collapsed),
+            "optimized" => Ok(Preset::Optimized),
+            _ => Err(format!("unknown engine `{}`", s)),
+        }
+    }
+}
+
+impl Preset {
+    fn passes(&self) -> Vec<Pass> {
+        match *self {
+            Preset::Simple => Vec::new(),
+            Preset::Collapsed => vec![Pass::Collapse],
+            Preset::Optimized => DEFAULT_PASSES.to_vec(),
+        }
+    }
+}
+
+/// The flags that choose the optimization pipeline, shared by runs, `ir`,
+/// `compile` and `loop-tests` so they all build it the same way.
+struct Pipeline {
+    passes: Vec<Pass>,
+    // Applied once the pipeline itself is known, whatever the order.
+    disabled: Vec<String>,
+    unroll_threshold: Option<u32>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Pipeline {
+        Pipeline {
+            passes: DEFAULT_PASSES.to_vec(),
+            disabled: Vec::new(),
+            unroll_threshold: None,
+        }
+    }
+}
+
+impl Pipeline {
+    /// Takes `arg`, and its value from `args`, if it is a pipeline flag.
+    fn flag<T>(&mut self, arg: &str, args: &mut T) -> bool
+    where
+        T: Iterator<Item = String>,
+    {
+        match arg {
+            "-O0" => self.passes = Vec::new(),
+            "-O1" => self.passes = DEFAULT_PASSES.to_vec(),
+            "-O2" => self.passes = FUSED_PASSES.to_vec(),
+            "-O3" => self.passes = AGGRESSIVE_PASSES.to_vec(),
+            "--unroll-threshold" => self.unroll_threshold = Some(value(args)),
+            "--passes" => {
+                let list: String = value(args);
+                self.passes = parse_passes(&list).unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    Exit::Usage.exit()
+                });
+            }
+            "--disable-pass" => {
+                let pass: String = value(args);
+                if let Err(e) = pass.parse::<Pass>() {
+                    eprintln!("{}", e);
+                    Exit::Usage.exit();
+                }
+                self.disabled.push(pass);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// The passes to run, with the disabled ones taken out and the unroll
+    /// threshold applied.
+    fn passes(self) -> Vec<Pass> {
+        let mut passes = self.passes;
+        if !self.disabled.is_empty() {
+            let mut manager = PassManager::new(&passes);
+            for name in &self.disabled {
+                manager.disable(name);
+            }
+            passes = manager.passes();
+        }
+        if let Some(threshold) = self.unroll_threshold {
+            for pass in &mut passes {
+                if let Pass::Unroll(ref mut t) = *pass {
+                    *t = threshold;
+                }
+            }
+        }
+        passes
+    }
+}
+
+const USAGE: &'static str = "Usage: rustfuck [OPTIONS] PROGRAM
+       rustfuck demo NAME [OPTIONS]
+       rustfuck check [--quine] [OPTIONS] PROGRAM
+       rustfuck run [DIR] [--output FILE] [--stats-out FILE]
+       rustfuck run --watch [OPTIONS] PROGRAM
+       rustfuck test [DIR] [--whitespace MODE] [--jobs N] [--report FILE]
+       rustfuck trace-merge TRACE...
+       rustfuck ir-doc [--format text|json]
+       rustfuck ir PROGRAM [PASS OPTIONS] [--check EXPECTED]
+       rustfuck compile PROGRAM [-o FILE] [PASS OPTIONS]
+       rustfuck loop-tests DIR [PASS OPTIONS]
+       rustfuck slice PROGRAM --criterion output[N]|cell[N] [--input FILE]
+                      [--step-limit N]
+       rustfuck equiv A B [--inputs DIR] [--random N] [--seed N] [--step-limit N]
+                      [--whitespace MODE]
+
+Commands:
+    check               report lints for PROGRAM without running it, or with
+                        --quine run it as --check-quine does
+    demo                run the built-in example program NAME, as --demo NAME
+                        does; --list-demos lists them
+    run                 run the project described by DIR/rustfuck.toml
+                        (DIR defaults to the current directory); --output
+                        and --stats-out work as they do for a single program;
+                        with --watch, run PROGRAM as --watch does instead
+    test                run each of the project's [[test]] inputs and check
+                        the output against the expected file, N tests at a
+                        time with --jobs, retrying failures as the manifest
+                        says; --report writes the results to FILE as JSON
+    equiv               run programs A and B on the empty input, every file in
+                        DIR and N random inputs (seeded with --seed), and
+                        report the first input they disagree on; outputs
+                        must match exactly unless --whitespace says otherwise,
+                        and each run stops after 100000000 steps unless
+                        --step-limit says otherwise
+    loop-tests          run the loop tests in DIR, optimized with the given
+                        passes, and check each still does what it did
+    slice               print the source of PROGRAM with every op that didn't
+                        influence output byte N, or the final value of cell
+                        N, dimmed (or blanked out when not writing to a
+                        terminal); every step is recorded, so runs stop
+                        after 10000000 steps unless --step-limit says
+                        otherwise
+    ir                  print the optimized IR of PROGRAM, one op per line, or
+                        with --check compare it to EXPECTED, a listing
+                        saved from ir, and fail on the first op that differs;
+                        the passes come from EXPECTED's `# passes:` line
+    compile             optimize PROGRAM once and save it as bytecode to FILE
+                        (default PROGRAM with a .bfc extension), which runs
+                        like a program without parsing or optimizing again
+    ir-doc              print a reference of every IR instruction: its opcode,
+                        operands, semantics, the pass that introduces it
+                        and the engines that run it, as a Markdown table or
+                        JSON
+    trace-merge         add up traces saved with --trace-out, from several
+                        runs or machines, and report the costliest loops
+
+PASS OPTIONS for ir, compile and loop-tests are -O0..-O3, --passes,
+--disable-pass and --unroll-threshold, as below.
+
+Options:
+    --max-depth N       refuse programs with loops nested deeper than N (default 1024)
+    --max-tokens N      refuse programs with more than N commands
+    --max-source-bytes N
+                        refuse programs larger than N bytes
+    --emit-bf           print the program back out as Brainfuck instead of running it
+    --strip-comments    drop comments from --emit-bf output
+    --wrap N            break --emit-bf output lines longer than N characters
+    --emit-bytecode FILE
+                        write the optimized program to FILE as bytecode that
+                        embedders can run in place, instead of running it
+    --watch             re-run the program every time the file changes; takes
+                        the input, limit, pass, lint, --dispatch, --output
+                        and --stats-out options, but none of the tools that
+                        only make sense for a single run
+    --demo NAME         run one of the built-in example programs
+    --list-demos        list the built-in example programs
+    --check-quine       check that the program prints its own source
+    --whitespace MODE   how --check-quine and --difftest-external treat
+                        whitespace: exact, trailing (default) or ignore
+    --cell-width BITS   wrap cells at 8, 16 or 32 (default) bits
+    --char-io           read input as UTF-8 characters and write output cells
+                        as Latin-1 characters, as older versions did,
+                        instead of raw bytes
+    --step-limit N      stop after N steps (overrides @limit steps=)
+    --time-limit SECS   stop the program after SECS seconds
+    --tape-size N       use a tape of N cells (overrides @limit tape=)
+    --tape-fill X       start with every cell set to X (decimal or 0x hex)
+    --tape-init FILE[,OFFSET]
+                        copy the bytes of FILE onto the tape, starting at
+                        cell OFFSET (default 0), before running
+    --tape-out FILE[:START..END]
+                        write the final tape, or cells START up to END, to
+                        FILE as bytes
+    --eof MODE          what `,` stores at end of input: zero (default),
+                        minus-one, unchanged, or strict to stop with an
+                        error
+    --ip-end MODE       what a jump past the end of the program does: error
+                        (default) refuses to run a program with one, halt
+                        stops as if the program had finished
+    --input FILE        feed the contents of FILE to the program's input
+    --then-stdin        once the input runs out, read the rest from stdin as
+                        the program asks for it, showing output as it comes;
+                        not with --input-newlines or --verify-deterministic
+    --input-newlines MODE
+                        convert line endings in the input to lf or crlf
+                        before the program sees it (default keep)
+    --output-newlines MODE
+                        convert line endings in the output to lf or crlf
+                        (default keep); not with --debug or --throttle-hz
+    --transcript FILE   log the program's input and output, with step counts
+                        and timestamps, to FILE
+    --record FILE       save every step the program takes, with the cell it
+                        stored to and the bytes it wrote, to FILE
+    --replay FILE       check each step against a run saved with --record,
+                        given the same input, and stop at the first one that
+                        differs; not with --repeat
+    --throttle-hz N     execute at most N instructions per second, printing
+                        output as it is produced
+    --debug             step through the program interactively; type `help` at
+                        the prompt for commands
+    --explain-steps     describe each step as it runs, e.g. `ptr 2→3` or
+                        `cell 3: 4→5`, indenting the steps inside loops; pair
+                        with --step-limit to keep it short
+    --on-cell CELL[>N|<N]=ACTION
+                        when cell CELL changes, or rises above or falls below
+                        N, dump the cells around the pointer, count it, or
+                        abort the program; may be given more than once
+    --audit             check every step for behavior interpreters disagree on,
+                        like cells wrapping, the pointer wrapping and reads
+                        past the end of input, and report what the program
+                        relied on
+    --cost-report FILE  charge the steps each op took to the characters of
+                        the source it came from and write the source with
+                        the counts to FILE, as HTML if FILE ends in .html
+    --trajectory FILE   record the tape pointer over time and write it to FILE
+                        as a CSV table, or an SVG chart if FILE ends in .svg
+    --trajectory-every N
+                        sample the pointer every N steps (default 1000)
+    --animate FILE      write an animated SVG of the cells around the pointer,
+                        at most 500 frames and 30 seconds long, to FILE
+    --animate-every N   take a frame every N steps (default 1); doubled as
+                        needed to stay under the frame limit
+    --checkpoints FILE  every so many steps, append the cells that changed and
+                        the output written since the last checkpoint to FILE
+    --checkpoint-every N
+                        write a checkpoint every N steps (default 1000000)
+    --trace-out FILE    save the loop counters of the run to FILE, for
+                        trace-merge
+    --time-passes       report the wall time spent reading, parsing, linting,
+                        in each optimization pass and running the program
+    --emit-loop-tests DIR
+                        write the hottest loops to DIR as tests, each with the
+                        cells around the pointer when it first ran and what
+                        it left behind, for loop-tests
+    --trace-inputs DIR  run the program once per file in DIR, using it as
+                        input, and report loop statistics across the runs
+    --passes LIST       run the comma separated optimization passes in order,
+                        repeats allowed (default collapse,zero)
+    --disable-pass NAME skip every NAME pass in the pipeline chosen by the
+                        other options; may be repeated
+    -O0, -O1, -O2, -O3  optimization level: no passes, the default passes
+                        (-O1), those plus instruction fusion (-O2), or
+                        those plus loop unrolling (-O3)
+    --engine NAME       simple (one op per command, as -O0), collapsed
+                        (runs of a command folded into one op, as bf.rs and
+                        bf2.rs did) or optimized (as -O1 and bf3.rs, the
+                        default)
+    --unroll-threshold N
+                        unroll loops that run at most N times (default 8)
+    --dispatch MODE     run ops through a match (default) or a table of
+                        handler functions
+    --unchecked         run ops through the match without bounds checks
+                        when the program is shown not to need them; needs
+                        rustfuck built with the `unchecked` feature
+    --lazy-jumps        skip optimizing (as -O0) and find each loop's end the
+                        first time it is reached, for quick runs of large
+                        programs that only execute a small part of their code
+    --memoize           skip re-running loops that touch at most 8 cells, do
+                        no I/O and have no nested loops when they start from
+                        cells they started from before, and report the hit
+                        rate; only with the default match dispatch, and not
+                        with per-step hooks such as --debug, --trajectory
+                        or --throttle
+    --memo-entries N    remember at most N loop results (default 4096)
+    --opt-fuel N        stop optimizing after N rewrites
+    --opt-max-growth N  run unoptimized, with a warning, if optimizing makes
+                        more than N ops per source command (default 32)
+    --opt-max-passes N  likewise if the pipeline has more than N passes
+                        (default 64)
+    --opt-max-time SECS likewise if optimizing takes longer than SECS
+                        (default 5)
+    --bisect-fuel       find the first optimizer rewrite that changes the
+                        program's output
+    --bench N           time N runs of the optimized program
+    --repeat N          run the program N times on the same input, reusing
+                        the interpreter, and keep the last run's output
+    --verify-deterministic
+                        with --repeat, check that every run halts the same
+                        way after the same steps with the same output and
+                        final tape as the first, and fail on any difference
+    --output FILE       write the program's output to FILE instead of stdout
+    --stats-out FILE    write how the run went (halt reason, steps, output
+                        bytes, SHA-256 of the output even if discarded) to
+                        FILE as a JSON object
+    --output-audio FILE write the program's output to FILE as 8-bit PCM
+                        samples in a WAV file instead of printing it
+    --audio-rate HZ     sample rate for --output-audio (default 8000)
+    --output-image WxH:FILE
+                        write the program's output to FILE as a W by H image,
+                        grayscale (PGM) if it is W*H bytes or RGB (PPM) if it
+                        is W*H*3, instead of printing it
+    --discard-output    count the program's output instead of keeping it, and
+                        report only the number of bytes written
+    --stream-output MODE
+                        write output to stdout a line at a time as the program
+                        runs; when stdout can't take more, block (wait for
+                        it), buffer:N (keep up to N bytes waiting) or error
+                        (stop the program)
+    --difftest-external CMD
+                        also run the program with the external interpreter
+                        CMD and compare outputs (see --whitespace)
+    --difftest-timeout SECS
+                        give the external interpreter this long (default 10)
+    --conformance       run a suite of classic programs under every cell
+                        width and EOF behavior, and through run_str; with
+                        the `unchecked` feature, also check the unchecked
+                        engine against the safe one
+    -A, --allow LINT    don't report LINT
+    -W, --warn LINT     report LINT as a warning
+    -D, --deny LINT     report LINT as an error and refuse to run; LINT may
+                        be `warnings` for every lint that would warn, or one
+                        of unbalanced-loops, dead-code, cancelled-out,
+                        pointer-drift; unbalanced-loops stays an error
+                        when running, unless with --lazy-jumps
+    --message-format FMT
+                        write diagnostics to stderr as text (default) or
+                        json, one object per line
+    --format FORMAT     read PROGRAM as bf, bytecode (from compile or
+                        --emit-bytecode), ir (a listing from ir) or ook
+                        (Ook!) instead of guessing from its extension and
+                        contents
+    --invalid-utf8 MODE what to do with bytes in PROGRAM that aren't valid
+                        UTF-8: lossy (default) replaces them, ignore drops
+                        them, strict refuses to run
+    -h, --help          print this message
+
+Exit status:
+    0                   success
+    1                   bad command line or project manifest
+    2                   the program has errors and wasn't run
+    3                   runtime error, such as moving left of the first cell
+    4                   the program hit its step limit
+    5                   the program hit its time limit
+    6                   a file couldn't be read or written
+    7                   a check failed: test, --check-quine,
+                        --difftest-external, --bisect-fuel or an --on-cell
+                        abort";
+
+struct Options {
+    path: String,
+    max_depth: usize,
+    max_tokens: Option<usize>,
+    max_source_bytes: Option<usize>,
+    emit_bf: bool,
+    strip_comments: bool,
+    wrap: Option<usize>,
+    output: Option<String>,
+    stats_out: Option<String>,
+    watch: bool,
+    demo: Option<&'static Demo>,
+    list_demos: bool,
+    check_quine: bool,
+    whitespace: Whitespace,
+    profile: Profile,
+    limits: Limits,
+    conformance: bool,
+    input: Option<String>,
+    then_stdin: bool,
+    input_newlines: Newlines,
+    output_newlines: Newlines,
+    transcript: Option<String>,
+    record: Option<String>,
+    replay: Option<String>,
+    throttle_hz: Option<u32>,
+    debug: bool,
+    trajectory: Option<String>,
+    cost_report: Option<String>,
+    trajectory_every: u64,
+    animate: Option<String>,
+    animate_every: u64,
+    checkpoints: Option<String>,
+    checkpoint_every: u64,
+    trace_out: Option<String>,
+    emit_loop_tests: Option<String>,
+    memoize: bool,
+    memo_entries: usize,
+    time_passes: bool,
+    trace_inputs: Option<String>,
+    emit_bytecode: Option<String>,
+    opt_fuel: Option<u64>,
+    opt_budget: OptBudget,
+    bisect_fuel: bool,
+    passes: Vec<Pass>,
+    bench: Option<usize>,
+    repeat: Option<usize>,
+    verify_deterministic: bool,
+    difftest_external: Option<String>,
+    difftest_timeout: u64,
+    check: bool,
+    lints: LintLevels,
+    message_format: MessageFormat,
+    invalid_utf8: InvalidUtf8,
+    format: Option<SourceFormat>,
+    tape_fill: Option<i32>,
+    tape_init: Option<TapeImage>,
+    tape_out: Option<TapeDump>,
+    time_limit: Option<f64>,
+    discard_output: bool,
+    stream_output: Option<Backpressure>,
+    output_audio: Option<String>,
+    audio_rate: u32,
+    output_image: Option<ImageOutput>,
+    lazy_jumps: bool,
+    dispatch: Dispatch,
+    explain_steps: bool,
+    on_cell: Vec<Trigger>,
+    audit: bool,
+}
+
+impl Options {
+    fn from_args<T>(mut args: T) -> Options
+    where
+        T: Iterator<Item = String>,
+    {
+        let mut opts = Options {
+            path: String::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_tokens: None,
+            max_source_bytes: None,
+            emit_bf: false,
+            strip_comments: false,
+            wrap: None,
+            output: None,
+            stats_out: None,
+            watch: false,
+            demo: None,
+            list_demos: false,
+            check_quine: false,
+            whitespace: Whitespace::Trailing,
+            profile: Profile::default(),
+            limits: Limits::default(),
+            conformance: false,
+            input: None,
+            then_stdin: false,
+            input_newlines: Newlines::Keep,
+            output_newlines: Newlines::Keep,
+            transcript: None,
+            record: None,
+            replay: None,
+            throttle_hz: None,
+            debug: false,
+            trajectory: None,
+            cost_report: None,
+            trajectory_every: 1000,
+            animate: None,
+            animate_every: 1,
+            checkpoints: None,
+            checkpoint_every: 1_000_000,
+            trace_out: None,
+            emit_loop_tests: None,
+            memoize: false,
+            memo_entries: DEFAULT_MEMO_ENTRIES,
+            time_passes: false,
+            trace_inputs: None,
+            emit_bytecode: None,
+            opt_fuel: None,
+            opt_budget: OptBudget::default(),
+            bisect_fuel: false,
+            passes: DEFAULT_PASSES.to_vec(),
+            bench: None,
+            repeat: None,
+            verify_deterministic: false,
+            difftest_external: None,
+            difftest_timeout: 10,
+            check: false,
+            lints: LintLevels::default(),
+            message_format: MessageFormat::Text,
+            invalid_utf8: InvalidUtf8::Lossy,
+            format: None,
+            tape_fill: None,
+            tape_init: None,
+            tape_out: None,
+            time_limit: None,
+            discard_output: false,
+            stream_output: None,
+            output_audio: None,
+            audio_rate: 8000,
+            output_image: None,
+            lazy_jumps: false,
+            dispatch: Dispatch::Match,
+            explain_steps: false,
+            on_cell: Vec::new(),
+            audit: false,
+        };
+        let mut pipeline = Pipeline::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => usage_and_exit(0),
+                "--max-depth" => opts.max_depth = value(&mut args),
+                "--max-tokens" => opts.max_tokens = Some(value(&mut args)),
+                "--max-source-bytes" => opts.max_source_bytes = Some(value(&mut args)),
+                "--emit-bf" => opts.emit_bf = true,
+                "--strip-comments" => opts.strip_comments = true,
+                "--wrap" => opts.wrap = Some(value(&mut args)),
+                "--output" => opts.output = Some(value(&mut args)),
+                "--stats-out" => opts.stats_out = Some(value(&mut args)),
+                "--watch" => opts.watch = true,
+                "--demo" => {
+                    let name: String = value(&mut args);
+                    opts.demo = Some(find_demo(&name).unwrap_or_else(|| {
+                        eprintln!("Unknown demo `{}`, try --list-demos", name);
+                        Exit::Usage.exit()
+                    }));
+                }
+                "--list-demos" => opts.list_demos = true,
+                "--check-quine" => opts.check_quine = true,
+                "--whitespace" => opts.whitespace = value(&mut args),
+                "--cell-width" => opts.profile.cells = value(&mut args),
+                "--eof" => opts.profile.eof = value(&mut args),
+                "--ip-end" => opts.profile.ip_end = value(&mut args),
+                "--char-io" => opts.profile.char_io = true,
+                "--step-limit" => opts.limits.steps = Some(value(&mut args)),
+                "--time-limit" => opts.time_limit = Some(value(&mut args)),
+                "--tape-size" => opts.limits.tape = Some(value(&mut args)),
+                "--tape-fill" => {
+                    let fill: String = value(&mut args);
+                    opts.tape_fill = Some(parse_cell_value(&fill).unwrap_or_else(|| usage_and_exit(1)));
+                }
+                "--tape-init" => opts.tape_init = Some(value(&mut args)),
+                "--tape-out" => opts.tape_out = Some(value(&mut args)),
+                "--conformance" => opts.conformance = true,
+                "--input" => opts.input = Some(value(&mut args)),
+                "--then-stdin" => opts.then_stdin = true,
+                "--input-newlines" => opts.input_newlines = value(&mut args),
+                "--output-newlines" => opts.output_newlines = value(&mut args),
+                "--transcript" => opts.transcript = Some(value(&mut args)),
+                "--record" => opts.record = Some(value(&mut args)),
+                "--replay" => opts.replay = Some(value(&mut args)),
+                "--throttle-hz" => opts.throttle_hz = Some(value(&mut args)),
+                "--debug" => opts.debug = true,
+                "--trajectory" => opts.trajectory = Some(value(&mut args)),
+                "--cost-report" => opts.cost_report = Some(value(&mut args)),
+                "--trajectory-every" => opts.trajectory_every = value(&mut args),
+                "--animate" => opts.animate = Some(value(&mut args)),
+                "--animate-every" => opts.animate_every = value(&mut args),
+                "--checkpoints" => opts.checkpoints = Some(value(&mut args)),
+                "--checkpoint-every" => opts.checkpoint_every = value(&mut args),
+                "--trace-out" => opts.trace_out = Some(value(&mut args)),
+                "--emit-loop-tests" => opts.emit_loop_tests = Some(value(&mut args)),
+                "--memoize" => opts.memoize = true,
+                "--memo-entries" => opts.memo_entries = value(&mut args),
+                "--time-passes" => opts.time_passes = true,
+                "--trace-inputs" => opts.trace_inputs = Some(value(&mut args)),
+                "--emit-bytecode" => opts.emit_bytecode = Some(value(&mut args)),
+                "--lazy-jumps" => opts.lazy_jumps = true,
+                "--dispatch" => opts.dispatch = value(&mut args),
+                #[cfg(feature = "unchecked")]
+                "--unchecked" => opts.dispatch = Dispatch::Unchecked,
+                #[cfg(not(feature = "unchecked"))]
+                "--unchecked" => {
+                    status!("--unchecked needs rustfuck built with `--features unchecked`");
+                    Exit::Usage.exit();
+                }
+                "--explain-steps" => opts.explain_steps = true,
+                "--on-cell" => opts.on_cell.push(value(&mut args)),
+                "--audit" => opts.audit = true,
+                "--opt-fuel" => opts.opt_fuel = Some(value(&mut args)),
+                "--opt-max-growth" => opts.opt_budget.max_growth = value(&mut args),
+                "--opt-max-passes" => opts.opt_budget.max_passes = value(&mut args),
+                "--opt-max-time" => opts.opt_budget.max_time = Duration::from_secs(value(&mut args)),
+                "--bisect-fuel" => opts.bisect_fuel = true,
+                "--bench" => opts.bench = Some(value(&mut args)),
+                "--repeat" => opts.repeat = Some(value(&mut args)),
+                "--verify-deterministic" => opts.verify_deterministic = true,
+                "--discard-output" => opts.discard_output = true,
+                "--stream-output" => opts.stream_output = Some(value(&mut args)),
+                "--output-audio" => opts.output_audio = Some(value(&mut args)),
+                "--audio-rate" => opts.audio_rate = value(&mut args),
+                "--output-image" => opts.output_image = Some(value(&mut args)),
+                "--difftest-external" => opts.difftest_external = Some(value(&mut args)),
+                "--difftest-timeout" => opts.difftest_timeout = value(&mut args),
+                "--message-format" => opts.message_format = value(&mut args),
+                "--invalid-utf8" => opts.invalid_utf8 = value(&mut args),
+                "--format" => opts.format = Some(value(&mut args)),
+                "--engine" => pipeline.passes = value::<_, Preset>(&mut args).passes(),
+                "-A" | "--allow" => lint_level(&mut args, &mut opts.lints, Level::Allow),
+                "-W" | "--warn" => lint_level(&mut args, &mut opts.lints, Level::Warn),
+                "-D" | "--deny" => lint_level(&mut args, &mut opts.lints, Level::Deny),
+                _ if pipeline.flag(&arg, &mut args) => {}
+                _ if opts.path.is_empty() && !arg.starts_with("-") => opts.path = arg,
+                _ => usage_and_exit(1),
+            }
+        }
+
+        opts.passes = pipeline.passes();
+
+        let has_path = !opts.path.is_empty();
+        let standalone = opts.list_demos || opts.conformance;
+        let bad_limits = opts.limits.tape == Some(0) || opts.time_limit.map_or(false, |t| t.is_nan() || t < 0.0);
+        // These need the output itself, not just its length.
+        let needs_output = opts.check_quine
+            || opts.difftest_external.is_some()
+            || opts.output_audio.is_some()
+            || opts.output_image.is_some()
+            || opts.output.is_some();
+        // What hooks into every step, so runs through the slow path, which
+        // never consults --memoize's cache.
+        let per_step = opts.then_stdin
+            || opts.transcript.is_some()
+            || opts.record.is_some()
+            || opts.replay.is_some()
+            || opts.throttle_hz.is_some()
+            || opts.debug
+            || opts.explain_steps
+            || opts.audit
+            || !opts.on_cell.is_empty()
+            || opts.trajectory.is_some()
+            || opts.cost_report.is_some()
+            || opts.animate.is_some()
+            || opts.checkpoints.is_some()
+            || opts.emit_loop_tests.is_some();
+        // What only a single run does, which --watch doesn't.
+        let single_run = opts.emit_bf
+            || opts.emit_bytecode.is_some()
+            || opts.check_quine
+            || opts.then_stdin
+            || opts.input_newlines != Newlines::Keep
+            || opts.output_newlines != Newlines::Keep
+            || opts.transcript.is_some()
+            || opts.record.is_some()
+            || opts.replay.is_some()
+            || opts.throttle_hz.is_some()
+            || opts.debug
+            || opts.explain_steps
+            || opts.audit
+            || !opts.on_cell.is_empty()
+            || opts.trajectory.is_some()
+            || opts.cost_report.is_some()
+            || opts.animate.is_some()
+            || opts.checkpoints.is_some()
+            || opts.trace_out.is_some()
+            || opts.emit_loop_tests.is_some()
+            || opts.trace_inputs.is_some()
+            || opts.time_passes
+            || opts.memoize
+            || opts.lazy_jumps
+            || opts.opt_fuel.is_some()
+            || opts.bisect_fuel
+            || opts.bench.is_some()
+            || opts.repeat.is_some()
+            || opts.difftest_external.is_some()
+            || opts.tape_fill.is_some()
+            || opts.tape_init.is_some()
+            || opts.tape_out.is_some()
+            || opts.discard_output
+            || opts.stream_output.is_some()
+            || opts.output_audio.is_some()
+            || opts.output_image.is_some();
+        if has_path == opts.demo.is_some() && !standalone
+            || opts.watch && (!has_path || single_run)
+            || bad_limits
+            || opts.discard_output && needs_output
+            || opts.stream_output.is_some() && (needs_output || opts.discard_output || opts.repeat.is_some())
+            || opts.output_newlines != Newlines::Keep && (opts.debug || opts.throttle_hz.is_some())
+            || opts.lazy_jumps && (opts.dispatch == Dispatch::Table || opts.emit_bytecode.is_some())
+            || opts.memoize && (opts.lazy_jumps || opts.dispatch != Dispatch::Match || per_step || opts.memo_entries == 0)
+            || (opts.output_audio.is_some() || opts.output_image.is_some()) && opts.profile.char_io
+            || opts.wrap == Some(0)
+            || opts.repeat == Some(0)
+            || opts.verify_deterministic && opts.repeat.map_or(true, |runs| runs < 2)
+            || opts.then_stdin && (opts.input_newlines != Newlines::Keep || opts.verify_deterministic)
+            || opts.output.is_some() && (opts.output_audio.is_some() || opts.output_image.is_some())
+            || (opts.record.is_some() || opts.replay.is_some()) && opts.repeat.is_some()
+        {
+            usage_and_exit(1);
+        }
+
+        opts
+    }
+}
+
+/// Parses the value following a flag, bailing out with the usage text if it
+/// is missing or malformed.
+fn value<T, V>(args: &mut T) -> V
+where
+    T: Iterator<Item = String>,
+    V: FromStr,
+{
+    args.next()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| usage_and_exit(1))
+}
+
+fn lint_level<T>(args: &mut T, lints: &mut LintLevels, level: Level)
+where
+    T: Iterator<Item = String>,
+{
+    let name: String = value(args);
+    lints.set(&name, level).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        Exit::Usage.exit()
+    });
+}
+
+/// `--output-image WxH:FILE`: the size of the picture a program draws and
+/// where to save it.
+#[derive(Debug, Clone)]
+struct ImageOutput {
+    width: usize,
+    height: usize,
+    path: String,
+}
+
+impl FromStr for ImageOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<ImageOutput, String> {
+        let bad = || format!("expected WxH:FILE, got `{}`", s);
+        let colon = s.find(':').ok_or_else(bad)?;
+        let x = s[..colon].find('x').ok_or_else(bad)?;
+        let width = s[..x].parse().map_err(|_| bad())?;
+        let height = s[x + 1..colon].parse().map_err(|_| bad())?;
+        if width == 0 || height == 0 || colon + 1 == s.len() {
+            return Err(bad());
+        }
+        Ok(ImageOutput {
+            width: width,
+            height: height,
+            path: s[colon + 1..].to_string(),
+        })
+    }
+}
+
+/// Writes `pixels` as a binary PGM (one byte per pixel) or PPM (three) file,
+/// depending on how many there are for the image's size.
+fn write_image<W: io::Write>(w: &mut W, pixels: &[u8], image: &ImageOutput) -> io::Result<()> {
+    let area = image.width * image.height;
+    let magic = if pixels.len() == area {
+        "P5"
+    } else if pixels.len() == area * 3 {
+        "P6"
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "output is {}, expected {} for grayscale or {} for RGB",
+                plural(pixels.len(), "byte"),
+                area,
+                area * 3
+            ),
+        ));
+    };
+
+    write!(w, "{}\n{} {}\n255\n", magic, image.width, image.height)?;
+    w.write_all(pixels)
+}
+
+/// Writes `samples` as a mono, 8-bit PCM WAV file. 8-bit WAV samples are
+/// unsigned, with silence at 128.
+fn write_wav<W: io::Write>(w: &mut W, samples: &[u8], rate: u32) -> io::Result<()> {
+    let len = samples.len() as u32;
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(36 + len).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    header.extend_from_slice(&1u16.to_le_bytes()); // mono
+    header.extend_from_slice(&rate.to_le_bytes());
+    header.extend_from_slice(&rate.to_le_bytes()); // bytes per second
+    header.extend_from_slice(&1u16.to_le_bytes()); // bytes per frame
+    header.extend_from_slice(&8u16.to_le_bytes()); // bits per sample
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&len.to_le_bytes());
+
+    w.write_all(&header)?;
+    w.write_all(samples)
+}
+
+fn usage_and_exit(code: i32) -> ! {
+    if code == 0 {
+        println!("{}", USAGE);
+    } else {
+        eprintln!("{}", USAGE);
+    }
+    std::process::exit(code)
+}
+
+/// Unwraps the result of a file operation on `path`, or reports the error
+/// and exits with `Exit::Io`.
+fn io_or_exit<T>(result: io::Result<T>, path: &str) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        Exit::Io.exit()
+    })
+}
+
+fn read_bytes(path: &str) -> Vec<u8> {
+    io_or_exit(std::fs::read(path), path)
+}
+
+/// Creates `path` and fills it in with `write`. Regular files are written
+/// to a temporary file alongside and renamed into place once complete, so a
+/// crash or a reader running at the same time never sees half a file.
+/// Anything else, like `/dev/stdout`, is written directly.
+fn write_file<F>(path: &str, write: F)
+where
+    F: FnOnce(&mut std::fs::File) -> io::Result<()>,
+{
+    let special = std::fs::metadata(path).map_or(false, |meta| !meta.is_file());
+    if special {
+        let mut file = io_or_exit(std::fs::File::create(path), path);
+        io_or_exit(write(&mut file), path);
+        return;
+    }
+
+    let temp = format!("{}.{}.tmp", path, std::process::id());
+    let mut file = io_or_exit(std::fs::File::create(&temp), &temp);
+    if let Err(e) = write(&mut file).and_then(|()| file.sync_all()) {
+        std::fs::remove_file(&temp).ok();
+        io_or_exit(Err(e), path)
+    }
+    io_or_exit(std::fs::rename(&temp, path), path);
+}
+
+/// Reads a program file, in `format` or whatever format it looks like.
+fn read_source(path: &str, format: Option<SourceFormat>, policy: InvalidUtf8) -> std::result::Result<String, Diagnostic> {
+    let bytes = read_bytes(path);
+    let format = format.unwrap_or_else(|| SourceFormat::detect(path, &bytes));
+    decode_program(bytes, format, policy)
+}
+
+/// Turns a program file in `format` into Brainfuck source.
+fn decode_program(bytes: Vec<u8>, format: SourceFormat, policy: InvalidUtf8) -> std::result::Result<String, Diagnostic> {
+    match format {
+        SourceFormat::Brainfuck => decode_source(bytes, policy),
+        SourceFormat::Ook => {
+            ook_to_brainfuck(&decode_source(bytes, policy)?).map_err(|e| e.to_diagnostic())
+        }
+        SourceFormat::Bytecode => Err(Diagnostic {
+            code: "bytecode",
+            level: Level::Deny,
+            span: None,
+            message: "this is compiled bytecode, which can be run but not read as source".to_string(),
+            notes: Vec::new(),
+        }),
+        SourceFormat::IrText => Err(Diagnostic {
+            code: "ir-text",
+            level: Level::Deny,
+            span: None,
+            message: "this is an IR listing, which can be run but not read as source".to_string(),
+            notes: Vec::new(),
+        }),
+    }
+}
+
+/// Optimizes the program at `path` and writes it to `out` as bytecode, for
+/// `compile`. Programs with errors are refused, as they would be by a run.
+fn compile(path: &str, out: &str, passes: &[Pass]) {
+    let source = read_source(path, None, InvalidUtf8::Lossy).unwrap_or_else(|d| {
+        report_diagnostics(path, &[d], MessageFormat::Text);
+        Exit::Parse.exit()
+    });
+    let mut lints = LintLevels::default();
+    lints.set("warnings", Level::Allow).unwrap();
+    if report_diagnostics(path, &check_source(&source, &lints, DEFAULT_MAX_DEPTH), MessageFormat::Text) {
+        Exit::Parse.exit();
+    }
+
+    let ops = optimize(parse(&source, DEFAULT_MAX_DEPTH), passes, &mut Fuel::unlimited());
+    write_file(out, |file| io::Write::write_all(file, &to_bytecode(&ops)));
+    status!("Wrote {} to {}", plural(ops.len(), "op"), out);
+}
+
+/// Runs a file of already-optimized ops in `format`: bytecode from `compile`
+/// or `--emit-bytecode`, or an IR listing from `ir`. There is no source to
+/// lint or point diagnostics at, so only the plain run options apply.
+fn run_compiled(bytes: &[u8], format: SourceFormat, input: &[u8], opts: &Options) {
+    let ops = match format {
+        SourceFormat::IrText => testing::parse_ir_text(&String::from_utf8_lossy(bytes)),
+        _ => CompiledProgram::from_bytes(bytes).map(|compiled| compiled.ops().collect()).map_err(|e| e.to_string()),
+    };
+    let ops = ops.unwrap_or_else(|e| {
+        status!("{}: {}", opts.path, e);
+        Exit::Parse.exit()
+    });
+    let mut profile = opts.profile;
+    profile.tape = opts.limits.tape.unwrap_or(profile.tape);
+
+    let mut prog = Program::with_profile(ops, profile);
+    prog.dispatch = opts.dispatch;
+    if let Some(steps) = opts.limits.steps {
+        prog.step_limit = steps;
+    }
+    if let Some(secs) = opts.time_limit {
+        prog.deadline = Some(Instant::now() + Duration::from_secs_f64(secs));
+    }
+    prog.live_output = opts.then_stdin && opts.output.is_none();
+    let mut output = Vec::new();
+    let result = run_translated(&mut prog, input, opts.then_stdin, &mut output, opts.output_newlines);
+
+    match opts.output {
+        Some(ref path) => write_file(path, |file| io::Write::write_all(file, &output)),
+        None if !prog.live_output => write_output(&output),
+        None => {}
+    }
+    if result.halt != HaltReason::Completed {
+        status!("\nStopped after {} steps: {}", result.steps, result.halt);
+    }
+    if let Some(exit) = result.halt.exit() {
+        exit.exit();
+    }
+}
+
+/// Polls the program file and re-runs it whenever it changes. Only the
+/// top-level blocks touched by an edit are re-optimized; a broken edit is
+/// reported and the previous state kept until the next save.
+fn watch(opts: &Options) {
+    use std::fs;
+    use std::thread;
+
+    let mut optimizer = IncrementalOptimizer::new(&opts.passes);
+    let mut last_modified = None;
+
+    loop {
+        let modified = fs::metadata(&opts.path).and_then(|m| m.modified()).ok();
+
+        if modified.is_some() && modified != last_modified {
+            last_modified = modified;
+            status!("----- {} -----", opts.path);
+            watch_run(opts, &mut optimizer);
+        }
+
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
+/// One run of `watch`, checked like any other run. Errors are reported and
+/// leave `optimizer` as it was.
+fn watch_run(opts: &Options, optimizer: &mut IncrementalOptimizer) {
+    let source = match read_source(&opts.path, opts.format, opts.invalid_utf8) {
+        Ok(source) => source,
+        Err(diagnostic) => {
+            report_diagnostics(&opts.path, &[diagnostic], opts.message_format);
+            return;
+        }
+    };
+    let parse_options = ParseOptions {
+        max_tokens: opts.max_tokens,
+        max_source_bytes: opts.max_source_bytes,
+        max_depth: opts.max_depth,
+    };
+    let parsed = parse_with(&source, &parse_options);
+    let diagnostics = match parsed {
+        Err(ParseError::TooDeep { .. }) | Ok(_) => check_source(&source, &opts.lints, opts.max_depth),
+        Err(ref e) => vec![e.to_diagnostic()],
+    };
+    if report_diagnostics(&opts.path, &diagnostics, opts.message_format) {
+        return;
+    }
+
+    let input = opts.input.as_ref().map_or(Vec::new(), |path| read_bytes(path));
+    // Already checked along with the lints above.
+    let limits = opts.limits.or(Limits::from_pragmas(&source).unwrap_or_default());
+    let tokens = optimizer.update(parsed.unwrap());
+    let blocks = optimizer.chunks.len();
+    let mut profile = opts.profile;
+    profile.tape = limits.tape.unwrap_or(profile.tape);
+    let mut prog = Program::with_profile(tokens, profile);
+    prog.dispatch = opts.dispatch;
+    if let Some(steps) = limits.steps {
+        prog.step_limit = steps;
+    }
+    if let Some(secs) = opts.time_limit {
+        prog.deadline = Some(Instant::now() + Duration::from_secs_f64(secs));
+    }
+    let mut output = Vec::new();
+
+    let start = Instant::now();
+    let result = prog.run(&input, &mut output);
+    let elapsed = start.elapsed();
+
+    match opts.output {
+        Some(ref path) => write_file(path, |file| io::Write::write_all(file, &output)),
+        None => write_output(&output),
+    }
+    if let Some(ref path) = opts.stats_out {
+        let stats = stats_json(&result, &opts.path, &Metadata::from_header(&source), Some(sha256(&output)));
+        write_file(path, |file| io::Write::write_all(file, (stats + "\n").as_bytes()));
+    }
+    if result.halt != HaltReason::Completed {
+        status!("[stopped: {}]", result.halt);
+    }
+    status!(
+        "[{} ops, {} of {} blocks re-optimized, ran in {:.3}s]",
+        prog.ops.len(),
+        optimizer.reoptimized,
+        blocks,
+        elapsed.as_secs_f64()
+    );
+}
+
+/// Traces one run per input file in `dir` and prints per-loop statistics, so
+/// input-dependent programs aren't tuned against a single input.
+fn trace_inputs(ops: Vec<BrainFuckToken>, profile: Profile, dir: &str) {
+    let mut paths: Vec<_> = io_or_exit(std::fs::read_dir(dir), dir)
+        .map(|entry| io_or_exit(entry, dir).path())
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+
+    let reports: Vec<HashMap<String, u32>> = paths
+        .iter()
+        .map(|path| {
+            let mut prog = Program::with_profile(ops.clone(), profile);
+            let mut output = Vec::new();
+            prog.run(&read_bytes(path.to_str().unwrap()), &mut output);
+            prog.tracer.report(&prog.ops)
+        })
+        .collect();
+
+    status!("Trace over {} inputs:\n", reports.len());
+    for (name, stats) in aggregate_reports(&reports) {
+        status!(
+            "{} -> mean {:.1}, stddev {:.1}, min {}, max {}",
+            name, stats.mean, stats.stddev, stats.min, stats.max
+        );
+    }
+}
+
+/// Adds up the traces saved by `--trace-out` at `paths` and reports the
+/// loops by steps spent, across all of them.
+fn trace_merge(paths: &[String]) {
+    let mut merged = SavedTrace::default();
+    for path in paths {
+        let text = String::from_utf8_lossy(&read_bytes(path)).into_owned();
+        match SavedTrace::parse(&text) {
+            Ok(trace) => merged.merge(trace),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                Exit::Usage.exit()
+            }
+        }
+    }
+
+    status!("Trace over {}, {} steps:\n", plural(paths.len(), "run"), merged.steps);
+    for (name, &(count, cost)) in merged.sorted() {
+        let percent = 100.0 * cost as f64 / merged.steps.max(1) as f64;
+        status!("{} -> {} entries, {} steps ({:.1}%)", name, count, cost, percent);
+    }
+}
+
+const BISECT_STEP_LIMIT: u64 = 10_000_000_000;
+
+/// Binary searches the optimizer fuel for the first rewrite that changes the
+/// program's output compared to running it with no rewrites at all. A
+/// miscompile that crashes the interpreter or never finishes counts as a
+/// divergence.
+fn bisect_fuel(source: &str, input: &[u8], opts: &Options) {
+    use std::panic::{self, AssertUnwindSafe};
+
+    let run_with = |fuel: &mut Fuel| {
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let ops = optimize(parse(source, opts.max_depth), &opts.passes, fuel);
+            let mut prog = Program::with_profile(ops, opts.profile);
+            let mut output = Vec::new();
+            prog.step_limit = BISECT_STEP_LIMIT;
+            let result = prog.run(input, &mut output);
+            (output, result.halt == HaltReason::Completed)
+        })).ok()
+    };
+
+    let reference = run_with(&mut Fuel::new(0));
+    match reference {
+        Some((_, true)) => {}
+        _ => {
+            status!("The unoptimized program doesn't finish, nothing to compare against");
+            Exit::Limit.exit();
+        }
+    }
+    panic::set_hook(Box::new(|_| {}));
+
+    let mut full = Fuel::unlimited();
+    if run_with(&mut full) == reference {
+        status!("No divergence across all {} rewrites", full.spent);
+        return;
+    }
+
+    // Invariant: fuel `good` matches the reference and fuel `bad` doesn't.
+    let (mut good, mut bad) = (0, full.spent);
+    while bad - good > 1 {
+        let mid = good + (bad - good) / 2;
+        if run_with(&mut Fuel::new(mid)) == reference {
+            good = mid;
+        } else {
+            bad = mid;
+        }
+    }
+
+    let mut culprit = Fuel::new(bad);
+    run_with(&mut culprit);
+    status!(
+        "Output first diverges at rewrite {} of {}: {}",
+        bad,
+        full.spent,
+        culprit.last.unwrap_or_default()
+    );
+    Exit::Assertion.exit();
+}
+
+/// Times repeated runs of an already optimized program, so changes to the
+/// interpreter core can be measured without parsing and optimizing noise.
+fn bench(
+    ops: Vec<BrainFuckToken>,
+    profile: Profile,
+    input: &[u8],
+    runs: usize,
+    dispatch: Dispatch,
+    discard_output: bool,
+) {
+    let mut times = Vec::with_capacity(runs);
+    let mut steps = 0;
+    let mut output_bytes = 0;
+
+    for _ in 0..runs.max(1) {
+        let mut prog = Program::with_profile(ops.clone(), profile);
+        prog.dispatch = dispatch;
+        let start = Instant::now();
+        let result = if discard_output {
+            prog.run(input, &mut CountingSink::default())
+        } else {
+            prog.run(input, &mut Vec::new())
+        };
+        times.push(start.elapsed().as_secs_f64());
+        steps = result.steps;
+        output_bytes = result.output_bytes;
+    }
+
+    times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mean = times.iter().sum::<f64>() / times.len() as f64;
+    status!(
+        "{} runs, {} steps and {} output each: min {:.4}s, median {:.4}s, mean {:.4}s, max {:.4}s ({:.1} Msteps/s)",
+        times.len(),
+        steps,
+        plural(output_bytes as usize, "byte"),
+        times[0],
+        times[times.len() / 2],
+        mean,
+        times[times.len() - 1],
+        steps as f64 / times[0] / 1e6
+    );
+}
+
+/// Runs `prog` with its output's line endings converted to `newlines`.
+fn run_translated<O: OutputSink>(prog: &mut Program, input: &[u8], then_stdin: bool, out: &mut O, newlines: Newlines) -> RunResult {
+    if newlines == Newlines::Keep {
+        return run_input(prog, input, then_stdin, out);
+    }
+    let mut sink = NewlineSink::new(out, newlines);
+    let result = run_input(prog, input, then_stdin, &mut sink);
+    sink.finish();
+    result
+}
+
+/// `run_translated` with the options' input and newline settings, also
+/// giving the SHA-256 of the output if `hash` asks for it.
+fn run_hashed<O: OutputSink>(prog: &mut Program, input: &[u8], opts: &Options, out: &mut O, hash: bool) -> (RunResult, Option<[u8; 32]>) {
+    let mut sink = HashingSink::new(out, if hash { Some(Sha256::new()) } else { None });
+    let result = run_translated(prog, input, opts.then_stdin, &mut sink, opts.output_newlines);
+    (result, sink.finish())
+}
+
+/// Runs `prog` on `input`, followed by stdin with `--then-stdin`.
+fn run_input<O: OutputSink>(prog: &mut Program, input: &[u8], then_stdin: bool, out: &mut O) -> RunResult {
+    if !then_stdin {
+        return prog.run(input, out);
+    }
+    let stdin = io::stdin();
+    let mut source = prog.input_from(io::Read::chain(input, stdin.lock()));
+    prog.run_from(&mut source, out)
+}
+
+/// What a run left behind, for `--verify-deterministic` to compare runs.
+struct RunSnapshot {
+    halt: HaltReason,
+    steps: u64,
+    output_bytes: u64,
+    output: Vec<u8>,
+    tape: Tape,
+}
+
+impl RunSnapshot {
+    /// How this run differs from `first`, if it does.
+    fn divergence(&self, first: &RunSnapshot) -> Option<String> {
+        if self.halt != first.halt {
+            Some(format!("it {}, but the first {}", self.halt, first.halt))
+        } else if self.steps != first.steps {
+            Some(format!("it took {} steps, but the first took {}", self.steps, first.steps))
+        } else if self.output_bytes != first.output_bytes || self.output != first.output {
+            compare_output(&first.output, &self.output, Whitespace::Exact)
+                .err()
+                .or_else(|| Some(format!("it wrote {} bytes, but the first wrote {}", self.output_bytes, first.output_bytes)))
+        } else if self.tape.loc != first.tape.loc {
+            Some(format!("its pointer ended at {}, but the first's at {}", self.tape.loc, first.tape.loc))
+        } else {
+            let idx = self.tape.tape.iter().zip(&first.tape.tape).position(|(a, b)| a != b)?;
+            Some(format!("cell {} ended at {}, but the first's at {}", idx, self.tape.tape[idx], first.tape.tape[idx]))
+        }
+    }
+}
+
+/// Where in `source` the `,` at op `loc` came from. No pass merges, drops
+/// or copies a `,`, so the nth one among the ops is the nth in the source.
+fn input_span(source: &str, ops: &[BrainFuckToken], loc: usize) -> Option<Span> {
+    let nth = ops[..loc]
+        .iter()
+        .filter(|op| match **op {
+            BrainFuckToken::StdIn => true,
+            _ => false,
+        })
+        .count();
+
+    spanned_tokens(source)
+        .into_iter()
+        .filter(|&(_, token)| match token {
+            BrainFuckToken::StdIn => true,
+            _ => false,
+        })
+        .nth(nth)
+        .map(|(span, _)| span)
+}
+
+/// Moves of the pointer shown when it falls off the tape.
+const OFF_TAPE_TRAIL: usize = 8;
+/// Cells either side of the pointer shown when it falls off the tape.
+const OFF_TAPE_RADIUS: usize = 8;
+
+/// Where a move off the tape came from and what led up to it.
+#[derive(Default)]
+struct OffTapeContext {
+    span: Option<Span>,
+    notes: Vec<String>,
+}
+
+/// Explains the move off the tape at op `loc` by running the program again
+/// from `tape` with a `PointerTrail`, so the run that failed stayed on the
+/// fast path. The op and its loop are traced back to the source through the
+/// optimizer's op map.
+fn explain_off_tape(
+    loc: usize,
+    source: &str,
+    opts: &Options,
+    profile: Profile,
+    ops: Vec<BrainFuckToken>,
+    tape: Tape,
+    input: &[u8],
+) -> OffTapeContext {
+    let tokens = spanned_tokens(source);
+    let map = op_map(source, opts);
+    let span_of = |op: usize| {
+        let token = match map {
+            Some(ref map) => *map.origins(op).first()?,
+            None => op,
+        };
+        tokens.get(token).map(|&(span, _)| span)
+    };
+
+    let mut context = OffTapeContext {
+        span: span_of(loc),
+        notes: Vec::new(),
+    };
+    if let Some(open) = enclosing_loop(&ops, loc) {
+        let close = matching_close(&ops, open);
+        if let (Some(start), Some(end)) = (span_of(open), close.and_then(&span_of)) {
+            context.notes.push(format!("in the loop from {} to {}", start, end));
+        }
+    }
+
+    let mut replay = Program::with_profile(ops, profile);
+    replay.rewind(tape);
+    replay.trail = Some(PointerTrail::new(OFF_TAPE_TRAIL));
+    if replay.run(input, &mut CountingSink::default()).halt != HaltReason::OffTape(loc) {
+        return context;
+    }
+    context.notes.push(format!("the tape around the pointer: {}", replay.tape.window(OFF_TAPE_RADIUS)));
+    let moves: Vec<String> = replay.trail.unwrap().moves().iter().map(|m| m.to_string()).collect();
+    context.notes.push(format!("the last {}: {}", plural(moves.len(), "move"), moves.join(", ")));
+    context
+}
+
+/// Which parsed tokens each op was made from, found by optimizing `source`
+/// again the way the run did. `None` means one op per token, as lazily
+/// resolved jumps leave them.
+fn op_map(source: &str, opts: &Options) -> Option<OpMap> {
+    if opts.lazy_jumps {
+        return None;
+    }
+    let mut fuel = opts.opt_fuel.map_or(Fuel::unlimited(), Fuel::new);
+    Some(optimize_mapped(parse(source, opts.max_depth), &opts.passes, &mut fuel).1)
+}
+
+/// The `[` of the innermost loop around op `loc`, if it is in one.
+fn enclosing_loop(ops: &[BrainFuckToken], loc: usize) -> Option<usize> {
+    let mut depth = 0;
+    for idx in (0..loc).rev() {
+        match ops[idx] {
+            BrainFuckToken::JumpB(_) => depth += 1,
+            BrainFuckToken::JumpF(_) if depth == 0 => return Some(idx),
+            BrainFuckToken::JumpF(_) => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The `]` closing the `[` at op `open`.
+fn matching_close(ops: &[BrainFuckToken], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (idx, op) in ops.iter().enumerate().skip(open + 1) {
+        match *op {
+            BrainFuckToken::JumpF(_) => depth += 1,
+            BrainFuckToken::JumpB(_) if depth == 0 => return Some(idx),
+            BrainFuckToken::JumpB(_) => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Reports how a run ended: a runtime error as a diagnostic, and in JSON
+/// mode always a summary object, so tools can tell a finished run from one
+/// cut short. `explained` says more about a move off the tape, if that's
+/// how it ended.
+fn report_halt(
+    result: &RunResult,
+    prog: &Program,
+    source: &str,
+    path: &str,
+    format: MessageFormat,
+    explained: Option<OffTapeContext>,
+    output_hash: Option<[u8; 32]>,
+) {
+    let diagnostic = match result.halt {
+        HaltReason::OffTape(loc) => {
+            let context = explained.unwrap_or_default();
+            Some(Diagnostic {
+                code: "runtime-error",
+                level: Level::Deny,
+                span: context.span,
+                message: off_tape(loc),
+                notes: context.notes,
+            })
+        }
+        HaltReason::RuntimeError(ref e) => Some(Diagnostic {
+            code: "runtime-error",
+            level: Level::Deny,
+            span: None,
+            message: e.clone(),
+            notes: Vec::new(),
+        }),
+        HaltReason::InputExhaustedStrict(loc) => Some(Diagnostic {
+            code: "input-exhausted",
+            level: Level::Deny,
+            span: input_span(source, &prog.ops, loc),
+            message: "`,` read past the end of the input".to_string(),
+            notes: Vec::new(),
+        }),
+        _ => None,
+    };
+    if let Some(diagnostic) = diagnostic {
+        diagnostic.print(path, format);
+    }
+
+    if format == MessageFormat::Json {
+        eprintln!("{}", stats_json(result, path, &Metadata::from_header(source), output_hash));
+    }
+}
+
+/// How a run went as one JSON object, for `--message-format json` and
+/// `--stats-out`, with the SHA-256 of the output and what the program's
+/// header says about it.
+fn stats_json(result: &RunResult, path: &str, meta: &Metadata, output_hash: Option<[u8; 32]>) -> String {
+    let field = |value: &Option<String>| value.as_ref().map_or("null".to_string(), |value| json_string(value));
+    format!(
+        "{{\"halt\":{},\"message\":{},\"steps\":{},\"output_bytes\":{},\"output_sha256\":{},\"file\":{},\"title\":{},\"author\":{},\"input\":{}}}",
+        json_string(result.halt.code()),
+        json_string(&result.halt.to_string()),
+        result.steps,
+        result.output_bytes,
+        output_hash.map_or("null".to_string(), |hash| json_string(&hex(&hash))),
+        json_string(path),
+        field(&meta.title),
+        field(&meta.author),
+        field(&meta.input)
+    )
+}
+
+fn main() {
+    use std::env;
+
+    let mut args = env::args().skip(1).peekable();
+    // `run --watch PROGRAM` watches a single program, not a project.
+    let watching = env::args().any(|a| a == "--watch");
+    if let Some(command) = args.peek().filter(|a| *a == "run" && !watching || *a == "test").cloned() {
+        args.next();
+        let mut opts = ProjectOptions::default();
+        let run = command == "run";
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--output" if run => opts.output = Some(value(&mut args)),
+                "--stats-out" if run => opts.stats_out = Some(value(&mut args)),
+                "--whitespace" if !run => opts.whitespace = value(&mut args),
+                "--jobs" if !run => opts.jobs = value(&mut args),
+                "--report" if !run => opts.report = Some(value(&mut args)),
+                _ if !arg.starts_with("-") => opts.dir = arg,
+                _ => usage_and_exit(1),
+            }
+        }
+        project(&command, &opts);
+        return;
+    }
+
+    if args.peek().map_or(false, |a| a == "equiv") {
+        args.next();
+        let mut opts = EquivOptions::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--inputs" => opts.inputs = Some(value(&mut args)),
+                "--random" => opts.random = value(&mut args),
+                "--seed" => opts.seed = value(&mut args),
+                "--step-limit" => opts.step_limit = value(&mut args),
+                "--whitespace" => opts.whitespace = value(&mut args),
+                _ if !arg.starts_with("-") => opts.paths.push(arg),
+                _ => usage_and_exit(1),
+            }
+        }
+        if opts.paths.len() != 2 {
+            usage_and_exit(1);
+        }
+        equiv(&opts);
+        return;
+    }
+
+    if args.peek().map_or(false, |a| a == "ir") {
+        args.next();
+        let (mut path, mut pipeline) = (None, Pipeline::default());
+        let mut check: Option<String> = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--check" => check = Some(value(&mut args)),
+                _ if pipeline.flag(&arg, &mut args) => {}
+                _ if !arg.starts_with("-") && path.is_none() => path = Some(arg),
+                _ => usage_and_exit(1),
+            }
+        }
+        let (path, passes) = (path.unwrap_or_else(|| usage_and_exit(1)), pipeline.passes());
+        let source = read_source(&path, None, InvalidUtf8::Lossy).unwrap_or_else(|d| {
+            report_diagnostics(&path, &[d], MessageFormat::Text);
+            Exit::Parse.exit()
+        });
+        let mut lints = LintLevels::default();
+        lints.set("warnings", Level::Allow).unwrap();
+        if report_diagnostics(&path, &check_source(&source, &lints, DEFAULT_MAX_DEPTH), MessageFormat::Text) {
+            Exit::Parse.exit();
+        }
+
+        match check {
+            Some(expected) => {
+                let text = String::from_utf8_lossy(&read_bytes(&expected)).into_owned();
+                if let Err(e) = testing::golden_ir(&source, &text) {
+                    status!("{} doesn't match {}: {}", path, expected, e);
+                    Exit::Assertion.exit();
+                }
+                status!("{} matches {}", path, expected);
+            }
+            None => print!("{}", testing::ir_listing(&source, &passes)),
+        }
+        return;
+    }
+
+    if args.peek().map_or(false, |a| a == "compile") {
+        args.next();
+        let (mut path, mut out, mut pipeline) = (None, None, Pipeline::default());
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-o" => out = Some(value::<_, String>(&mut args)),
+                _ if pipeline.flag(&arg, &mut args) => {}
+                _ if !arg.starts_with("-") && path.is_none() => path = Some(arg),
+                _ => usage_and_exit(1),
+            }
+        }
+        let path = path.unwrap_or_else(|| usage_and_exit(1));
+        let out = out.unwrap_or_else(|| {
+            let out = std::path::Path::new(&path).with_extension("bfc");
+            out.to_str().unwrap().to_string()
+        });
+        compile(&path, &out, &pipeline.passes());
+        return;
+    }
+
+    if args.peek().map_or(false, |a| a == "ir-doc") {
+        args.next();
+        let mut format = MessageFormat::Text;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--format" => format = value(&mut args),
+                _ => usage_and_exit(1),
+            }
+        }
+        ir_doc(format);
+        return;
+    }
+
+    if args.peek().map_or(false, |a| a == "slice") {
+        args.next();
+        let mut opts = SliceOptions::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--criterion" => opts.criterion = Some(value(&mut args)),
+                "--input" => opts.input = Some(value(&mut args)),
+                "--step-limit" => opts.step_limit = value(&mut args),
+                _ if !arg.starts_with("-") && opts.path.is_none() => opts.path = Some(arg),
+                _ => usage_and_exit(1),
+            }
+        }
+        if opts.path.is_none() || opts.criterion.is_none() {
+            usage_and_exit(1);
+        }
+        slice(&opts);
+        return;
+    }
+
+    if args.peek().map_or(false, |a| a == "loop-tests") {
+        args.next();
+        let (mut dir, mut pipeline) = (None, Pipeline::default());
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                _ if pipeline.flag(&arg, &mut args) => {}
+                _ if !arg.starts_with("-") && dir.is_none() => dir = Some(arg),
+                _ => usage_and_exit(1),
+            }
+        }
+        loop_tests(&dir.unwrap_or_else(|| usage_and_exit(1)), &pipeline.passes());
+        return;
+    }
+
+    if args.peek().map_or(false, |a| a == "trace-merge") {
+        args.next();
+        let paths: Vec<String> = args.collect();
+        if paths.is_empty() || paths.iter().any(|p| p.starts_with("-")) {
+            usage_and_exit(1);
+        }
+        trace_merge(&paths);
+        return;
+    }
+
+    let check = args.peek().map_or(false, |a| a == "check");
+    if check || args.peek().map_or(false, |a| a == "run") {
+        args.next();
+    }
+
+    let mut opts = if check {
+        // `check --quine` is `--check-quine`: the program is run after all.
+        Options::from_args(args.map(|arg| if arg == "--quine" { "--check-quine".to_string() } else { arg }))
+    } else if args.peek().map_or(false, |a| a == "demo") {
+        args.next();
+        // `demo NAME` is `--demo NAME`, and takes the same options.
+        Options::from_args(std::iter::once("--demo".to_string()).chain(args))
+    } else {
+        Options::from_args(args)
+    };
+    opts.check = check;
+    // Unbalanced loops only get past the lints where nothing needs every
+    // jump resolved up front: `check` alone, or a run with --lazy-jumps.
+    if !opts.lazy_jumps && (!check || opts.check_quine) {
+        opts.lints.set("unbalanced-loops", Level::Deny).unwrap();
+    }
+
+    if opts.list_demos {
+        for demo in DEMOS {
+            let meta = Metadata::from_header(demo.source);
+            let author = meta.author.map_or(String::new(), |author| format!(", by {}", author));
+            println!("{:<12}{}{}", demo.name, demo.description, author);
+            if let Some(input) = meta.input {
+                println!("{:<12}input: {}", "", input);
+            }
+        }
+        return;
+    }
+
+    if opts.conformance {
+        conformance(opts.max_depth);
+        return;
+    }
+
+    if opts.watch {
+        watch(&opts);
+        return;
+    }
+
+    let mut timings = Timings::default();
+    let mut compiled = None;
+    let (s, mut input) = match opts.demo {
+        Some(demo) => (demo.source.to_string(), demo.input.as_bytes().to_vec()),
+        None => {
+            let bytes = timings.time("read", || read_bytes(&opts.path));
+            let format = opts.format.unwrap_or_else(|| SourceFormat::detect(&opts.path, &bytes));
+            if format == SourceFormat::Bytecode || format == SourceFormat::IrText {
+                compiled = Some((bytes, format));
+                (String::new(), Vec::new())
+            } else {
+                match decode_program(bytes, format, opts.invalid_utf8) {
+                    Ok(source) => (source, Vec::new()),
+                    Err(diagnostic) => {
+                        diagnostic.print(&opts.path, opts.message_format);
+                        Exit::Parse.exit()
+                    }
+                }
+            }
+        }
+    };
+
+    if let Some(ref path) = opts.input {
+        input = read_bytes(path);
+    }
+    if opts.input_newlines != Newlines::Keep {
+        input = opts.input_newlines.translate(&input);
+    }
+    if let Some((ref bytes, format)) = compiled {
+        run_compiled(bytes, format, &input, &opts);
+        return;
+    }
+
+    if opts.emit_bf {
+        let emitted = parse_lossless(s.chars()).emit(!opts.strip_comments);
+        match opts.wrap {
+            Some(width) => print!("{}", wrap(&emitted, width)),
+            None => print!("{}", emitted),
+        }
+        return;
+    }
+
+    if opts.bisect_fuel {
+        bisect_fuel(&s, &input, &opts);
+        return;
+    }
+
+    let name = opts.demo.map_or(opts.path.as_str(), |demo| demo.name);
+    let mut allocs = AllocReport::start();
+    let parse_options = ParseOptions {
+        max_tokens: opts.max_tokens,
+        max_source_bytes: opts.max_source_bytes,
+        max_depth: opts.max_depth,
+    };
+    let parsed = timings.time("parse", || parse_with(&s, &parse_options));
+    let diagnostics = match parsed {
+        // Nesting too deep is reported by the lints, along with the rest.
+        Err(ParseError::TooDeep { .. }) | Ok(_) => {
+            timings.time("lint", || check_source(&s, &opts.lints, opts.max_depth))
+        }
+        Err(ref e) => vec![e.to_diagnostic()],
+    };
+    allocs.phase("parse");
+    let failed = report_diagnostics(name, &diagnostics, opts.message_format);
+    if failed {
+        if !opts.check && opts.message_format == MessageFormat::Text {
+            eprintln!("Not running {} due to errors", name);
+        }
+        Exit::Parse.exit();
+    }
+    if opts.check && !opts.check_quine {
+        return;
+    }
+
+    // Already checked along with the lints above.
+    let limits = opts.limits.or(Limits::from_pragmas(&s).unwrap_or_default());
+    let mut profile = opts.profile;
+    profile.tape = limits.tape.unwrap_or(profile.tape);
+
+    let mut fuel = opts.opt_fuel.map_or(Fuel::unlimited(), Fuel::new);
+    let tokens = if opts.lazy_jumps {
+        let mut tokens: Vec<BrainFuckToken> = parsed.unwrap().into();
+        defer_jumps(&mut tokens);
+        tokens
+    } else {
+        let (tokens, exceeded) = optimize_budgeted(parsed.unwrap(), &opts.passes, &mut fuel, opts.opt_budget, &mut timings);
+        if let Some(exceeded) = exceeded {
+            status!("{}: warning: running unoptimized, {}", opts.path, exceeded);
+        }
+        tokens
+    };
+    allocs.phase("optimize");
+
+    if let Some(runs) = opts.bench {
+        bench(tokens, profile, &input, runs, opts.dispatch, opts.discard_output);
+        return;
+    }
+
+    if let Some(ref dir) = opts.trace_inputs {
+        trace_inputs(tokens, profile, dir);
+        return;
+    }
+
+    if let Some(ref path) = opts.emit_bytecode {
+        write_file(path, |file| io::Write::write_all(file, &to_bytecode(&tokens)));
+        status!("Wrote {} to {}", plural(tokens.len(), "op"), path);
+        return;
+    }
+
+    let mut prog = Program::with_profile(tokens, profile);
+    prog.dispatch = opts.dispatch;
+    let mut output = Vec::new();
+    if let Some(steps) = limits.steps {
+        prog.step_limit = steps;
+    }
+    if let Some(secs) = opts.time_limit {
+        prog.deadline = Some(Instant::now() + Duration::from_secs_f64(secs));
+    }
+    if let Some(fill) = opts.tape_fill {
+        prog.tape.fill(fill);
+    }
+    if let Some(ref image) = opts.tape_init {
+        let bytes = read_bytes(&image.path);
+        prog.tape.load(&bytes, image.offset).unwrap_or_else(|e| {
+            eprintln!("--tape-init {}: {}", image.path, e);
+            Exit::Usage.exit()
+        });
+    }
+    if opts.transcript.is_some() {
+        prog.transcript = Some(Transcript::new());
+    }
+    if opts.record.is_some() {
+        prog.recording = Some(Recording::new());
+    }
+    if let Some(ref path) = opts.replay {
+        let text = String::from_utf8_lossy(&read_bytes(path)).into_owned();
+        match Recording::parse(&text) {
+            Ok(recording) => prog.replay = Some(Replay::new(recording)),
+            Err(e) => {
+                status!("{}: {}", path, e);
+                Exit::Usage.exit()
+            }
+        }
+    }
+    if let Some(hz) = opts.throttle_hz {
+        prog.throttle = Some(Duration::from_secs(1) / hz.max(1));
+    }
+    if opts.debug {
+        prog.debugger = Some(Debugger::new());
+    }
+    if opts.explain_steps {
+        prog.explainer = Some(Explainer::default());
+    }
+    if opts.audit {
+        prog.audit = Some(Audit::default());
+    }
+    if opts.emit_loop_tests.is_some() {
+        prog.loop_capture = Some(LoopCapture::default());
+    }
+    if opts.memoize {
+        prog.memo = Some(Memo::new(&prog.ops, opts.memo_entries));
+    }
+    if !opts.on_cell.is_empty() {
+        let triggers = Triggers::new(opts.on_cell.clone(), &prog.tape).unwrap_or_else(|e| {
+            eprintln!("--on-cell: {}", e);
+            Exit::Usage.exit()
+        });
+        prog.triggers = Some(triggers);
+    }
+    if opts.trajectory.is_some() {
+        prog.trajectory = Some(Trajectory::new(opts.trajectory_every));
+    }
+    if opts.cost_report.is_some() {
+        prog.op_counts = Some(vec![0; prog.ops.len()]);
+    }
+    if opts.animate.is_some() {
+        prog.animation = Some(Animation::new(opts.animate_every));
+    }
+    if let Some(ref path) = opts.checkpoints {
+        let file = io_or_exit(std::fs::File::create(path), path);
+        prog.checkpoints = Some(Checkpoints::new(opts.checkpoint_every, file, &prog.tape));
+    }
+
+    // Throttled and debugged runs are meant to be watched, so show output as
+    // it happens rather than at the end.
+    prog.live_output = (prog.throttle.is_some() || prog.debugger.is_some() || opts.then_stdin)
+        && !opts.discard_output
+        && opts.output.is_none()
+        && opts.stream_output.is_none();
+    let mut stream = opts.stream_output.map(|policy| WriteSink::new(io::stdout(), policy));
+    let (runs, initial_tape) = (opts.repeat.unwrap_or(1), prog.tape.clone());
+    // Hashed on the way out, so there is a hash even of discarded output.
+    let hash = opts.stats_out.is_some() || opts.message_format == MessageFormat::Json;
+    let (result, output_hash) = timings.time("run", || {
+        let mut first: Option<RunSnapshot> = None;
+        for run in 1.. {
+            let (result, output_hash) = if opts.discard_output {
+                run_hashed(&mut prog, &input, &opts, &mut CountingSink::default(), hash)
+            } else if let Some(ref mut stream) = stream {
+                run_hashed(&mut prog, &input, &opts, stream, hash)
+            } else {
+                run_hashed(&mut prog, &input, &opts, &mut output, hash)
+            };
+
+            if opts.verify_deterministic {
+                let snapshot = RunSnapshot {
+                    halt: result.halt.clone(),
+                    steps: result.steps,
+                    output_bytes: result.output_bytes,
+                    output: output.clone(),
+                    tape: prog.tape.clone(),
+                };
+                match first {
+                    Some(ref first) => if let Some(difference) = snapshot.divergence(first) {
+                        status!("Run {} of {} diverged: {}", run, name, difference);
+                        Exit::Assertion.exit();
+                    },
+                    None => first = Some(snapshot),
+                }
+            }
+            if run == runs {
+                return (result, output_hash);
+            }
+            prog.rewind(initial_tape.clone());
+            output.clear();
+        }
+        unreachable!()
+    });
+    if opts.verify_deterministic {
+        status!("{} ran the same way all {} times", name, runs);
+    }
+    // After an output error there is no point waiting on the rest.
+    let output_failed = match result.halt {
+        HaltReason::OutputError(_) => true,
+        _ => false,
+    };
+    if let Some(stream) = stream.filter(|_| !output_failed) {
+        if let Err(e) = stream.finish() {
+            status!("Couldn't write output: {}", e);
+            Exit::Io.exit();
+        }
+    }
+    allocs.phase("run");
+
+    if let (Some(path), Some(transcript)) = (opts.transcript.as_ref(), prog.transcript.as_ref()) {
+        write_file(path, |file| transcript.write_to(file));
+    }
+    if let (Some(path), Some(recording)) = (opts.record.as_ref(), prog.recording.as_ref()) {
+        write_file(path, |file| {
+            let mut file = io::BufWriter::new(file);
+            recording.write_to(&mut file)?;
+            io::Write::flush(&mut file)
+        });
+    }
+    if let (Some(path), Some(replay)) = (opts.replay.as_ref(), prog.replay.take()) {
+        let matched = replay.matched();
+        match replay.finish() {
+            None => status!("Replay matches {} over {}", path, plural(matched, "step")),
+            Some(divergence) => {
+                status!("Replay of {} {}", path, divergence);
+                let mut ops: Vec<usize> = divergence.expected.iter().chain(divergence.actual.iter()).map(|step| step.op).collect();
+                ops.dedup();
+                for op in ops {
+                    if let Some(token) = prog.ops.get(op) {
+                        status!("    op {} is `{}`", op, token.to_string().trim());
+                    }
+                }
+                Exit::Assertion.exit();
+            }
+        }
+    }
+
+    if let Some(ref dump) = opts.tape_out {
+        let (start, end) = dump.range.unwrap_or((0, prog.tape.tape.len()));
+        let bytes = prog.tape.dump(start, end).unwrap_or_else(|e| {
+            eprintln!("--tape-out {}: {}", dump.path, e);
+            Exit::Usage.exit()
+        });
+        write_file(&dump.path, |file| io::Write::write_all(file, &bytes));
+    }
+
+    if let Some(ref path) = opts.trace_out {
+        let saved = SavedTrace::from_run(&prog.tracer, &prog.ops, result.steps);
+        write_file(path, |file| saved.write_to(file));
+    }
+
+    if let (Some(dir), Some(capture)) = (opts.emit_loop_tests.as_ref(), prog.loop_capture.as_ref()) {
+        let written = capture.write_tests(dir, &prog.tracer, &prog.ops, result.steps, profile.cells);
+        status!("Wrote {} to {}", plural(io_or_exit(written, dir), "loop test"), dir);
+    }
+
+    if let (Some(path), Some(animation)) = (opts.animate.as_ref(), prog.animation.as_ref()) {
+        write_file(path, |file| animation.write_svg(file));
+    }
+
+    if let (Some(path), Some(trajectory)) = (opts.trajectory.as_ref(), prog.trajectory.as_ref()) {
+        if path.ends_with(".svg") {
+            write_file(path, |file| trajectory.write_svg(file));
+        } else {
+            write_file(path, |file| trajectory.write_csv(file));
+        }
+    }
+
+    if let (Some(path), Some(counts)) = (opts.cost_report.as_ref(), prog.op_counts.as_ref()) {
+        let report = CostReport::new(&s, counts, op_map(&s, &opts).as_ref());
+        if path.ends_with(".html") {
+            write_file(path, |file| report.write_html(file));
+        } else {
+            write_file(path, |file| report.write_text(file));
+        }
+    }
+
+    if let Some(ref command) = opts.difftest_external {
+        let timeout = Duration::from_secs(opts.difftest_timeout);
+        let result = run_external(command, &opts.path, &input, timeout)
+            .and_then(|theirs| compare_output(&theirs, &output, opts.whitespace));
+
+        match result {
+            Ok(()) => status!("Output matches `{}`", command),
+            Err(reason) => {
+                status!("Output doesn't match `{}`: {}", command, reason);
+                Exit::Assertion.exit();
+            }
+        }
+        return;
+    }
+
+    if opts.check_quine {
+        match check_quine(&s, &output, opts.whitespace) {
+            Ok(()) => status!("{} is a quine", opts.path),
+            Err(reason) => {
+                status!("{} is not a quine: {}", opts.path, reason);
+                Exit::Assertion.exit();
+            }
+        }
+        return;
+    }
+
+    if opts.discard_output {
+        status!("Output: {} discarded", plural(result.output_bytes as usize, "byte"));
+    } else if let Some(ref path) = opts.output_audio {
+        write_file(path, |file| write_wav(file, &output, opts.audio_rate));
+        status!("Output: {} written to {}", plural(output.len(), "sample"), path);
+    } else if let Some(ref image) = opts.output_image {
+        // Encoded first so a picture of the wrong size leaves no file behind.
+        let mut bytes = Vec::new();
+        io_or_exit(write_image(&mut bytes, &output, image), &image.path);
+        write_file(&image.path, |file| io::Write::write_all(file, &bytes));
+        status!("Output: {}x{} image written to {}", image.width, image.height, image.path);
+    } else if let Some(ref path) = opts.output {
+        write_file(path, |file| io::Write::write_all(file, &output));
+    } else if !prog.live_output && opts.stream_output.is_none() {
+        write_output(&output);
+    }
+    if let Some(ref path) = opts.stats_out {
+        write_file(path, |file| io::Write::write_all(file, (stats_json(&result, name, &Metadata::from_header(&s), output_hash) + "\n").as_bytes()));
+    }
+
+    if result.halt != HaltReason::Completed {
+        status!("\nStopped after {} steps: {}", result.steps, result.halt);
+    }
+    let off_tape = match result.halt {
+        HaltReason::OffTape(loc) => {
+            Some(explain_off_tape(loc, &s, &opts, profile, prog.ops.clone(), initial_tape, &input))
+        }
+        _ => None,
+    };
+    report_halt(&result, &prog, &s, name, opts.message_format, off_tape, output_hash);
+
+    status!("\nTrace:\n");
+    let r = prog.tracer.report(&prog.ops);
+
+    let mut report: Vec<(&String, &u32)> = r.iter().collect();
+    report.sort_by(|&(_, a), &(_, b)| b.cmp(a));
+
+    for (name, count) in report {
+        status!("{} -> {}", name, count);
+    }
+    status!("\n{}", hot_summary(&prog.tracer, &prog.ops, result.steps, &opts.passes));
+    if let Some(ref triggers) = prog.triggers {
+        status!("\nTriggers:\n\n{}", triggers.summary());
+    }
+    if let Some(ref audit) = prog.audit {
+        status!("\nAudit:\n\n{}", audit.report());
+    }
+    if let Some(ref memo) = prog.memo {
+        status!("\nMemo:\n\n{}", memo.report());
+    }
+    allocs.print();
+    if opts.time_passes {
+        status!("\nTime passes:\n\n{}", timings.report());
+    }
+
+    if let Some(exit) = result.halt.exit() {
+        exit.exit();
+    }
+}